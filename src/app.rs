@@ -1,220 +1,3449 @@
-use anyhow::{anyhow, Context, Result};
-use image::ImageReader;
+use anyhow::{anyhow, bail, Context, Result};
+use crossterm::event::{KeyCode, KeyModifiers};
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageReader};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::{Deserialize, Serialize};
 
 use std::{
-    cmp::max,
-    collections::HashMap,
-    fs,
-    path::{Path, PathBuf},
+    any::Any,
+    cmp::{max, Reverse},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    iter,
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        mpsc::{sync_channel, Receiver},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TryRecvError},
+        Arc, Mutex, RwLock,
     },
     thread::{self, available_parallelism, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
-use ratatui_image::{picker::Picker, protocol::StatefulProtocol};
+use ratatui_image::{
+    picker::{Picker, ProtocolType},
+    protocol::StatefulProtocol,
+    FontSize,
+};
+
+use crate::{
+    cache::{ImgCache, SourceInfo},
+    colors::{self, ColorStats},
+    config::{self, Config, Dest, KeyBind, Modifier, FREE_KEYS},
+    decode::{decode_oriented, decode_raw, decode_tiff_page, flatten_alpha, tiff_page_count},
+    done::DoneList,
+    fileops::{
+        content_hash, free_space, index_dir, numbered_path, place_img, replace_img, rotate_img,
+        same_content, unplace_img, unrotate_img, Rewrite, Trash,
+    },
+    heif, hook,
+    journal::Journal,
+    labels::Labels,
+    manifest, pdf, raw, rules,
+    session::{Session, SessionEntry},
+    similar,
+    suggest::{Suggester, Suggestion},
+    summary::Summary,
+    svg, template,
+    theme::Theme,
+    ui::PanelLayout,
+    video,
+    watch::DirWatcher,
+};
+
+/// ワーカー間で共有する，処理中の段階ごとのワーカーの数などのカウンタ
+#[derive(Default)]
+struct WorkerStats {
+    // ファイルを読んでいるワーカーの数
+    reading: AtomicUsize,
+    // デコードしているワーカーの数
+    decoding: AtomicUsize,
+    // 受信側が詰まっていて送れずに待っているワーカーの数
+    blocked: AtomicUsize,
+    // 送り終えた画像の数
+    sent: AtomicUsize,
+}
+
+/// ある時点のワーカーとデコードの列の状態
+#[derive(Clone, Copy, Default)]
+pub struct WorkerStatus {
+    // 動いているワーカーの数
+    pub workers: usize,
+    pub reading: usize,
+    pub decoding: usize,
+    pub blocked: usize,
+    // デコード済みで表示を待っている画像の数
+    pub ready: usize,
+    // まだデコードし終えていない画像の数
+    pub left: usize,
+    // デコードし終えた画像の数の累計
+    pub decoded: usize,
+}
+
+/// ワーカーがデコードする画像の番号の列
+///
+/// 表示を待っている画像から順に渡し，それより前の番号は後回しにする
+struct DecodeQueue {
+    pending: Mutex<BTreeSet<usize>>,
+    // 次に表示する画像の番号 (全てのワーカーで共有する)
+    wanted: Arc<AtomicUsize>,
+    // 絞り込みに合わないので後回しにする画像の番号 (全てのワーカーで共有する)
+    deferred: Arc<Mutex<HashSet<usize>>>,
+    // シャッフル中に次に表示する順に並べた画像の番号 (全てのワーカーで共有する)
+    ahead: Arc<Mutex<Vec<usize>>>,
+}
+
+impl DecodeQueue {
+    fn new(
+        idxs: Range<usize>,
+        wanted: Arc<AtomicUsize>,
+        deferred: Arc<Mutex<HashSet<usize>>>,
+        ahead: Arc<Mutex<Vec<usize>>>,
+    ) -> Self {
+        DecodeQueue {
+            pending: Mutex::new(idxs.collect()),
+            wanted,
+            deferred,
+            ahead,
+        }
+    }
+
+    /// 次にデコードする画像の番号．残っていなければNone
+    fn pop(&self) -> Option<usize> {
+        loop {
+            let mut pending = self.pending.lock().ok()?;
+            // シャッフル中は番号が飛び飛びになるので，次に表示する画像だけを先にデコードする
+            let ahead = self.ahead.lock().ok()?;
+            if !ahead.is_empty() && !pending.is_empty() {
+                let Some(&idx) = ahead.iter().find(|idx| pending.contains(idx)) else {
+                    // 先の画像を溜め込まないよう，表示が進むまで待つ
+                    drop(ahead);
+                    drop(pending);
+                    thread::sleep(AHEAD_WAIT);
+                    continue;
+                };
+                pending.remove(&idx);
+                return Some(idx);
+            }
+            let wanted = self.wanted.load(Ordering::Relaxed);
+            let deferred = self.deferred.lock().ok()?;
+            let mut order = pending.range(wanted..).chain(pending.range(..wanted));
+            let idx = *order
+                .clone()
+                .find(|idx| !deferred.contains(idx))
+                .or_else(|| order.next())?;
+            pending.remove(&idx);
+            return Some(idx);
+        }
+    }
+}
+
+/// ワーカーが画像ごとの処理に使うもの
+#[derive(Clone)]
+struct DecodeContext {
+    // 端末の文字の大きさが変わると差し替わる
+    picker: Arc<RwLock<Picker>>,
+    suggester: Option<Arc<Suggester>>,
+    // OCRのコマンド
+    ocr: Option<String>,
+    // 規則の判定で読み取った文字列
+    rule_texts: Arc<HashMap<PathBuf, String>>,
+    // 文字列の欄を表示しているか．隠している間はOCRを行わない
+    show_text: Arc<AtomicBool>,
+    cache: Option<Arc<ImgCache>>,
+    stats: Arc<WorkerStats>,
+    // 表示せずに分類したので処理しなくてよい画像の番号
+    cancelled: Arc<Mutex<HashSet<usize>>>,
+    low_bandwidth: Arc<AtomicBool>,
+    alpha_background: AlphaBackground,
+}
+
+/// 表示用の画像を作ったときの設定．変わっていれば表示する前に作り直す
+#[derive(Clone, Copy, PartialEq)]
+struct RenderParams {
+    font_size: FontSize,
+    low_bandwidth: bool,
+}
 
-use crate::Config;
+impl DecodeContext {
+    fn is_cancelled(&self, idx: usize) -> bool {
+        self.cancelled
+            .lock()
+            .is_ok_and(|cancelled| cancelled.contains(&idx))
+    }
+}
 
 struct ProcessedImg {
-    state: StatefulProtocol,
+    // デコードに失敗した場合はNone
+    frames: Option<Vec<Frame>>,
+    thumb: Option<StatefulProtocol>,
+    // 重複を見つけるためのファイルの大きさと中身のハッシュ
+    hash: Option<(u64, u64)>,
+    // モデルが出した分類の候補
+    suggestions: Vec<Suggestion>,
+    // OCRで読み取った文字列
+    text: Option<String>,
+    meta: Option<ImgMeta>,
+    // デコードに失敗した理由
+    error: Option<String>,
+    render: Option<RenderParams>,
     idx: usize,
 }
 
+/// ワーカーでデコードした画像と，そのときに分かる情報
+struct Decoded {
+    frames: Vec<Frame>,
+    render: RenderParams,
+    thumb: StatefulProtocol,
+    suggestions: Vec<Suggestion>,
+    meta: ImgMeta,
+}
+
+/// 画像の大きさや形式，色などの情報
+#[derive(Clone)]
+pub struct ImgMeta {
+    pub width: u32,
+    pub height: u32,
+    // ファイルサイズ (バイト)
+    pub bytes: u64,
+    pub format: Option<ImageFormat>,
+    // 1チャンネルあたりのビット数
+    pub bit_depth: u16,
+    pub alpha: bool,
+    pub colors: ColorStats,
+    // 複数ページのTIFFのページ数 (それ以外は1)
+    pub pages: usize,
+}
+
+/// 移動先のファイルとの衝突
+struct Conflict {
+    // 設定上の分類先
+    dest: PathBuf,
+    // 衝突した移動先のファイル
+    existing: PathBuf,
+    // 名前に関わらず中身が同じファイルか
+    duplicate: bool,
+}
+
+/// 分類する画像の一覧と分類の状態．デコードするワーカーを持ち，画像の移動や取り消しを行う
 pub struct App {
     // viewmodelの作成に直接関係
     config: Config,
     imgs: Arc<Vec<PathBuf>>,
     rx: Receiver<ProcessedImg>,
+    // 順番が来るまでワーカーから届いた画像を溜めておく
+    reorder_buf: BTreeMap<usize, Option<Vec<Frame>>>,
+    // 次に受け取る画像の番号
+    next_idx: usize,
+    // ワーカーに優先してデコードさせる画像の番号 (next_idxと同じ値)
+    wanted: Arc<AtomicUsize>,
+    // 受け取ったがまだviewmodelに渡していないサムネイル
+    new_thumbs: Vec<(usize, StatefulProtocol)>,
     pub log: Option<AppLog>,
+    // このセッションで行った操作の履歴 (logの値を順に並べたもの)
+    logs: Vec<AppLog>,
+    // 履歴の各項目が決定を変えた画像の番号 (logsと同じ並び)
+    log_imgs: Vec<Option<usize>>,
 
-    idx: usize,
+    // 表示した順の画像の番号
+    shown: Vec<usize>,
+    // shownと同じ画像の番号．表示したかをすぐに調べるために持つ
+    shown_set: HashSet<usize>,
+    // shownの中で表示中の位置
+    pos: usize,
+    // 各画像に対する決定
+    decisions: Vec<Option<Decision>>,
+    // 取り消し用に決定や回転を行った順に並べたもの
+    history: Vec<Step>,
+    // やり直し用に取り消した順に並べた画像の番号と取り消した決定
+    redo: Vec<(usize, Decision)>,
+    // 後回しにした画像の番号 (後回しにした順)
+    later: Vec<usize>,
+    session: Session,
+    // 画像を送るゴミ箱
+    trash: Trash,
+    done: DoneList,
+    journal: Option<Journal>,
+    // タグ付けモードで付けたラベル
+    labels: Option<Labels>,
+    // 画像ごとの説明文 (元の場所をキーにする)
+    captions: HashMap<PathBuf, String>,
+    // このセッションで書き出した説明文のファイル．取り消すときはこれだけを消す
+    caption_files: HashSet<PathBuf>,
+    // 同名ファイルとの衝突の解決待ちになっている分類先
+    conflict: Option<Conflict>,
+    // 中身が同じハッシュの画像のうち最初のものの番号
+    hashes: HashMap<(u64, u64), usize>,
+    // 画像ごとのファイルの大きさと中身のハッシュ
+    content_hashes: HashMap<usize, (u64, u64)>,
+    // 分類先のディレクトリごとの，大きさと中身のハッシュからそのファイルを引く索引
+    dest_indexes: HashMap<PathBuf, HashMap<(u64, u64), PathBuf>>,
+    // 列の中で先にある画像と中身が同じ画像の番号と，その先にある画像の番号
+    duplicates: HashMap<usize, usize>,
+    // 見た目が似ている画像ごとの，同じグループの他の画像の番号
+    similar: HashMap<usize, Vec<usize>>,
+    // 画像ごとにモデルが出した分類の候補
+    suggestions: HashMap<usize, Vec<Suggestion>>,
+    // 分類の候補を出すモデル (設定されていなければNone)
+    suggester: Option<Arc<Suggester>>,
+    // 表示用に縮小した画像のキャッシュ
+    cache: Option<Arc<ImgCache>>,
+    // 画像ごとにOCRで読み取った文字列
+    texts: HashMap<usize, String>,
+    // 規則の判定のためにOCRで読み取った文字列．ワーカーで読み直さない
+    rule_texts: Arc<HashMap<PathBuf, String>>,
+    // 文字列の欄を表示しているか
+    show_text: Arc<AtomicBool>,
+    // 画像ごとの大きさや形式などの情報
+    metas: HashMap<usize, ImgMeta>,
+    // 失敗した移動の数
+    failure_num: usize,
+    // デコードに失敗した画像の番号と理由
+    decode_errors: BTreeMap<usize, String>,
+    started: Instant,
+    // ワーカーと共有する．端末の文字の大きさが変わると差し替える
+    picker: Arc<RwLock<Picker>>,
+    // 前の文字の大きさや低帯域モードの切り替え前に作ったので表示する前に作り直す画像の番号
+    stale: HashSet<usize>,
+    // ワーカーと共有する．解像度と色数を落として端末に送る量を減らすか
+    low_bandwidth: Arc<AtomicBool>,
+    // 端末に文字の大きさを問い合わせられるか (応答のない端末では毎回待たされる)
+    can_query: bool,
     handles: Vec<JoinHandle<()>>,
+    stats: Arc<WorkerStats>,
+    // ワーカーに処理をやめさせる画像の番号
+    cancelled: Arc<Mutex<HashSet<usize>>>,
+    // 表示する画像をファイル名で絞り込む，入力したままの文字列とパターン
+    filter: Option<(String, glob::Pattern)>,
+    // 絞り込みに合わないのでワーカーに後回しにさせる画像の番号
+    deferred: Arc<Mutex<HashSet<usize>>>,
+    // 実行中にシャッフルした，表示する順に並べた全ての画像の番号
+    shuffled: Option<Vec<usize>>,
+    // 並べる順を決める乱数の種
+    seed: u64,
+    // シャッフル中に次に表示する順に並べた，ワーカーに先にデコードさせる画像の番号
+    ahead: Arc<Mutex<Vec<usize>>>,
+    // ワーカーから受け取った画像の数
+    received_num: usize,
+    // 監視モードで新しく作られた画像を見つける
+    watcher: Option<DirWatcher>,
+    // 監視モードで後から加えた画像をデコードして送る
+    watch_tx: Option<SyncSender<ProcessedImg>>,
 }
 
-pub struct ImgInfo {
+/// 表示用にデコードした1フレーム
+pub struct Frame {
     pub state: StatefulProtocol,
+    // 次のフレームまでの時間
+    pub delay: Duration,
+}
+
+pub struct ImgInfo {
+    // 静止画の場合は1フレームのみ
+    pub frames: Vec<Frame>,
+    pub path: PathBuf,
+    // 画像に付けた説明文
+    pub caption: Option<String>,
+    // タグ付けモードで画像に付けたラベル
+    pub labels: Vec<String>,
+    // 列の中で先にある，中身が同じ画像
+    pub duplicate_of: Option<PathBuf>,
+    // モデルが出した分類の候補
+    pub suggestions: Vec<Suggestion>,
+    // OCRで読み取った文字列
+    pub text: Option<String>,
+    pub meta: Option<ImgMeta>,
+    // デコードに失敗した理由 (framesは空)
+    pub error: Option<String>,
+}
+
+/// 比較表示で並べる画像
+pub struct CompareImg {
+    pub idx: usize,
     pub path: PathBuf,
+    pub state: StatefulProtocol,
+}
+
+/// 分類先のディレクトリに最近置かれた画像
+pub struct Peek {
+    pub name: String,
+    // 新しいものから順に並べた画像のパスとサムネイル
+    pub imgs: Vec<(PathBuf, StatefulProtocol)>,
 }
 
 pub struct AppInfo {
     pub img_num: usize,
-    pub keybind: HashMap<char, PathBuf>,
+    // 以前のセッションで決定済みの画像の数
+    pub done_num: usize,
+    pub keybind: HashMap<KeyBind, Dest>,
+    pub theme: Theme,
+    pub layout: PanelLayout,
+    // 画面のうち画像を表示する領域の割合 (%)
+    pub image_percent: u16,
+    pub copy_modifier: Modifier,
+    pub sticky: bool,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum AppLog {
     MoveSuccess(PathBuf, PathBuf),
+    // 分類せずに分類先にコピーした
+    Copy(PathBuf, PathBuf),
     Skip(PathBuf),
+    Trash(PathBuf),
+    Defer(PathBuf),
+    Undo(PathBuf),
+    Redo(PathBuf),
+    Staged(PathBuf, PathBuf),
+    Commit(usize),
+    NewDest(KeyBind, PathBuf),
+    Flag(PathBuf),
+    // ラベルを付けたか外したか
+    Label(PathBuf, String, bool),
+    // 規則で自動的に分類した画像の数
+    Rules(usize),
+    // 絞り込みに合う画像がなくなったので絞り込みをやめた
+    FilterCleared,
+    // 操作に失敗した
+    Error(String),
+    // 画像が他のプロセスに削除されていたので飛ばした
+    Missing(PathBuf),
+    // ファイルを回転した (trueなら時計回り)
+    Rotate(PathBuf, bool),
+}
+
+/// 画像を端末に表示する方式
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Kitty,
+    Sixel,
+    Iterm2,
+    // 対応していない端末でも使える半角ブロック文字
+    Halfblocks,
+}
+
+impl From<Protocol> for ProtocolType {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Kitty => ProtocolType::Kitty,
+            Protocol::Sixel => ProtocolType::Sixel,
+            Protocol::Iterm2 => ProtocolType::Iterm2,
+            Protocol::Halfblocks => ProtocolType::Halfblocks,
+        }
+    }
+}
+
+/// 透明な部分の下に敷く背景
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AlphaBackground {
+    // 白と灰色の市松模様
+    #[default]
+    Checkerboard,
+    // 端末の背景のまま
+    None,
+    // 単色 ("#rrggbb"で指定する)
+    Color([u8; 3]),
+}
+
+impl FromStr for AlphaBackground {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "checkerboard" => Ok(AlphaBackground::Checkerboard),
+            "none" => Ok(AlphaBackground::None),
+            _ => {
+                let hex = s
+                    .strip_prefix('#')
+                    .filter(|hex| hex.len() == 6)
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| anyhow!("invalid alpha background: {}", s))?;
+                let [_, r, g, b] = hex.to_be_bytes();
+                Ok(AlphaBackground::Color([r, g, b]))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AlphaBackground {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// 移動先に同名のファイルがある場合の扱い
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    // 末尾に番号を付けて移動する
+    RenameWithSuffix,
+    // 上書きする
+    Overwrite,
+    // 移動せずにスキップする
+    Skip,
+    // その都度確認する
+    #[default]
+    Ask,
+}
+
+/// 分類先に画像を置く方法
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DestMode {
+    #[default]
+    Move,
+    // 元の画像を残してコピーする
+    Copy,
+    // 元の画像を残してシンボリックリンクを作る
+    #[serde(alias = "link")]
+    Symlink,
+    // 元の画像を残してハードリンクを作る (容量を使わないが，同じファイルシステム内に限る)
+    Hardlink,
+}
+
+impl DestMode {
+    pub fn is_move(&self) -> bool {
+        *self == DestMode::Move
+    }
+}
+
+/// 画像を表示する順番の基準
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    // ファイル名順
+    #[default]
+    Name,
+    // 更新日時順
+    Mtime,
+    // ファイルサイズ順
+    Size,
+    // ランダム
+    Random,
+    // 見た目が似ている画像が隣り合う順
+    Similarity,
+}
+
+/// 分類のしかた
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // 分類先のディレクトリに移動する
+    #[default]
+    Move,
+    // 画像はそのままにして，分類先の値をラベルとして付ける
+    Tag,
+}
+
+/// 分類先に設定された操作
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Move(PathBuf),
+    // 以降のセッションでも表示しない
+    Skip,
+    Trash,
+    // このセッションの最後にもう一度表示する
+    Defer,
+}
+
+impl Action {
+    /// 設定された分類先から操作を決める．"skip" ("ignore")，"defer"，"trash" は特別扱い
+    pub fn from_dest(dest: &Path) -> Self {
+        if dest == Path::new("skip") || dest == Path::new("ignore") {
+            Action::Skip
+        } else if dest == Path::new("defer") {
+            Action::Defer
+        } else if dest == Path::new("trash") {
+            Action::Trash
+        } else {
+            Action::Move(dest.to_path_buf())
+        }
+    }
+
+    /// 設定上の分類先
+    pub fn dest(&self) -> &Path {
+        match self {
+            Action::Move(dest) => dest,
+            Action::Skip => Path::new("skip"),
+            Action::Trash => Path::new("trash"),
+            Action::Defer => Path::new("defer"),
+        }
+    }
+}
+
+/// 画像に対する決定
+enum Decision {
+    Move {
+        dest: PathBuf,
+        mode: DestMode,
+        rewrite: Rewrite,
+        // 移動先にあったファイルをゴミ箱に送って上書きした
+        replaced: bool,
+        // 書き直して置き，移動元のファイルはゴミ箱に送った
+        rewritten: bool,
+    },
+    Skip,
+    Trash,
+    // 段階的に反映する設定で保留中の操作
+    Staged(Action),
+    // 後で見直すために印を付けた (ファイルはそのまま)
+    Flagged,
+    // セッションの最後にもう一度表示するまで後回しにした
+    Deferred,
+    // 表示や移動の前に他のプロセスに削除されていた
+    Gone,
+}
+
+/// 取り消せる操作
+enum Step {
+    // 画像に対する決定 (画像の番号)
+    Decide(usize),
+    // ファイルの回転
+    Rotate {
+        idx: usize,
+        path: PathBuf,
+        clockwise: bool,
+        // 書き直して回し，元の画像はゴミ箱に送った
+        rewritten: bool,
+    },
+}
+
+impl Step {
+    /// idx番の画像に対する決定か
+    fn decides(&self, idx: usize) -> bool {
+        matches!(self, Step::Decide(i) if *i == idx)
+    }
 }
 
 const PROCESSED_IMG_BUFSIZE: usize = 7;
+// タグ付けモードでラベルを書き出すファイルの既定の名前
+const LABELS_FILE_NAME: &str = "labels.json";
+// 残りの画像の一覧を書き出すファイルの既定の名前
+const REMAINING_FILE_NAME: &str = "remaining.txt";
+// 先読みしてサムネイルを表示する画像の数
+const THUMB_NUM: usize = 5;
+// サムネイルの長辺のピクセル数
+const THUMB_SIZE: u32 = 256;
+// 分類先の中身を覗くときに並べる最近の画像の数
+const PEEK_NUM: usize = 6;
+// 設定で指定しない場合に対象にする画像の拡張子
+const IMG_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff"];
+// 画像の形式を判定するために読むファイルの先頭のバイト数
+const SNIFF_LEN: usize = 64;
+// 見た目が似ているとみなすハッシュの距離の既定値
+const SIMILAR_THRESHOLD: u32 = 8;
+// 画像を表示する領域の割合の既定値 (%)
+const IMAGE_PERCENT: u16 = 70;
+// 表示する分類の候補の数の既定値
+const SUGGESTION_NUM: usize = 3;
+// 低帯域モードで解像度を落とす割合
+const LOW_BANDWIDTH_SCALE: u32 = 2;
+// 低帯域モードでチャンネルごとに残す階調の数 (6段階で216色)
+const LOW_BANDWIDTH_LEVELS: u32 = 6;
+// シャッフル中に次に表示する画像のデコードが終わっているとき，ワーカーが待つ間隔
+const AHEAD_WAIT: Duration = Duration::from_millis(10);
 
 impl App {
-    pub fn new(config: Config) -> Result<Self> {
+    /// 端末に文字の大きさと表示の方式を問い合わせてから始める
+    pub fn new(config: Config, resume: bool) -> Result<Self> {
+        let queried = Picker::from_query_stdio();
+        let can_query = queried.is_ok();
+        let picker = queried.unwrap_or(Picker::from_fontsize((8, 14)));
+        let mut app = App::with_picker(config, resume, picker)?;
+        app.can_query = can_query;
+        Ok(app)
+    }
+
+    /// 渡された表示用の設定で始める．端末には問い合わせないので，他のツールへの組み込みや端末なしのテストに使う
+    pub fn with_picker(config: Config, resume: bool, mut picker: Picker) -> Result<Self> {
+        // tmuxやSSH越しでは誤って判定されることがあるので，設定されていれば従う
+        if let Some(protocol) = config.protocol {
+            picker.set_protocol_type(protocol.into());
+        }
+        let mut app = App::load(config, resume, picker)?;
+        // 監視中は画像がなくても新しく作られるのを待つ
+        let watch = app.config.watch;
+        if app.imgs.is_empty() && !watch {
+            return Err(anyhow!(
+                "no images found in dir: {}",
+                app.config.base_dir().display()
+            ));
+        }
+        app.apply_rules()?;
+        if app.imgs.is_empty() && !watch {
+            return Err(anyhow!(
+                "all images were classified by rules: {}",
+                app.config.base_dir().display()
+            ));
+        }
+        if app.config.sort == SortKey::Similarity {
+            app.order_by_similarity()?;
+        }
+        if app.config.group_similar {
+            app.group_similar();
+        }
+        if watch {
+            let dirs: Vec<PathBuf> = app
+                .config
+                .dir
+                .iter()
+                .filter(|dir| !config::is_glob(dir))
+                .cloned()
+                .collect();
+            app.watcher = Some(DirWatcher::new(
+                &dirs,
+                app.config.recursive,
+                excluded_dirs(&app.config),
+            )?);
+        }
+        if let Some(model) = &app.config.model {
+            let top_k = app.config.model_top_k.unwrap_or(SUGGESTION_NUM);
+            let suggester = Suggester::load(model, app.config.model_labels.as_deref(), top_k)?;
+            app.suggester = Some(Arc::new(suggester));
+        }
+        if app.config.cache {
+            app.cache = Some(Arc::new(ImgCache::open()?));
+        }
+        app.spawn_workers();
+        Ok(app)
+    }
+
+    /// TUIを使わずに分類するため，画像をデコードせずに一覧だけを読み込む
+    pub fn headless(config: Config, resume: bool) -> Result<Self> {
+        // 端末に問い合わせないよう既定のフォントサイズを使う
+        App::load(config, resume, Picker::from_fontsize((8, 14)))
+    }
+
+    /// 分類する画像の一覧を読み込む．ワーカーは作らない
+    fn load(config: Config, resume: bool, picker: Picker) -> Result<Self> {
         // imagesの取得
-        if !config.dir.is_dir() {
-            return Err(anyhow!("dir is not valid: {}", config.dir.display()));
+        let imgs = find_images(&config)?;
+        let base_dir = config.base_dir();
+
+        // 前回のセッションで決定済みの画像は除く
+        let session = if resume {
+            Session::load(&base_dir)?
+        } else {
+            Session::new(&base_dir)
+        };
+        // 過去のセッションでスキップした画像も除く
+        let done = DoneList::load(&base_dir)?;
+        let mut imgs: Vec<PathBuf> = imgs
+            .into_iter()
+            // 一覧で指定した画像は以前にスキップしていても表示する
+            .filter(|img| !session.is_done(img) && (config.files.is_some() || !done.contains(img)))
+            .collect();
+        // 種を指定しなければ毎回変える
+        let seed = config.seed.unwrap_or_else(rand::random);
+        sort_images(&mut imgs, config.sort, config.descending, seed);
+        let journal = config.journal.as_deref().map(Journal::open).transpose()?;
+        let labels = match config.mode {
+            Mode::Move => None,
+            Mode::Tag => {
+                let path = config
+                    .labels
+                    .clone()
+                    .unwrap_or_else(|| base_dir.join(LABELS_FILE_NAME));
+                Some(Labels::load(&path, &base_dir)?)
+            }
+        };
+
+        let low_bandwidth = Arc::new(AtomicBool::new(config.low_bandwidth));
+        // ワーカーは規則で分類した残りの画像が決まってから作る
+        let (_, rx) = sync_channel(0);
+        Ok(App {
+            trash: Trash::new(config.trash_dir.clone()),
+            config,
+            decisions: (0..imgs.len()).map(|_| None).collect(),
+            // うまく使わない方法を模索している
+            // 不変参照かつAppのほうが長生きな気がするので
+            imgs: Arc::new(imgs),
+            rx,
+            reorder_buf: BTreeMap::new(),
+            next_idx: 0,
+            wanted: Arc::new(AtomicUsize::new(0)),
+            new_thumbs: Vec::new(),
+            log: None,
+            logs: Vec::new(),
+            log_imgs: Vec::new(),
+            shown: Vec::new(),
+            shown_set: HashSet::new(),
+            pos: 0,
+            history: Vec::new(),
+            redo: Vec::new(),
+            later: Vec::new(),
+            session,
+            done,
+            journal,
+            labels,
+            captions: HashMap::new(),
+            caption_files: HashSet::new(),
+            conflict: None,
+            hashes: HashMap::new(),
+            content_hashes: HashMap::new(),
+            dest_indexes: HashMap::new(),
+            duplicates: HashMap::new(),
+            similar: HashMap::new(),
+            suggestions: HashMap::new(),
+            suggester: None,
+            cache: None,
+            texts: HashMap::new(),
+            rule_texts: Arc::new(HashMap::new()),
+            show_text: Arc::new(AtomicBool::new(true)),
+            metas: HashMap::new(),
+            failure_num: 0,
+            decode_errors: BTreeMap::new(),
+            started: Instant::now(),
+            picker: Arc::new(RwLock::new(picker)),
+            stale: HashSet::new(),
+            low_bandwidth,
+            can_query: false,
+            handles: Vec::new(),
+            stats: Arc::new(WorkerStats::default()),
+            cancelled: Arc::new(Mutex::new(HashSet::new())),
+            filter: None,
+            deferred: Arc::new(Mutex::new(HashSet::new())),
+            shuffled: None,
+            seed,
+            ahead: Arc::new(Mutex::new(Vec::new())),
+            received_num: 0,
+            watcher: None,
+            watch_tx: None,
+        })
+    }
+
+    /// 設定の規則に一致する画像を表示せずに分類し，残りの画像だけを手作業の対象にする
+    pub fn apply_rules(&mut self) -> Result<()> {
+        // タグ付けモードでは使わない
+        if self.config.rules.is_empty() || self.labels.is_some() {
+            return Ok(());
+        }
+        let rules = std::mem::take(&mut self.config.rules);
+        let ocr = self.config.ocr.clone();
+        let mut texts = HashMap::new();
+        let result = self.apply_auto(|src| {
+            let (dest, text) = rules::classify(&rules, src, ocr.as_deref());
+            if let Some(text) = text {
+                texts.insert(src.to_path_buf(), text);
+            }
+            dest.map(Action::from_dest)
+        });
+        self.config.rules = rules;
+        // 規則の判定で読み取った文字列は，表示するときにOCRをやり直さず使う
+        self.rule_texts = Arc::new(texts);
+        result
+    }
+
+    /// 以前に記録した決定と同じ名前の画像を同じように分類する
+    pub fn apply_decisions(&mut self, entries: &[SessionEntry]) -> Result<()> {
+        let actions: HashMap<&OsStr, Action> = entries
+            .iter()
+            .filter_map(|entry| {
+                let action = match entry {
+                    SessionEntry::Move { dest, .. } => Action::Move(dest.parent()?.to_path_buf()),
+                    SessionEntry::Skip { .. } => Action::Skip,
+                    SessionEntry::Trash { .. } => Action::Trash,
+                    // コピーは決定ではないので繰り返さない
+                    SessionEntry::Copy { .. } => return None,
+                };
+                Some((entry.src().file_name()?, action))
+            })
+            .collect();
+        self.apply_auto(|src| actions.get(src.file_name()?).cloned())
+    }
+
+    /// 確認画面を出さずにまとめて分類するときの衝突の扱い．確認できないので番号を付けて解決する
+    fn unattended_policy(&self) -> CollisionPolicy {
+        match self.config.collision {
+            CollisionPolicy::Ask => CollisionPolicy::RenameWithSuffix,
+            policy => policy,
+        }
+    }
+
+    /// 操作が決まる画像を表示せずに分類し，残りの画像だけを手作業の対象にする
+    fn apply_auto(&mut self, mut action_of: impl FnMut(&Path) -> Option<Action>) -> Result<()> {
+        let policy = self.unattended_policy();
+        let mut rest = Vec::new();
+        let mut ruled_num = 0;
+        for idx in 0..self.imgs.len() {
+            // 後回しにする画像は手作業で分類するときに後回しにする
+            let action = action_of(&self.imgs[idx]).filter(|action| *action != Action::Defer);
+            let Some(action) = action else {
+                rest.push(self.imgs[idx].clone());
+                continue;
+            };
+            let result = match self.perform(idx, &action, policy) {
+                Ok(Some((_, entry))) => self.record(entry),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            // 分類に失敗した画像は手作業で分類できるよう残す
+            if self.count_failure(result).is_ok() {
+                ruled_num += 1;
+            } else {
+                rest.push(self.imgs[idx].clone());
+            }
         }
-        let imgs = find_images_in_dir(&config.dir)?;
-        if imgs.is_empty() {
-            return Err(anyhow!("no images found in dir: {}", config.dir.display()));
+        self.decisions = (0..rest.len()).map(|_| None).collect();
+        self.imgs = Arc::new(rest);
+        if ruled_num > 0 {
+            self.push_log(AppLog::Rules(ruled_num));
         }
-        let img_num = imgs.len();
+        Ok(())
+    }
+
+    /// 自動で分類する規則が設定されているか
+    pub fn has_rules(&self) -> bool {
+        !self.config.rules.is_empty()
+    }
+
+    /// 手作業で分類する残りの画像の数
+    pub fn rest_num(&self) -> usize {
+        self.imgs.len()
+    }
 
-        // うまく使わない方法を模索している
-        // 不変参照かつAppのほうが長生きな気がするので
-        let imgs = Arc::new(imgs);
+    /// 画像をデコードするワーカーを作る
+    fn spawn_workers(&mut self) {
+        let imgs = self.imgs.clone();
 
         // スレッド作成の準備
-        let worker_num = match available_parallelism() {
-            Ok(n) => max(n.get() - 1, 1),
-            Err(_) => 1,
+        let worker_num = match (self.config.workers, available_parallelism()) {
+            (Some(n), _) => max(n, 1),
+            (None, Ok(n)) => max(n.get() - 1, 1),
+            (None, Err(_)) => 1,
         };
 
-        let (tx, rx) = sync_channel::<ProcessedImg>(PROCESSED_IMG_BUFSIZE);
-        let picker = Picker::from_query_stdio().unwrap_or(Picker::from_fontsize((8, 14)));
-        let next_idx = Arc::new(AtomicUsize::new(0));
+        let bufsize = self.config.prefetch.unwrap_or(PROCESSED_IMG_BUFSIZE);
+        let (tx, rx) = sync_channel::<ProcessedImg>(bufsize);
+        let context = self.decode_context();
+        let queue = Arc::new(DecodeQueue::new(
+            0..imgs.len(),
+            self.wanted.clone(),
+            self.deferred.clone(),
+            self.ahead.clone(),
+        ));
 
         // スレッド作成
         let mut handles: Vec<JoinHandle<()>> = Vec::new();
         for _ in 0..worker_num {
             let thread_tx = tx.clone();
-            let thread_next_idx = next_idx.clone();
+            let thread_queue = queue.clone();
             let thread_imgs = imgs.clone();
-            let thread_picker = picker.clone();
-            let handle = thread::spawn(move || loop {
-                let idx = thread_next_idx.fetch_add(1, Ordering::Relaxed);
-                if idx >= img_num {
-                    break;
-                }
+            let thread_context = context.clone();
+            let handle = thread::spawn(move || {
+                decode_worker(&thread_imgs, &thread_queue, &thread_tx, &thread_context)
+            });
+            handles.push(handle);
+        }
+        // 監視中は後から追加した画像も送るので，全て送り終えても閉じない
+        if self.watcher.is_some() {
+            self.watch_tx = Some(tx);
+        }
 
-                // 画像処理
-                let Ok(reader) = ImageReader::open(&thread_imgs[idx]) else {
-                    eprintln!("cannot open file {}", thread_imgs[idx].display());
-                    continue;
-                };
+        self.rx = rx;
+        self.handles = handles;
+    }
 
-                let Ok(dynamic_img) = reader.decode() else {
-                    eprintln!("cannot decpde image {}", thread_imgs[idx].display());
-                    continue;
-                };
+    /// 監視しているディレクトリに新しく作られた画像を列の最後に加えてデコードする
+    fn append_new_images(&mut self) {
+        let (Some(watcher), Some(tx)) = (&mut self.watcher, &self.watch_tx) else {
+            return;
+        };
+        let new_imgs: Vec<PathBuf> = watcher
+            .poll()
+            .into_iter()
+            .filter(|path| {
+                is_target(path, &self.config)
+                    && !self.imgs.contains(path)
+                    && !self.session.is_done(path)
+                    && !self.done.contains(path)
+            })
+            .collect();
+        // 監視を始めた後に作られた分類先に置いたファイルは，監視側では除けない
+        let new_imgs: Vec<PathBuf> = new_imgs
+            .into_iter()
+            .filter(|path| !self.is_in_dest(path))
+            .collect();
+        if new_imgs.is_empty() {
+            return;
+        }
 
-                let state = thread_picker.new_resize_protocol(dynamic_img);
+        let start = self.imgs.len();
+        let mut imgs = self.imgs.to_vec();
+        imgs.extend(new_imgs);
+        self.imgs = Arc::new(imgs);
+        self.decisions.resize_with(self.imgs.len(), || None);
+        self.update_deferred();
 
-                if thread_tx.send(ProcessedImg { state, idx }).is_err() {
-                    break;
-                }
-            });
-            handles.push(handle);
+        let thread_imgs = self.imgs.clone();
+        if let Some(shuffled) = &mut self.shuffled {
+            // シャッフル中に加わった画像は最後に回す
+            shuffled.extend(start..self.imgs.len());
         }
-        drop(tx);
+        let thread_queue = DecodeQueue::new(
+            start..self.imgs.len(),
+            self.wanted.clone(),
+            self.deferred.clone(),
+            self.ahead.clone(),
+        );
+        let thread_tx = tx.clone();
+        let thread_context = self.decode_context();
+        self.handles.push(thread::spawn(move || {
+            decode_worker(&thread_imgs, &thread_queue, &thread_tx, &thread_context)
+        }));
+    }
 
-        let app = App {
-            config,
-            imgs: imgs,
-            rx,
-            log: None,
-            idx: 0,
-            handles,
-        };
+    /// ワーカーに渡す画像ごとの処理に使うもの
+    fn decode_context(&self) -> DecodeContext {
+        DecodeContext {
+            picker: self.picker.clone(),
+            suggester: self.suggester.clone(),
+            ocr: self.config.ocr.clone(),
+            rule_texts: self.rule_texts.clone(),
+            show_text: self.show_text.clone(),
+            cache: self.cache.clone(),
+            stats: self.stats.clone(),
+            cancelled: self.cancelled.clone(),
+            low_bandwidth: self.low_bandwidth.clone(),
+            alpha_background: self.config.alpha_background,
+        }
+    }
+
+    /// OCRで文字列を読み取るか
+    pub fn has_ocr(&self) -> bool {
+        self.config.ocr.is_some()
+    }
+
+    /// 文字列の欄の表示を切り替える．隠している間に届いた表示中の画像は，ここで読み取る
+    pub fn set_show_text(&mut self, show: bool) -> Option<String> {
+        self.show_text.store(show, Ordering::Relaxed);
+        let idx = self.current_idx().filter(|_| show)?;
+        if let Some(text) = self.texts.get(&idx) {
+            return Some(text.clone());
+        }
+        let ocr = self.config.ocr.as_deref()?;
+        let text = hook::capture(ocr, &self.imgs[idx]).ok()?.trim().to_string();
+        if text.is_empty() {
+            return None;
+        }
+        self.texts.insert(idx, text.clone());
+        Some(text)
+    }
+
+    /// ワーカーとデコードの列の今の状態
+    pub fn worker_status(&self) -> WorkerStatus {
+        let sent = self.stats.sent.load(Ordering::Relaxed);
+        WorkerStatus {
+            workers: self.handles.iter().filter(|h| !h.is_finished()).count(),
+            reading: self.stats.reading.load(Ordering::Relaxed),
+            decoding: self.stats.decoding.load(Ordering::Relaxed),
+            blocked: self.stats.blocked.load(Ordering::Relaxed),
+            ready: self.reorder_buf.len() + sent.saturating_sub(self.received_num),
+            left: self.imgs.len().saturating_sub(sent),
+            decoded: sent,
+        }
+    }
+
+    /// 監視中で，新しい画像が作られるのを待っているか
+    pub fn is_waiting(&self) -> bool {
+        self.watcher.is_some() && self.next_idx >= self.imgs.len()
+    }
+
+    /// 次の未分類の画像に進む．まだデコード中の場合はNone
+    pub fn get_img(&mut self) -> Result<Option<ImgInfo>> {
+        // 戻って表示した画像の先に未分類の画像が残っていればそちらを優先する
+        let next_pos =
+            (self.pos + 1..self.shown.len()).find(|&pos| self.decisions[self.shown[pos]].is_none());
+        if let Some(pos) = next_pos {
+            self.pos = pos;
+            return self.current_img().map(Some);
+        }
+        self.poll_img()
+    }
+
+    /// 表示中の画像を読み込み直す
+    pub fn current_img(&self) -> Result<ImgInfo> {
+        let idx = *self.shown.get(self.pos).context("no image shown")?;
+        let path = self.current_path(idx);
+        Ok(match self.decode_img(&path) {
+            Ok(frames) => self.img_info(idx, frames),
+            // 取り消して戻した画像などデコードできなくても分類はできるようにする
+            Err(e) => ImgInfo {
+                error: Some(format!("{:#}", e)),
+                ..self.img_info(idx, Vec::new())
+            },
+        })
+    }
 
-        return Ok(app);
+    /// 分類の有無に関わらず1つ前に表示した画像に戻る
+    pub fn go_prev(&mut self) -> Result<ImgInfo> {
+        self.conflict = None;
+        // ゴミ箱に送った画像など表示できないものは飛ばす
+        for pos in (0..self.pos).rev() {
+            let path = self.current_path(self.shown[pos]);
+            if let Ok(frames) = self.decode_img(&path) {
+                self.pos = pos;
+                return Ok(self.img_info(self.shown[pos], frames));
+            }
+        }
+        Err(anyhow!("no previous image"))
+    }
+
+    /// 分類の有無に関わらず次の画像に進む．まだデコード中の場合はNone
+    pub fn go_next(&mut self) -> Result<Option<ImgInfo>> {
+        self.conflict = None;
+        for pos in self.pos + 1..self.shown.len() {
+            let path = self.current_path(self.shown[pos]);
+            if let Ok(frames) = self.decode_img(&path) {
+                self.pos = pos;
+                return Ok(Some(self.img_info(self.shown[pos], frames)));
+            }
+        }
+        self.poll_img()
     }
 
-    pub fn get_img(&mut self) -> Result<ImgInfo> {
-        match self.rx.recv() {
-            Ok(r) => Ok({
-                self.idx = r.idx;
-                ImgInfo {
-                    state: r.state,
-                    path: self.imgs[r.idx].clone(),
+    /// まだ表示していない画像をワーカーから番号順に受け取る
+    ///
+    /// 待たずに返し，次の画像がまだデコード中の場合はNone，全て受け取り済みの場合はエラーになる
+    pub fn poll_img(&mut self) -> Result<Option<ImgInfo>> {
+        loop {
+            // 先の画像に移って飛ばした画像は，最後まで表示してから戻って表示する
+            if self.next_idx >= self.imgs.len() {
+                // 絞り込みに合う画像がなくなったら，絞り込みをやめて残りの画像を表示する
+                if self.filter.is_some() && self.queue().next().is_none() {
+                    self.filter = None;
+                    self.update_deferred();
+                    self.update_ahead();
+                    self.push_log(AppLog::FilterCleared);
+                }
+                let skipped = self.queue().next();
+                if let Some(idx) = skipped {
+                    self.next_idx = idx;
+                    self.wanted.store(idx, Ordering::Relaxed);
+                    self.update_ahead();
+                } else if let Some(img_info) = self.show_deferred() {
+                    return Ok(Some(img_info));
+                }
+            }
+            // 表示済みの画像や絞り込みに合わない画像は届くのを待たずに飛ばす
+            if self.next_idx < self.imgs.len()
+                && (self.shown_set.contains(&self.next_idx) || !self.in_filter(self.next_idx))
+            {
+                self.advance();
+                continue;
+            }
+            let Some(frames) = self.reorder_buf.remove(&self.next_idx) else {
+                match self.rx.try_recv() {
+                    Ok(r) => {
+                        self.receive(r);
+                        continue;
+                    }
+                    Err(TryRecvError::Empty) => return Ok(None),
+                    Err(TryRecvError::Disconnected) => return Err(anyhow!("no more images")),
+                }
+            };
+            let idx = self.next_idx;
+            // デコードした後に消えた画像は表示せずに飛ばす
+            if self.mark_gone(idx) {
+                self.push_shown(idx);
+                self.advance();
+                continue;
+            }
+            self.push_shown(idx);
+            self.pos = self.shown.len() - 1;
+            self.advance();
+
+            let frames = match frames {
+                Some(frames) => frames,
+                // 文字の大きさなどが変わる前に作った画像は作り直す
+                None if self.stale.remove(&idx) => {
+                    self.decode_img(&self.imgs[idx]).unwrap_or_default()
                 }
-            }),
-            Err(e) => Err(e.into()),
+                // デコードに失敗した画像も分類できるよう，フレームなしで表示する
+                None => Vec::new(),
+            };
+            return Ok(Some(self.img_info(idx, frames)));
         }
     }
 
-    pub fn get_app_info(&self) -> AppInfo {
-        AppInfo {
-            img_num: self.imgs.len(),
-            keybind: self.config.dests.clone(),
+    /// 画像が他のプロセスに削除されていれば，分類せずに済ませたことにして履歴に残す
+    fn mark_gone(&mut self, idx: usize) -> bool {
+        let src = &self.imgs[idx];
+        if src.exists() {
+            return false;
         }
+        let file_name = src.file_name().unwrap_or_default().into();
+        self.decisions[idx] = Some(Decision::Gone);
+        self.push_log(AppLog::Missing(file_name));
+        true
     }
 
-    /// キー入力に基づいてアクションを実行する
-    pub fn on_key(&mut self, key: char) -> Result<()> {
-        if let Some(dest) = self.config.dests.get(&key) {
-            // "skip" は特別扱い
-            if dest == Path::new("skip") {
-                self.log = Some(AppLog::Skip(
-                    self.imgs[self.idx]
-                        .file_name()
-                        .context("skip filename cannot get")?
-                        .into(),
-                ));
-            } else {
-                let log = self.move_img(dest, &self.imgs[self.idx])?;
-                self.log = Some(log);
+    /// 後回しにした画像を古いものから1枚取り出し，最後に表示した画像として表示し直す
+    fn show_deferred(&mut self) -> Option<ImgInfo> {
+        while !self.later.is_empty() {
+            let idx = self.later.remove(0);
+            // 後回しにした後で分類し直した画像は飛ばす
+            if !matches!(self.decisions[idx], Some(Decision::Deferred)) {
+                continue;
             }
+            self.decisions[idx] = None;
+            self.history.retain(|step| !step.decides(idx));
+            self.shown.retain(|&i| i != idx);
+            self.shown.push(idx);
+            self.pos = self.shown.len() - 1;
+            let frames = self.decode_img(&self.imgs[idx]).unwrap_or_default();
+            return Some(self.img_info(idx, frames));
         }
-        Ok(())
+        None
     }
 
-    /// 現在の画像を新しいディレクトリに移動する
-    fn move_img(&self, dest: &Path, src: &Path) -> Result<AppLog> {
-        let file_name = src.file_name().context("Failed to get file name")?;
+    /// 直前の操作を記録し，履歴にも加える
+    pub fn push_log(&mut self, log: AppLog) {
+        self.logs.push(log.clone());
+        self.log_imgs.push(None);
+        self.log = Some(log);
+    }
 
-        fs::create_dir_all(dest).with_context(|| {
-            format!("Failed to create destination directory: {}", dest.display())
-        })?;
+    /// 画像の決定を変えた操作を記録する．履歴から個別に取り消せるようになる
+    fn push_img_log(&mut self, idx: usize, log: AppLog) {
+        self.push_log(log);
+        if let Some(last) = self.log_imgs.last_mut() {
+            *last = Some(idx);
+        }
+    }
 
-        let dest = dest.join(file_name);
+    /// このセッションで行った操作の履歴
+    pub fn logs(&self) -> &[AppLog] {
+        &self.logs
+    }
 
-        if !dest.exists() {
-            fs::rename(src, &dest).with_context(|| {
-                format!(
-                    "Failed to move image from {} to {}",
-                    src.display(),
-                    dest.display()
-                )
-            })?;
-        } else {
-            return Err(anyhow!("move destination has same name file"));
+    /// 次に受け取る画像の番号を進める
+    fn advance(&mut self) {
+        self.next_idx = match &self.shuffled {
+            // シャッフル中は並べた順で次の画像を選ぶ
+            Some(_) => self.queue().next().unwrap_or(self.imgs.len()),
+            None => self.next_idx + 1,
+        };
+        self.wanted.store(self.next_idx, Ordering::Relaxed);
+        self.update_ahead();
+    }
+
+    /// シャッフル中は次に表示する画像からワーカーにデコードさせる
+    fn update_ahead(&self) {
+        let ahead = match &self.shuffled {
+            Some(_) => {
+                let num = self.config.prefetch.unwrap_or(PROCESSED_IMG_BUFSIZE) + THUMB_NUM;
+                let next = Some(self.next_idx).filter(|&idx| idx < self.imgs.len());
+                // 次の画像はまだ表示していないのでqueueにも含まれる
+                next.into_iter()
+                    .chain(self.queue().filter(|&idx| idx != self.next_idx))
+                    .take(num)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        if let Ok(mut shared) = self.ahead.lock() {
+            *shared = ahead;
         }
+    }
+
+    /// まだ表示していない画像をシャッフルした順に表示するかを切り替える
+    ///
+    /// 順番は乱数の種で決まるので，同じ種なら同じ順になる
+    pub fn toggle_shuffle(&mut self) {
+        self.shuffled = match self.shuffled {
+            Some(_) => None,
+            None => {
+                let mut order: Vec<usize> = (0..self.imgs.len()).collect();
+                order.shuffle(&mut StdRng::seed_from_u64(self.seed));
+                Some(order)
+            }
+        };
+        self.next_idx = match self.shuffled {
+            Some(_) => self.queue().next().unwrap_or(self.imgs.len()),
+            // 元の順に戻し，まだ表示していない最初の画像から表示する
+            None => 0,
+        };
+        self.wanted.store(self.next_idx, Ordering::Relaxed);
+        self.update_ahead();
+    }
 
-        Ok(AppLog::MoveSuccess(file_name.into(), dest))
+    /// シャッフルした順に表示しているか
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffled.is_some()
     }
-}
 
-impl Drop for App {
-    fn drop(&mut self) {
-        while let Some(handle) = self.handles.pop() {
-            if let Err(e) = handle.join() {
-                eprintln!("error in thread {:?}", e);
+    /// 並べる順を決める乱数の種
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// ワーカーから届いている画像を先読みしておく
+    pub fn prefetch(&mut self) {
+        self.append_new_images();
+        while self.reorder_buf.len() < THUMB_NUM {
+            match self.rx.try_recv() {
+                Ok(r) => self.receive(r),
+                Err(_) => break,
             }
         }
     }
-}
 
-/// 指定されたディレクトリから画像ファイルの一覧を取得する
-fn find_images_in_dir(dir: &Path) -> Result<Vec<PathBuf>> {
-    let img_extensions = ["jpg", "jpeg", "png", "gif", "bmp"];
-    let images = fs::read_dir(dir)
-        .with_context(|| format!("cannot read dir: {}", dir.display()))?
-        .filter_map(Result::ok)
-        .map(|entry| entry.path())
-        .filter(|path| {
-            path.is_file()
-                && path
-                    .extension()
-                    .and_then(|s| s.to_str())
-                    .map_or(false, |ext| {
-                        img_extensions.contains(&ext.to_lowercase().as_str())
-                    })
-        })
-        .collect();
-    Ok(images)
+    fn receive(&mut self, r: ProcessedImg) {
+        self.received_num += 1;
+        if let Some(thumb) = r.thumb {
+            self.new_thumbs.push((r.idx, thumb));
+        }
+        if let Some(hash) = r.hash {
+            self.content_hashes.insert(r.idx, hash);
+            // 届く順番は前後するので，番号の小さいほうを元の画像とする
+            let first = self.hashes.entry(hash).or_insert(r.idx);
+            if *first > r.idx {
+                self.duplicates.insert(*first, r.idx);
+                *first = r.idx;
+            } else if *first < r.idx {
+                self.duplicates.insert(r.idx, *first);
+            }
+        }
+        if !r.suggestions.is_empty() {
+            self.suggestions.insert(r.idx, r.suggestions);
+        }
+        if let Some(text) = r.text {
+            self.texts.insert(r.idx, text);
+        }
+        if let Some(meta) = r.meta {
+            self.metas.insert(r.idx, meta);
+        }
+        if let Some(error) = r.error {
+            self.decode_errors.insert(r.idx, error);
+        }
+        let mut frames = r.frames;
+        if r.render
+            .is_some_and(|render| render != self.render_params())
+        {
+            frames = None;
+            self.stale.insert(r.idx);
+        }
+        // ギャラリーなどで表示せずに分類した画像は溜めておかない
+        if !self.shown_set.contains(&r.idx) {
+            self.reorder_buf.insert(r.idx, frames);
+        }
+    }
+
+    /// 埋め込みが近い画像が隣り合うよう並べ替える．ワーカーを作る前に呼ぶ
+    fn order_by_similarity(&mut self) -> Result<()> {
+        let model = self
+            .config
+            .embedding_model
+            .as_deref()
+            .map(similar::load_encoder)
+            .transpose()?;
+        let embeddings = similar::embeddings(&self.imgs, model.as_ref());
+        let order = similar::chain_order(&embeddings);
+        self.imgs = Arc::new(order.iter().map(|&idx| self.imgs[idx].clone()).collect());
+        Ok(())
+    }
+
+    /// 見た目が似ている画像が続けて表示されるよう並べ替える．ワーカーを作る前に呼ぶ
+    fn group_similar(&mut self) {
+        let hashes = similar::hashes(&self.imgs);
+        let threshold = self.config.similar_threshold.unwrap_or(SIMILAR_THRESHOLD);
+        let groups = similar::groups(&hashes, threshold);
+        // 各グループを最初の画像の位置にまとめる
+        let order: Vec<usize> = groups.concat();
+        let mut pos = 0;
+        for group in &groups {
+            let members: Vec<usize> = (pos..pos + group.len()).collect();
+            if members.len() > 1 {
+                for &idx in &members {
+                    let others = members.iter().copied().filter(|&i| i != idx).collect();
+                    self.similar.insert(idx, others);
+                }
+            }
+            pos += group.len();
+        }
+        self.imgs = Arc::new(order.iter().map(|&idx| self.imgs[idx].clone()).collect());
+    }
+
+    /// 表示中の画像と似ている，まだ決定していない画像の数
+    pub fn similar_num(&self) -> usize {
+        self.current_idx()
+            .and_then(|idx| self.similar.get(&idx))
+            .map_or(0, |others| {
+                others
+                    .iter()
+                    .filter(|&&i| self.decisions[i].is_none())
+                    .count()
+            })
+    }
+
+    /// 表示中の画像を残し，似ている画像のうちまだ決定していないものをまとめて分類する
+    pub fn dispatch_similar(&mut self) -> Result<()> {
+        if self.labels.is_some() {
+            return Ok(());
+        }
+        let Some(others) = self.current_idx().and_then(|idx| self.similar.get(&idx)) else {
+            return Ok(());
+        };
+        let targets: Vec<usize> = others
+            .iter()
+            .copied()
+            .filter(|&i| self.decisions[i].is_none())
+            .collect();
+        let dest = self.discard_dest();
+        self.act_many(&targets, &dest)
+    }
+
+    /// 比較表示で残さなかった画像を分類する
+    pub fn discard(&mut self, idx: usize) -> Result<()> {
+        if self.labels.is_some() {
+            return Ok(());
+        }
+        let dest = self.discard_dest();
+        self.act_many(&[idx], &dest)
+    }
+
+    /// 残さなかった似ている画像の分類先
+    fn discard_dest(&self) -> PathBuf {
+        self.config
+            .similar_dest
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("trash"))
+    }
+
+    /// 比較表示でafterの次に並べる，まだ表示していない画像の番号
+    pub fn compare_next(&self, after: usize) -> Option<usize> {
+        ((after + 1).max(self.next_idx)..self.imgs.len()).find(|idx| !self.shown_set.contains(idx))
+    }
+
+    /// 比較表示用に画像をデコードする．アニメーションは最初のフレームのみ
+    pub fn compare_img(&self, idx: usize) -> Result<CompareImg> {
+        let path = self.current_path(idx);
+        let img = self.load_img(&path)?;
+        Ok(CompareImg {
+            idx,
+            state: self.new_protocol(img),
+            path,
+        })
+    }
+
+    /// キーに割り当てた分類先のディレクトリにある画像を，更新日時が新しいものから読み込む
+    pub fn peek(&self, key: KeyBind) -> Result<Peek> {
+        let dest = self
+            .config
+            .dests
+            .get(&key)
+            .context("no destination is bound to the key")?;
+        let Action::Move(path) = Action::from_dest(&dest.path) else {
+            bail!("{} is not a directory", dest.name());
+        };
+        // テンプレートの分類先は表示中の画像を送る先を覗く
+        let dir = match self.current_idx() {
+            Some(idx) => template::expand(&path, &self.imgs[idx])?,
+            None => path,
+        };
+        // まだ作られていない分類先は空として扱う
+        let mut files: Vec<(SystemTime, PathBuf)> = fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && is_image(path, &self.config))
+            .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+            .collect();
+        files.sort_by_key(|(modified, _)| Reverse(*modified));
+        let imgs = files
+            .into_iter()
+            .filter_map(|(_, path)| {
+                let img = self.load_img(&path).ok()?;
+                let state = self.new_protocol(img.thumbnail(THUMB_SIZE, THUMB_SIZE));
+                Some((path, state))
+            })
+            .take(PEEK_NUM)
+            .collect();
+        Ok(Peek {
+            name: dest.name(),
+            imgs,
+        })
+    }
+
+    /// 新しく届いたサムネイルを取り出す
+    pub fn take_thumbs(&mut self) -> Vec<(usize, StatefulProtocol)> {
+        std::mem::take(&mut self.new_thumbs)
+    }
+
+    /// これから表示する画像の番号
+    pub fn upcoming(&self) -> Vec<usize> {
+        self.queue().take(THUMB_NUM).collect()
+    }
+
+    /// 画像を表示したものとして並びに加える
+    fn push_shown(&mut self, idx: usize) {
+        self.shown.push(idx);
+        self.shown_set.insert(idx);
+    }
+
+    /// まだ表示していない画像の番号を表示する順に並べたもの
+    ///
+    /// 先の画像に移った場合は，飛ばした画像を最後に回す．シャッフル中はシャッフルした順にする
+    fn queue(&self) -> impl Iterator<Item = usize> + '_ {
+        let sequential = (self.next_idx..self.imgs.len())
+            .chain(0..self.next_idx)
+            .filter(|_| self.shuffled.is_none());
+        self.shuffled
+            .iter()
+            .flatten()
+            .copied()
+            .chain(sequential)
+            .filter(|&idx| !self.shown_set.contains(&idx) && self.in_filter(idx))
+    }
+
+    /// 表示する画像をファイル名のパターンで絞り込む．空なら絞り込みをやめる
+    ///
+    /// ワイルドカードを含まなければファイル名の一部として扱う
+    pub fn set_filter(&mut self, text: &str) -> Result<()> {
+        let text = text.trim();
+        self.filter = if text.is_empty() {
+            None
+        } else {
+            let pattern = if config::is_glob(Path::new(text)) {
+                text.to_string()
+            } else {
+                format!("*{}*", glob::Pattern::escape(text))
+            };
+            let pattern = glob::Pattern::new(&pattern)
+                .with_context(|| format!("invalid filter pattern: {}", text))?;
+            Some((text.to_string(), pattern))
+        };
+        self.update_deferred();
+        self.update_ahead();
+        Ok(())
+    }
+
+    /// 絞り込みに使っている文字列
+    pub fn filter(&self) -> Option<String> {
+        self.filter.as_ref().map(|(text, _)| text.clone())
+    }
+
+    /// 画像のファイル名が絞り込みに合うか．大文字と小文字は区別しない
+    pub fn in_filter(&self, idx: usize) -> bool {
+        let Some((_, pattern)) = &self.filter else {
+            return true;
+        };
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        self.imgs[idx]
+            .file_name()
+            .is_some_and(|file| pattern.matches_with(&file.to_string_lossy(), options))
+    }
+
+    /// 絞り込みに合わない画像をワーカーに後回しにさせる
+    fn update_deferred(&self) {
+        if let Ok(mut deferred) = self.deferred.lock() {
+            deferred.clear();
+            deferred.extend((0..self.imgs.len()).filter(|&idx| !self.in_filter(idx)));
+        }
+    }
+
+    /// 指定した番号の画像に移る．まだ表示していなければワーカーに先にデコードさせる
+    pub fn seek(&mut self, idx: usize) -> Result<Option<ImgInfo>> {
+        if idx >= self.imgs.len() {
+            return Err(anyhow!("no image at position {}", idx + 1));
+        }
+        self.conflict = None;
+        if let Some(pos) = self.shown.iter().position(|&shown| shown == idx) {
+            self.pos = pos;
+            return self.current_img().map(Some);
+        }
+        self.next_idx = idx;
+        self.wanted.store(idx, Ordering::Relaxed);
+        self.update_ahead();
+        self.poll_img()
+    }
+
+    /// 表示中の画像の次から順に探して，ファイル名に文字列を含む最初の画像の番号
+    pub fn find_img(&self, name: &str) -> Option<usize> {
+        let name = name.to_lowercase();
+        let start = self.current_idx().map_or(0, |idx| idx + 1);
+        (start..self.imgs.len()).chain(0..start).find(|&idx| {
+            self.imgs[idx]
+                .file_name()
+                .is_some_and(|file| file.to_string_lossy().to_lowercase().contains(&name))
+        })
+    }
+
+    /// 表示中の画像の番号
+    pub fn current_idx(&self) -> Option<usize> {
+        self.shown.get(self.pos).copied()
+    }
+
+    fn img_info(&self, idx: usize, frames: Vec<Frame>) -> ImgInfo {
+        let src = &self.imgs[idx];
+        ImgInfo {
+            frames,
+            path: self.current_path(idx),
+            caption: self.captions.get(src).cloned(),
+            duplicate_of: self
+                .duplicates
+                .get(&idx)
+                .map(|&first| self.current_path(first)),
+            suggestions: self.suggestions.get(&idx).cloned().unwrap_or_default(),
+            text: self.texts.get(&idx).cloned(),
+            meta: self.metas.get(&idx).cloned(),
+            error: self.decode_errors.get(&idx).cloned(),
+            labels: self
+                .labels
+                .as_ref()
+                .map(|labels| labels.get(src))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// 画像の現在の場所
+    fn current_path(&self, idx: usize) -> PathBuf {
+        match &self.decisions[idx] {
+            Some(Decision::Move {
+                dest,
+                mode: DestMode::Move,
+                ..
+            }) => dest.clone(),
+            _ => self.imgs[idx].clone(),
+        }
+    }
+
+    /// 表示中の画像の番号
+    fn idx(&self) -> usize {
+        self.shown[self.pos]
+    }
+
+    /// 拡大表示用に画像をデコードする
+    pub fn load_img(&self, path: &Path) -> Result<DynamicImage> {
+        if heif::sniff(path) {
+            return heif::decode(path);
+        }
+        if raw::is_raw(path) {
+            return raw::decode_preview(path);
+        }
+        if svg::is_svg(path) {
+            return svg::render(path);
+        }
+        if pdf::sniff(path) {
+            return pdf::render(path);
+        }
+        if video::is_video(path) {
+            return video::frame(path);
+        }
+        let reader = ImageReader::open(path)
+            .with_context(|| format!("cannot open file {}", path.display()))?
+            .with_guessed_format()
+            .with_context(|| format!("cannot open file {}", path.display()))?;
+        decode_oriented(reader, path)
+    }
+
+    /// 複数ページの画像のページをデコードする．最初のページ以外はワーカーでデコードしていない
+    pub fn load_page(&self, path: &Path, page: usize) -> Result<DynamicImage> {
+        if page == 0 {
+            return self.load_img(path);
+        }
+        decode_tiff_page(path, page)
+    }
+
+    /// ワーカーと共有している表示用の設定
+    fn picker(&self) -> Picker {
+        read_picker(&self.picker)
+    }
+
+    /// 端末の文字の大きさ (ピクセル)
+    pub fn font_size(&self) -> FontSize {
+        self.picker().font_size()
+    }
+
+    /// 端末の大きさが変わったので文字の大きさを問い合わせ直す．変わっていればtrue
+    pub fn on_resize(&mut self) -> bool {
+        if !self.can_query {
+            return false;
+        }
+        let Ok(mut picker) = Picker::from_query_stdio() else {
+            return false;
+        };
+        let Ok(mut current) = self.picker.write() else {
+            return false;
+        };
+        if picker.font_size() == current.font_size() {
+            return false;
+        }
+        // 表示の方式は変えない
+        picker.set_protocol_type(current.protocol_type());
+        *current = picker;
+        drop(current);
+        self.invalidate_prefetched();
+        true
+    }
+
+    /// 低帯域モードか
+    pub fn is_low_bandwidth(&self) -> bool {
+        self.low_bandwidth.load(Ordering::Relaxed)
+    }
+
+    /// 低帯域モードを切り替える
+    pub fn toggle_low_bandwidth(&mut self) {
+        self.low_bandwidth.fetch_xor(true, Ordering::Relaxed);
+        self.invalidate_prefetched();
+    }
+
+    /// 前の設定で作った先読み済みの画像は表示する前に作り直す
+    fn invalidate_prefetched(&mut self) {
+        for (&idx, frames) in &mut self.reorder_buf {
+            if frames.take().is_some() {
+                self.stale.insert(idx);
+            }
+        }
+    }
+
+    fn render_params(&self) -> RenderParams {
+        RenderParams {
+            font_size: self.font_size(),
+            low_bandwidth: self.is_low_bandwidth(),
+        }
+    }
+
+    /// 画像を読み込み，今の設定で表示用にデコードする
+    fn decode_img(&self, path: &Path) -> Result<Vec<Frame>> {
+        decode_img(
+            path,
+            &self.picker(),
+            self.cache.as_deref(),
+            self.is_low_bandwidth(),
+            self.config.alpha_background,
+        )
+    }
+
+    /// 画像を表示用に変換する
+    pub fn new_protocol(&self, img: DynamicImage) -> StatefulProtocol {
+        let img = flatten_alpha(img, self.config.alpha_background);
+        let img = if self.is_low_bandwidth() {
+            reduce_for_bandwidth(img)
+        } else {
+            img
+        };
+        self.picker().new_resize_protocol(img)
+    }
+
+    /// 決定済みの画像の数．保留中の操作も含める
+    pub fn done_num(&self) -> usize {
+        match &self.labels {
+            Some(labels) => labels.len(),
+            None => {
+                let staged = self
+                    .decisions
+                    .iter()
+                    .filter(|decision| matches!(decision, Some(Decision::Staged(_))))
+                    .count();
+                self.session.done_num() + staged
+            }
+        }
+    }
+
+    /// ここまでの分類の集計
+    pub fn summary(&self) -> Summary {
+        let mut counts = self.counts();
+        let skipped = counts.remove(Path::new("skip")).unwrap_or(0);
+        let trashed = counts.remove(Path::new("trash")).unwrap_or(0);
+        let mut dests: Vec<(PathBuf, usize)> = counts.into_iter().collect();
+        dests.sort();
+        Summary {
+            dests,
+            skipped,
+            trashed,
+            failures: self.failure_num,
+            errors: self.decode_errors(),
+            flagged: self.flagged(),
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    /// デコードに失敗した画像のパスと理由
+    pub fn decode_errors(&self) -> Vec<(PathBuf, String)> {
+        self.decode_errors
+            .iter()
+            .map(|(&idx, error)| (self.imgs[idx].clone(), error.clone()))
+            .collect()
+    }
+
+    /// 分類した画像とラベルの一覧をマニフェストに書き出す
+    pub fn write_manifest(&self) -> Result<()> {
+        let Some(path) = &self.config.manifest else {
+            return Ok(());
+        };
+        let rows: Vec<(PathBuf, String)> = match &self.labels {
+            Some(labels) => labels.rows(),
+            // 移動した画像は分類先の名前をラベルにする
+            None => self
+                .session
+                .entries()
+                .iter()
+                .filter_map(|entry| match entry {
+                    SessionEntry::Move { src, dest, .. } | SessionEntry::Copy { src, dest } => {
+                        let label = match self.dest_of(src, dest) {
+                            Some(d) => manifest_label(d),
+                            // 規則の分類先などは移動先のディレクトリ名にする
+                            None => dest.parent()?.file_name()?.to_string_lossy().into_owned(),
+                        };
+                        Some((dest.clone(), label))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        };
+        manifest::write(
+            path,
+            &rows,
+            self.config.manifest_relative,
+            self.config.val_ratio,
+        )
+    }
+
+    /// 画像を開く外部のビューアのコマンド
+    pub fn viewer(&self) -> Option<&str> {
+        self.config.viewer.as_deref()
+    }
+
+    /// 終了時のコマンドを実行する
+    pub fn run_end_hook(&self) -> Result<()> {
+        match &self.config.end_hook {
+            Some(command) => hook::run(command, &[("dir", &self.config.base_dir())]),
+            None => Ok(()),
+        }
+    }
+
+    /// 設定されていれば集計をファイルに書き出す
+    pub fn write_summary(&self, summary: &Summary) -> Result<()> {
+        match &self.config.summary {
+            Some(path) => summary.write(path),
+            None => Ok(()),
+        }
+    }
+
+    /// 分類先ごとの置く先のファイルシステムの空き容量．テンプレートの分類先は表示中の画像で展開する
+    pub fn free_space(&self) -> HashMap<PathBuf, u64> {
+        let current = self.current_idx().map(|idx| &self.imgs[idx]);
+        self.config
+            .dests
+            .values()
+            .filter(|dest| matches!(Action::from_dest(&dest.path), Action::Move(_)))
+            .filter_map(|dest| {
+                let dir = match current {
+                    Some(src) => template::expand(&dest.path, src).ok()?,
+                    None => dest.path.clone(),
+                };
+                Some((dest.path.clone(), free_space(&dir)?))
+            })
+            .collect()
+    }
+
+    /// 分類先ごとの画像の数
+    pub fn counts(&self) -> HashMap<PathBuf, usize> {
+        if let Some(labels) = &self.labels {
+            return labels.counts();
+        }
+        let mut counts = HashMap::new();
+        for entry in self.session.entries() {
+            let dest = match entry {
+                SessionEntry::Move { dest, .. } => {
+                    let dir = dest.parent().unwrap_or(Path::new(""));
+                    // テンプレートから展開された移動先は設定上の分類先にまとめる
+                    self.config
+                        .dests
+                        .values()
+                        .map(|d| &d.path)
+                        .find(|d| template::is_template(d) && template::matches(d, dir))
+                        .map_or(dir, |d| d.as_path())
+                }
+                SessionEntry::Skip { .. } => Path::new("skip"),
+                SessionEntry::Trash { .. } => Path::new("trash"),
+                SessionEntry::Copy { .. } => continue,
+            };
+            *counts.entry(dest.to_path_buf()).or_insert(0) += 1;
+        }
+        for decision in self.decisions.iter().flatten() {
+            if let Decision::Staged(action) = decision {
+                *counts.entry(action.dest().to_path_buf()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// 以前のセッションで決定済みのものも含めた画像の数
+    pub fn img_num(&self) -> usize {
+        self.imgs.len() + self.session.done_num()
+    }
+
+    pub fn get_app_info(&self) -> AppInfo {
+        AppInfo {
+            img_num: self.img_num(),
+            done_num: self.done_num(),
+            keybind: self.config.dests.clone(),
+            theme: self.config.theme.clone(),
+            layout: self.config.layout,
+            image_percent: self
+                .config
+                .image_percent
+                .unwrap_or(IMAGE_PERCENT)
+                .clamp(10, 90),
+            copy_modifier: self.config.copy_modifier,
+            sticky: self.config.sticky,
+        }
+    }
+
+    /// キー入力に基づいてアクションを実行する
+    pub fn on_key(&mut self, key: KeyBind) -> Result<()> {
+        let result = self.apply_key(key);
+        self.count_failure(result)
+    }
+
+    fn apply_key(&mut self, key: KeyBind) -> Result<()> {
+        if self.shown.is_empty() || self.conflict.is_some() {
+            return Ok(());
+        }
+        let Some(dest) = self.config.dests.get(&key) else {
+            return Ok(());
+        };
+        let idx = self.idx();
+        if let Some(labels) = &mut self.labels {
+            // タグ付けモードではラベルを付け外しするだけで画像は動かさない
+            let label = dest.name();
+            let added = labels.toggle(&self.imgs[idx], &label)?;
+            let file_name = self.imgs[idx].file_name().unwrap_or_default().into();
+            self.push_log(AppLog::Label(file_name, label, added));
+            return Ok(());
+        }
+        let action = Action::from_dest(&dest.path);
+        self.redecide(idx, action, self.config.collision)
+    }
+
+    /// 表示中の画像に付けたラベル
+    pub fn labels(&self) -> Vec<String> {
+        match (&self.labels, self.current_idx()) {
+            (Some(labels), Some(idx)) => labels.get(&self.imgs[idx]),
+            _ => Vec::new(),
+        }
+    }
+
+    /// 修飾キーを押しながら分類先のキーを押したなら，その分類先のキー
+    pub fn copy_key(&self, key: KeyBind) -> Option<KeyBind> {
+        // 修飾キー付きのキーに分類先が割り当てられていればそちらを優先する
+        if self.config.dests.contains_key(&key) || self.is_tagging() {
+            return None;
+        }
+        let base = key.without(self.config.copy_modifier)?;
+        let dest = self.config.dests.get(&base)?;
+        matches!(Action::from_dest(&dest.path), Action::Move(_)).then_some(base)
+    }
+
+    /// 表示中の画像のファイルを90度回転する
+    pub fn rotate_file(&mut self, clockwise: bool) -> Result<()> {
+        if self.shown.is_empty() {
+            return Ok(());
+        }
+        let path = self.current_path(self.idx());
+        let rewritten = rotate_img(&path, clockwise, &self.trash)?;
+        // 中身が変わったので，重複を探すときは読み直す
+        self.content_hashes.remove(&self.idx());
+        self.history.push(Step::Rotate {
+            idx: self.idx(),
+            path: path.clone(),
+            clockwise,
+            rewritten,
+        });
+        let file_name = path.file_name().unwrap_or_default().into();
+        self.push_log(AppLog::Rotate(file_name, clockwise));
+        Ok(())
+    }
+
+    /// 表示中の画像を分類せずに分類先にコピーする．同名のファイルがあれば番号を付ける
+    pub fn copy_to(&mut self, key: KeyBind) -> Result<()> {
+        if self.shown.is_empty() {
+            return Ok(());
+        }
+        let dest = self
+            .config
+            .dests
+            .get(&key)
+            .context("no dest for key")?
+            .path
+            .clone();
+        let idx = self.idx();
+        let src = self.current_path(idx);
+        let mut target = self.target_path(&self.imgs[idx], &dest)?;
+        if target.exists() {
+            target = numbered_path(&target);
+        }
+        let rewrite = self.dest_rewrite(&dest);
+        let result = place_img(&src, &target, DestMode::Copy, rewrite, &self.trash);
+        self.count_failure(result.map(|_| ()))?;
+        self.index_placed(idx, &target, rewrite);
+        let file_name = src.file_name().unwrap_or_default().into();
+        self.push_log(AppLog::Copy(file_name, target.clone()));
+        // 取り消しやマニフェストのためにセッションにも残す
+        self.record(SessionEntry::Copy {
+            src: self.imgs[idx].clone(),
+            dest: target,
+        })
+    }
+
+    /// 送る前に確認する分類先なら，その名前
+    pub fn dest_to_confirm(&self, key: KeyBind) -> Option<String> {
+        let dest = self.config.dests.get(&key).filter(|dest| dest.confirm)?;
+        // ラベルの付け外しは取り消せるので確認しない
+        (!self.is_tagging()).then(|| dest.name())
+    }
+
+    /// タグ付けモードか
+    pub fn is_tagging(&self) -> bool {
+        self.labels.is_some()
+    }
+
+    /// 表示中の画像の説明文
+    pub fn caption(&self) -> Option<String> {
+        let idx = self.current_idx()?;
+        self.captions.get(&self.imgs[idx]).cloned()
+    }
+
+    /// 表示中の画像に説明文を付ける．空の場合は取り除く
+    pub fn set_caption(&mut self, caption: String) -> Result<()> {
+        let Some(idx) = self.current_idx() else {
+            return Ok(());
+        };
+        let src = self.imgs[idx].clone();
+        // 分類済みの画像は書き出した説明文も書き直す
+        let entry = self
+            .session
+            .entries()
+            .iter()
+            .rfind(|entry| !entry.is_copy() && entry.src() == src)
+            .cloned();
+        if let Some(entry) = &entry {
+            self.remove_caption(entry)?;
+        }
+        let caption = caption.trim();
+        if caption.is_empty() {
+            self.captions.remove(&src);
+        } else {
+            self.captions.insert(src, caption.to_string());
+        }
+        match &entry {
+            Some(entry) => self.write_caption(entry),
+            None => Ok(()),
+        }
+    }
+
+    /// 説明文を分類後の画像の隣に書き出す
+    fn write_caption(&mut self, entry: &SessionEntry) -> Result<()> {
+        let (Some(caption), Some(path)) = (self.captions.get(entry.src()), caption_path(entry))
+        else {
+            return Ok(());
+        };
+        // 利用者が置いたファイルは上書きしない
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("cannot create caption file: {}", path.display()))?;
+        file.write_all(caption.as_bytes())
+            .with_context(|| format!("cannot write caption file: {}", path.display()))?;
+        self.caption_files.insert(path);
+        Ok(())
+    }
+
+    /// 書き出した説明文を削除する．このセッションで書き出していないファイルは消さない
+    fn remove_caption(&mut self, entry: &SessionEntry) -> Result<()> {
+        let Some(path) = caption_path(entry) else {
+            return Ok(());
+        };
+        if self.caption_files.remove(&path) {
+            fs::remove_file(&path)
+                .with_context(|| format!("cannot remove caption file: {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// 表示中の画像に後で見直すための印を付ける
+    pub fn flag(&mut self) -> Result<()> {
+        if self.shown.is_empty() || self.conflict.is_some() {
+            return Ok(());
+        }
+        let idx = self.idx();
+        if self.decisions[idx].is_some() {
+            self.revert(idx)?;
+            self.history.retain(|step| !step.decides(idx));
+        }
+        self.redo.clear();
+        self.decisions[idx] = Some(Decision::Flagged);
+        self.history.push(Step::Decide(idx));
+        self.push_img_log(
+            idx,
+            AppLog::Flag(
+                self.imgs[idx]
+                    .file_name()
+                    .context("Failed to get file name")?
+                    .into(),
+            ),
+        );
+        self.write_flagged()
+    }
+
+    /// 印を付けた画像
+    pub fn flagged(&self) -> Vec<PathBuf> {
+        self.shown
+            .iter()
+            .filter(|&&idx| matches!(self.decisions[idx], Some(Decision::Flagged)))
+            .map(|&idx| self.imgs[idx].clone())
+            .collect()
+    }
+
+    /// まだ決定していない画像．スキップした画像も元の場所に残っているので含める
+    pub fn remaining(&self) -> Vec<PathBuf> {
+        self.imgs
+            .iter()
+            .zip(&self.decisions)
+            .filter(|(_, decision)| {
+                matches!(
+                    decision,
+                    None | Some(Decision::Skip) | Some(Decision::Deferred)
+                )
+            })
+            .map(|(img, _)| img.clone())
+            .collect()
+    }
+
+    /// 残りの画像の一覧を`--files`で読める形で書き出し，書き出したファイルを返す
+    pub fn write_remaining(&self) -> Result<PathBuf> {
+        let path = self
+            .config
+            .remaining
+            .clone()
+            .unwrap_or_else(|| self.config.base_dir().join(REMAINING_FILE_NAME));
+        let mut remaining_str = String::new();
+        for src in self.remaining() {
+            remaining_str.push_str(&src.to_string_lossy());
+            remaining_str.push('\n');
+        }
+        fs::write(&path, remaining_str)
+            .with_context(|| format!("cannot write remaining file: {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// 設定されていれば終了時に残りの画像の一覧を書き出す
+    pub fn write_remaining_on_quit(&self) -> Result<()> {
+        if self.config.write_remaining {
+            self.write_remaining()?;
+        }
+        Ok(())
+    }
+
+    /// 印を付けた画像の一覧を書き出す
+    fn write_flagged(&self) -> Result<()> {
+        let Some(path) = &self.config.flagged else {
+            return Ok(());
+        };
+        let mut flagged_str = String::new();
+        for src in self.flagged() {
+            flagged_str.push_str(&src.to_string_lossy());
+            flagged_str.push('\n');
+        }
+        fs::write(path, flagged_str)
+            .with_context(|| format!("cannot write flagged file: {}", path.display()))
+    }
+
+    /// 複数の画像にまとめてキー入力に基づくアクションを実行する
+    pub fn on_key_many(&mut self, idxs: &[usize], key: KeyBind) -> Result<()> {
+        let Some(dest) = self.config.dests.get(&key).cloned() else {
+            return Ok(());
+        };
+        if let Some(labels) = &mut self.labels {
+            // まとめて付ける場合は付け外しせずに付けるだけにする
+            let label = dest.name();
+            for &idx in idxs {
+                labels.add(&self.imgs[idx], &label)?;
+            }
+            return Ok(());
+        }
+        self.act_many(idxs, &dest.path)
+    }
+
+    /// 表示中の画像とその後に続く画像の合わせてcount枚に，キー入力に基づくアクションを実行する
+    ///
+    /// 失敗した画像で止め，その画像を表示中の画像にする．止めるまでに済ませた枚数と結果を返す
+    pub fn on_key_repeat(&mut self, key: KeyBind, count: usize) -> (usize, Result<()>) {
+        if self.shown.is_empty() || self.conflict.is_some() {
+            return (0, Ok(()));
+        }
+        // 戻って表示した画像の先にある未決定の画像も順番に含める．分類済みの画像はやり直さない
+        let revisited = self.shown[self.pos + 1..]
+            .iter()
+            .copied()
+            .filter(|&idx| self.decisions[idx].is_none());
+        let idxs: Vec<usize> = iter::once(self.shown[self.pos])
+            .chain(revisited)
+            .chain(self.queue())
+            .take(count)
+            .collect();
+        for (done, &idx) in idxs.iter().enumerate() {
+            if let Err(e) = self.on_key_many(&[idx], key) {
+                if let Some(pos) = self.shown.iter().position(|&shown| shown == idx) {
+                    self.pos = pos;
+                }
+                let e = e.context(format!("sorted {} of {} images", done, idxs.len()));
+                return (done, Err(e));
+            }
+        }
+        (idxs.len(), Ok(()))
+    }
+
+    /// 分類先が割り当てられたキーか
+    pub fn has_dest(&self, key: KeyBind) -> bool {
+        self.config.dests.contains_key(&key)
+    }
+
+    /// 複数の画像をまとめて分類先に送る
+    fn act_many(&mut self, idxs: &[usize], dest: &Path) -> Result<()> {
+        let mut result = Ok(());
+        for &idx in idxs {
+            // まだ表示していない画像は表示したものとして扱う
+            if !self.shown_set.contains(&idx) {
+                self.push_shown(idx);
+                self.reorder_buf.remove(&idx);
+                // デコード中やこれからデコードする画像は途中でやめさせる
+                if let Ok(mut cancelled) = self.cancelled.lock() {
+                    cancelled.insert(idx);
+                }
+            }
+            let r = self.redecide(idx, Action::from_dest(dest), self.config.collision);
+            // まとめて操作するときは確認できないので衝突は失敗として扱う
+            let r = r.and_then(|_| match self.conflict.take() {
+                Some(_) => Err(anyhow!("move destination has same name file")),
+                None => Ok(()),
+            });
+            let r = self.count_failure(r);
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+
+    /// 決定済みの画像の場合は決定をやり直す
+    fn redecide(&mut self, idx: usize, action: Action, policy: CollisionPolicy) -> Result<()> {
+        if self.decisions[idx].is_some() {
+            self.revert(idx)?;
+            self.history.retain(|step| !step.decides(idx));
+        }
+        self.act(idx, action, policy)
+    }
+
+    /// 新しい分類先を空いているキーに割り当てる
+    pub fn add_dest(&mut self, dest: PathBuf) -> Result<KeyBind> {
+        if dest.as_os_str().is_empty() {
+            bail!("destination is empty");
+        }
+        if self.config.dests.values().any(|d| d.path == dest) {
+            bail!("destination is already bound: {}", dest.display());
+        }
+        let key = FREE_KEYS
+            .chars()
+            .map(|c| KeyBind::new(KeyCode::Char(c), KeyModifiers::NONE))
+            .find(|key| !self.config.dests.contains_key(key))
+            .context("no free key for new destination")?;
+
+        // テンプレートは移動するときに展開するので，ここではディレクトリを作らない
+        match Action::from_dest(&dest) {
+            Action::Move(dir) if !template::is_template(&dir) => {
+                fs::create_dir_all(&dir).with_context(|| {
+                    format!("Failed to create destination directory: {}", dir.display())
+                })?;
+            }
+            _ => {}
+        }
+        if self.config.save_dests {
+            config::save_dest(&self.config.path, key, &dest)?;
+        }
+        self.config.dests.insert(key, Dest::from(dest.clone()));
+        self.push_log(AppLog::NewDest(key, dest));
+        Ok(key)
+    }
+
+    /// 表示中の画像がまだ分類されていないか
+    pub fn is_pending(&self) -> bool {
+        !self.shown.is_empty() && self.decisions[self.idx()].is_none()
+    }
+
+    /// 移動先のファイルとの衝突を待っている場合はそのファイルと，中身が同じかどうか
+    pub fn conflict(&self) -> Option<(PathBuf, bool)> {
+        let conflict = self.conflict.as_ref()?;
+        Some((conflict.existing.clone(), conflict.duplicate))
+    }
+
+    /// 移動先のファイルとの衝突を指定した方法で解決する
+    pub fn resolve_conflict(&mut self, policy: CollisionPolicy) -> Result<()> {
+        let conflict = self.conflict.take().context("no conflict to resolve")?;
+        let idx = self.idx();
+        let result = self.act(idx, Action::Move(conflict.dest), policy);
+        self.count_failure(result)
+    }
+
+    /// 移動先に中身が同じファイルがあるので，移動せずにゴミ箱に送る
+    pub fn resolve_duplicate(&mut self) -> Result<()> {
+        self.conflict.take().context("no conflict to resolve")?;
+        let idx = self.idx();
+        let result = self.act(idx, Action::Trash, self.config.collision);
+        self.count_failure(result)
+    }
+
+    /// 失敗した操作を集計に含める
+    fn count_failure<T>(&mut self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.failure_num += 1;
+        }
+        result
+    }
+
+    /// 移動先の同名ファイルとの衝突を解決せずに移動をやめる
+    pub fn cancel_conflict(&mut self) {
+        self.conflict = None;
+    }
+
+    /// 画像に操作を行う．段階的に反映する設定では操作を保留するだけにする
+    fn act(&mut self, idx: usize, action: Action, policy: CollisionPolicy) -> Result<()> {
+        // 後回しはファイルを動かさないので保留しない
+        if action == Action::Defer {
+            return self.defer(idx);
+        }
+        if self.mark_gone(idx) {
+            self.redo.clear();
+            self.history.push(Step::Decide(idx));
+            return Ok(());
+        }
+        if self.config.staged {
+            let file_name = self.imgs[idx]
+                .file_name()
+                .context("Failed to get file name")?;
+            self.push_img_log(
+                idx,
+                AppLog::Staged(file_name.into(), action.dest().to_path_buf()),
+            );
+            self.redo.clear();
+            self.decisions[idx] = Some(Decision::Staged(action));
+            self.history.push(Step::Decide(idx));
+            return Ok(());
+        }
+
+        match self.perform(idx, &action, policy)? {
+            Some((decision, entry)) => self.decide(idx, decision, entry),
+            // 同名ファイルとの衝突の確認待ち
+            None => Ok(()),
+        }
+    }
+
+    /// 画像をこのセッションの最後に表示し直すよう後回しにする．記録には残さない
+    fn defer(&mut self, idx: usize) -> Result<()> {
+        let file_name = self.imgs[idx]
+            .file_name()
+            .context("Failed to get file name")?;
+        self.push_img_log(idx, AppLog::Defer(file_name.into()));
+        self.redo.clear();
+        self.decisions[idx] = Some(Decision::Deferred);
+        self.history.push(Step::Decide(idx));
+        self.later.push(idx);
+        Ok(())
+    }
+
+    /// ファイルに操作を行う．同名ファイルとの衝突の確認が必要な場合はNone
+    fn perform(
+        &mut self,
+        idx: usize,
+        action: &Action,
+        policy: CollisionPolicy,
+    ) -> Result<Option<(Decision, SessionEntry)>> {
+        let src = self.imgs[idx].clone();
+        let file_name: PathBuf = src.file_name().context("Failed to get file name")?.into();
+
+        match action {
+            Action::Skip => {
+                self.push_img_log(idx, AppLog::Skip(file_name));
+                Ok(Some((Decision::Skip, SessionEntry::Skip { src })))
+            }
+            Action::Trash => {
+                self.trash
+                    .delete(&src)
+                    .with_context(|| format!("Failed to move image to trash: {}", src.display()))?;
+                self.push_img_log(idx, AppLog::Trash(file_name));
+                Ok(Some((Decision::Trash, SessionEntry::Trash { src })))
+            }
+            Action::Defer => bail!("cannot defer {} here", src.display()),
+            Action::Move(dest) => {
+                let mut target = self.target_path(&src, dest)?;
+                // 移動先に中身が同じファイルがあれば名前に関わらず確認する
+                if policy == CollisionPolicy::Ask {
+                    if let Some(existing) = target
+                        .parent()
+                        .and_then(|dir| self.find_duplicate(idx, dir))
+                    {
+                        self.conflict = Some(Conflict {
+                            dest: dest.clone(),
+                            existing,
+                            duplicate: true,
+                        });
+                        return Ok(None);
+                    }
+                }
+                let mut replaced = false;
+                if target.exists() {
+                    match policy {
+                        CollisionPolicy::RenameWithSuffix => target = numbered_path(&target),
+                        CollisionPolicy::Overwrite => replaced = true,
+                        CollisionPolicy::Skip => return self.perform(idx, &Action::Skip, policy),
+                        CollisionPolicy::Ask => {
+                            self.conflict = Some(Conflict {
+                                dest: dest.clone(),
+                                existing: target,
+                                duplicate: false,
+                            });
+                            return Ok(None);
+                        }
+                    }
+                }
+
+                let mode = self.dest_mode(dest);
+                let rewrite = self.dest_rewrite(dest);
+                let rewritten = if replaced {
+                    replace_img(&src, &target, mode, rewrite, &self.trash)?
+                } else {
+                    place_img(&src, &target, mode, rewrite, &self.trash)?
+                };
+                self.index_placed(idx, &target, rewrite);
+                self.push_img_log(idx, AppLog::MoveSuccess(file_name, target.clone()));
+                Ok(Some((
+                    Decision::Move {
+                        dest: target.clone(),
+                        mode,
+                        rewrite,
+                        replaced,
+                        rewritten,
+                    },
+                    SessionEntry::Move {
+                        src,
+                        dest: target,
+                        mode,
+                        replaced,
+                        rewritten,
+                    },
+                )))
+            }
+        }
+    }
+
+    /// 分類先に設定された画像の置き方．規則の分類先などは移動する
+    fn dest_mode(&self, dest: &Path) -> DestMode {
+        self.config
+            .dests
+            .values()
+            .find(|d| d.path == dest)
+            .map_or(DestMode::Move, |d| d.mode)
+    }
+
+    /// 分類先に設定された画像の書き直し方
+    fn dest_rewrite(&self, dest: &Path) -> Rewrite {
+        self.config
+            .dests
+            .values()
+            .find(|d| d.path == dest)
+            .map_or(Rewrite::default(), |d| Rewrite {
+                strip_exif: d.strip_exif,
+                max_dimension: d.max_dimension,
+            })
+    }
+
+    /// 分類先から移動後のファイルのパスを決める
+    fn target_path(&self, src: &Path, dest: &Path) -> Result<PathBuf> {
+        let dir = template::expand(dest, src)?;
+        // 分類先に対応するキーにファイル名のテンプレートが設定されていれば名前を付け替える
+        let rename = self
+            .config
+            .dests
+            .iter()
+            .filter(|(_, d)| d.path == dest)
+            .find_map(|(key, _)| self.config.rename.get(key));
+        let file_name = match rename {
+            Some(rename) => template::rename(rename, src)?,
+            None => src.file_name().context("Failed to get file name")?.into(),
+        };
+        Ok(dir.join(file_name))
+    }
+
+    /// 決定を反映し，記録する
+    fn decide(&mut self, idx: usize, decision: Decision, entry: SessionEntry) -> Result<()> {
+        self.redo.clear();
+        self.decisions[idx] = Some(decision);
+        self.history.push(Step::Decide(idx));
+        self.record(entry)
+    }
+
+    /// 保留中の操作
+    pub fn staged(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.shown
+            .iter()
+            .filter_map(|&idx| match &self.decisions[idx] {
+                Some(Decision::Staged(action)) => {
+                    Some((self.imgs[idx].clone(), action.dest().to_path_buf()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 保留中の操作をまとめて反映する．途中で失敗した場合は反映済みの操作を元に戻す
+    pub fn commit_staged(&mut self) -> Result<()> {
+        let staged: Vec<(usize, Action)> = self
+            .shown
+            .iter()
+            .filter_map(|&idx| match &self.decisions[idx] {
+                Some(Decision::Staged(action)) => Some((idx, action.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let policy = self.unattended_policy();
+        let mut applied: Vec<(usize, Action, SessionEntry)> = Vec::new();
+        for (idx, action) in staged {
+            let result = self
+                .perform(idx, &action, policy)
+                .and_then(|r| r.context("move destination has same name file"));
+            self.conflict = None;
+            match result {
+                Ok((decision, entry)) => {
+                    self.decisions[idx] = Some(decision);
+                    applied.push((idx, action, entry));
+                }
+                Err(e) => {
+                    for (idx, action, entry) in applied.into_iter().rev() {
+                        if self.restore_file(idx).is_ok() {
+                            self.decisions[idx] = Some(Decision::Staged(action));
+                        } else {
+                            // 戻せなかったものは反映済みとして記録する
+                            self.record(entry)?;
+                        }
+                    }
+                    self.failure_num += 1;
+                    return Err(e);
+                }
+            }
+        }
+
+        let applied_num = applied.len();
+        for (_, _, entry) in applied {
+            self.record(entry)?;
+        }
+        self.push_log(AppLog::Commit(applied_num));
+        Ok(())
+    }
+
+    /// 直前の操作を取り消し，その画像を表示中にする
+    pub fn undo(&mut self) -> Result<()> {
+        self.conflict = None;
+        let idx = match self.history.last().context("nothing to undo")? {
+            Step::Decide(idx) => {
+                let idx = *idx;
+                if let Some(decision) = self.revert(idx)? {
+                    self.redo.push((idx, decision));
+                }
+                idx
+            }
+            Step::Rotate {
+                idx,
+                path,
+                clockwise,
+                rewritten,
+            } => {
+                unrotate_img(path, *clockwise, *rewritten, &self.trash)?;
+                *idx
+            }
+        };
+        self.history.pop();
+
+        if let Some(pos) = self.shown.iter().position(|&i| i == idx) {
+            self.pos = pos;
+        }
+        self.push_img_log(
+            idx,
+            AppLog::Undo(
+                self.imgs[idx]
+                    .file_name()
+                    .context("undo filename cannot get")?
+                    .into(),
+            ),
+        );
+        Ok(())
+    }
+
+    /// 履歴のpos番目の操作だけを取り消し，その画像を表示中にする
+    ///
+    /// 後の操作で決定が変わった画像や，分類先から動かされたファイルは取り消さない
+    pub fn undo_log(&mut self, pos: usize) -> Result<()> {
+        self.conflict = None;
+        let idx = self
+            .log_imgs
+            .get(pos)
+            .copied()
+            .flatten()
+            .context("this entry cannot be undone")?;
+        if self.log_imgs[pos + 1..].contains(&Some(idx)) || self.decisions[idx].is_none() {
+            bail!("this entry has been changed by a later action");
+        }
+        if let Some(Decision::Move { dest, .. }) = &self.decisions[idx] {
+            if !dest.exists() {
+                bail!("{} is no longer in its destination", dest.display());
+            }
+        }
+        if let Some(decision) = self.revert(idx)? {
+            self.redo.push((idx, decision));
+        }
+        self.history.retain(|step| !step.decides(idx));
+
+        if let Some(pos) = self.shown.iter().position(|&i| i == idx) {
+            self.pos = pos;
+        }
+        self.push_img_log(
+            idx,
+            AppLog::Undo(
+                self.imgs[idx]
+                    .file_name()
+                    .context("undo filename cannot get")?
+                    .into(),
+            ),
+        );
+        Ok(())
+    }
+
+    /// 取り消した操作を新しいものから順にやり直し，その画像を表示中にする
+    ///
+    /// 新しく決定するとやり直せる操作はなくなる
+    pub fn redo(&mut self) -> Result<()> {
+        self.conflict = None;
+        let (idx, decision) = self.redo.pop().context("nothing to redo")?;
+        if let Err(e) = self.reapply(idx, &decision) {
+            self.redo.push((idx, decision));
+            return Err(e);
+        }
+        let flagged = matches!(decision, Decision::Flagged);
+        self.decisions[idx] = Some(decision);
+        self.history.push(Step::Decide(idx));
+        if flagged {
+            self.write_flagged()?;
+        }
+
+        if let Some(pos) = self.shown.iter().position(|&i| i == idx) {
+            self.pos = pos;
+        }
+        self.push_img_log(
+            idx,
+            AppLog::Redo(
+                self.imgs[idx]
+                    .file_name()
+                    .context("redo filename cannot get")?
+                    .into(),
+            ),
+        );
+        Ok(())
+    }
+
+    /// 分類先のディレクトリの中で画像と中身が同じファイル
+    ///
+    /// ディレクトリの索引は初めて探すときに作り，ハッシュが一致したファイルだけ中身を比べる
+    fn find_duplicate(&mut self, idx: usize, dir: &Path) -> Option<PathBuf> {
+        let src = &self.imgs[idx];
+        let key = match self.content_hashes.get(&idx) {
+            Some(key) => *key,
+            None => content_hash(src)?,
+        };
+        let index = self
+            .dest_indexes
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| index_dir(dir));
+        let existing = index.get(&key)?;
+        if existing == src {
+            return None;
+        }
+        if same_content(src, existing) {
+            return Some(existing.clone());
+        }
+        // 取り消しなどで索引が古くなっていれば作り直す
+        *index = index_dir(dir);
+        index
+            .get(&key)
+            .filter(|existing| *existing != src && same_content(src, existing))
+            .cloned()
+    }
+
+    /// 分類先に置いたファイルを，そのディレクトリの索引に加える
+    fn index_placed(&mut self, idx: usize, dest: &Path, rewrite: Rewrite) {
+        let Some(index) = dest.parent().and_then(|dir| self.dest_indexes.get_mut(dir)) else {
+            return;
+        };
+        // 書き直したファイルは中身が変わるので読み直す
+        let key = match self.content_hashes.get(&idx) {
+            Some(key) if rewrite.is_none() => Some(*key),
+            _ => content_hash(dest),
+        };
+        if let Some(key) = key {
+            index.insert(key, dest.to_path_buf());
+        }
+    }
+
+    /// 取り消した決定のファイル操作をもう一度行い，セッションとジャーナルに記録する
+    fn reapply(&mut self, idx: usize, decision: &Decision) -> Result<()> {
+        let src = self.imgs[idx].clone();
+        let entry = match decision {
+            Decision::Move {
+                dest,
+                mode,
+                rewrite,
+                replaced,
+                ..
+            } => {
+                // 上書きしていたファイルは取り消しで戻したので，もう一度ゴミ箱に送る
+                let rewritten = if *replaced {
+                    replace_img(&src, dest, *mode, *rewrite, &self.trash)?
+                } else if dest.exists() {
+                    bail!("redo destination has same name file");
+                } else {
+                    place_img(&src, dest, *mode, *rewrite, &self.trash)?
+                };
+                self.index_placed(idx, dest, *rewrite);
+                SessionEntry::Move {
+                    src,
+                    dest: dest.clone(),
+                    mode: *mode,
+                    replaced: *replaced,
+                    rewritten,
+                }
+            }
+            Decision::Skip => SessionEntry::Skip { src },
+            Decision::Trash => {
+                self.trash
+                    .delete(&src)
+                    .with_context(|| format!("Failed to move image to trash: {}", src.display()))?;
+                SessionEntry::Trash { src }
+            }
+            // ファイルはそのままなので記録もしない
+            Decision::Deferred => {
+                self.later.push(idx);
+                return Ok(());
+            }
+            Decision::Staged(_) | Decision::Flagged | Decision::Gone => return Ok(()),
+        };
+        self.record(entry)
+    }
+
+    /// 画像に対する決定を取り消して元の場所に戻す．取り消した決定を返す
+    fn revert(&mut self, idx: usize) -> Result<Option<Decision>> {
+        self.restore_file(idx)?;
+        for copy in self.session.remove_copies(&self.imgs[idx])? {
+            if let SessionEntry::Copy { dest, .. } = &copy {
+                fs::remove_file(dest)
+                    .with_context(|| format!("Failed to remove {}", dest.display()))?;
+            }
+            if let Some(journal) = &mut self.journal {
+                journal.write_undo(&copy)?;
+            }
+        }
+        let decision = self.decisions[idx].take();
+        if matches!(decision, Some(Decision::Flagged)) {
+            self.write_flagged()?;
+        }
+
+        self.done.remove(&self.imgs[idx])?;
+        if let Some(entry) = self.session.remove(&self.imgs[idx])? {
+            self.remove_caption(&entry)?;
+            if let Some(journal) = &mut self.journal {
+                journal.write_undo(&entry)?;
+            }
+        }
+        Ok(decision)
+    }
+
+    /// 移動したりゴミ箱に送ったりした画像を元の場所に戻す
+    fn restore_file(&self, idx: usize) -> Result<()> {
+        let src = &self.imgs[idx];
+        match &self.decisions[idx] {
+            Some(Decision::Move {
+                dest,
+                mode,
+                replaced,
+                rewritten,
+                ..
+            }) => unplace_img(dest, src, *mode, *replaced, *rewritten, &self.trash),
+            Some(Decision::Trash) => self.trash.restore(src),
+            Some(Decision::Skip)
+            | Some(Decision::Staged(_))
+            | Some(Decision::Flagged)
+            | Some(Decision::Deferred)
+            | Some(Decision::Gone)
+            | None => Ok(()),
+        }
+    }
+
+    /// 保存されたセッションの決定を新しいものから順にnum個まで取り消す．取り消した数を返す
+    pub fn undo_session(&mut self, num: usize) -> Result<usize> {
+        let mut undone = 0;
+        while undone < num {
+            let Some(entry) = self.session.pop()? else {
+                break;
+            };
+            match &entry {
+                SessionEntry::Move {
+                    src,
+                    dest,
+                    mode,
+                    replaced,
+                    rewritten,
+                } => unplace_img(dest, src, *mode, *replaced, *rewritten, &self.trash)?,
+                SessionEntry::Trash { src } => self.trash.restore(src)?,
+                SessionEntry::Skip { .. } => {}
+                SessionEntry::Copy { dest, .. } => fs::remove_file(dest)
+                    .with_context(|| format!("Failed to remove {}", dest.display()))?,
+            }
+            if !entry.is_copy() {
+                self.done.remove(entry.src())?;
+            }
+            self.remove_caption(&entry)?;
+            if let Some(journal) = &mut self.journal {
+                journal.write_undo(&entry)?;
+            }
+            undone += 1;
+        }
+        Ok(undone)
+    }
+
+    /// 決定をセッションとジャーナルに記録する
+    fn record(&mut self, entry: SessionEntry) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.write(&entry)?;
+        }
+        // スキップやコピーをした画像は元の場所に残るので次回以降のために覚えておく
+        match &entry {
+            SessionEntry::Skip { src } => self.done.insert(src)?,
+            SessionEntry::Move { src, mode, .. } if !mode.is_move() => self.done.insert(src)?,
+            _ => {}
+        }
+        // 説明文を書き出せなくても移動は記録し，失敗として集計するだけにする
+        if self.write_caption(&entry).is_err() {
+            self.failure_num += 1;
+        }
+        // コマンドを実行できなくても移動は記録し，失敗として集計するだけにする
+        if let SessionEntry::Move { src, dest, .. } = &entry {
+            let hook = self.dest_of(src, dest).and_then(|d| d.hook.as_ref());
+            let commands = self.config.hook.iter().chain(hook);
+            let failed = commands
+                .filter(|command| hook::run(command, &[("src", src), ("dest", dest)]).is_err())
+                .count();
+            self.failure_num += failed;
+        }
+        self.session.push(entry)
+    }
+
+    /// 分類先に置かれたファイルか．テンプレートの分類先や実行中に追加した分類先も見る
+    fn is_in_dest(&self, path: &Path) -> bool {
+        let Ok(path) = path.canonicalize() else {
+            return false;
+        };
+        let is_same = |placed: &PathBuf| placed.canonicalize().is_ok_and(|placed| placed == path);
+        let placed = self.session.entries().iter().any(|entry| match entry {
+            SessionEntry::Move { dest, .. } | SessionEntry::Copy { dest, .. } => is_same(dest),
+            _ => false,
+        });
+        if placed {
+            return true;
+        }
+        self.config
+            .dests
+            .values()
+            .map(|dest| &dest.path)
+            .chain(self.config.rules.iter().map(|rule| &rule.dest))
+            .any(|dest| {
+                // プレースホルダより前の階層はあるはずなので，実際のパスにして比べる
+                let prefix: PathBuf = dest
+                    .components()
+                    .take_while(|c| !template::is_template(Path::new(c.as_os_str())))
+                    .collect();
+                let Ok(base) = prefix.canonicalize() else {
+                    return false;
+                };
+                let rest = dest.strip_prefix(&prefix).unwrap_or(Path::new(""));
+                if rest.as_os_str().is_empty() {
+                    return path.starts_with(&base);
+                }
+                let template = base.join(rest);
+                path.ancestors()
+                    .skip(1)
+                    .any(|dir| template::matches(&template, dir))
+            })
+    }
+
+    /// 移動先のファイルが入った分類先
+    fn dest_of(&self, src: &Path, target: &Path) -> Option<&Dest> {
+        let dir = target.parent()?;
+        self.config
+            .dests
+            .values()
+            .find(|dest| template::expand(&dest.path, src).is_ok_and(|expanded| expanded == dir))
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        // 送信待ちで止まっているワーカーを終了させるため先に受信側を閉じる
+        let (_, closed_rx) = sync_channel(0);
+        drop(std::mem::replace(&mut self.rx, closed_rx));
+        // 次に表示する画像を待っているワーカーも終了させる
+        if let Ok(mut ahead) = self.ahead.lock() {
+            ahead.clear();
+        }
+        while let Some(handle) = self.handles.pop() {
+            if let Err(e) = handle.join() {
+                eprintln!("error in thread {:?}", e);
+            }
+        }
+    }
+}
+
+/// 列から受け取った画像をデコードして送る．他のワーカーと列を分け合う
+fn decode_worker(
+    imgs: &[PathBuf],
+    queue: &DecodeQueue,
+    tx: &SyncSender<ProcessedImg>,
+    context: &DecodeContext,
+) {
+    let stats = &context.stats;
+    while let Some(idx) = queue.pop() {
+        let processed = process_img(&imgs[idx], idx, context);
+        stats.blocked.fetch_add(1, Ordering::Relaxed);
+        let result = tx.send(processed);
+        stats.blocked.fetch_sub(1, Ordering::Relaxed);
+        if result.is_err() {
+            break;
+        }
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 画像を読んでデコードし，OCRなどを行う
+///
+/// 失敗したり途中で取りやめたりしても順番待ちが止まらないよう，結果は必ず返す
+fn process_img(path: &Path, idx: usize, context: &DecodeContext) -> ProcessedImg {
+    let stats = &context.stats;
+    let mut processed = ProcessedImg {
+        frames: None,
+        thumb: None,
+        hash: None,
+        suggestions: Vec::new(),
+        text: None,
+        meta: None,
+        error: None,
+        render: None,
+        idx,
+    };
+    if context.is_cancelled(idx) {
+        return processed;
+    }
+
+    // 先にファイル全体を読むので，デコードはキャッシュから読むことになる
+    stats.reading.fetch_add(1, Ordering::Relaxed);
+    processed.hash = content_hash(path);
+    stats.reading.fetch_sub(1, Ordering::Relaxed);
+    if context.is_cancelled(idx) {
+        return processed;
+    }
+
+    // 画像処理
+    // デコーダがパニックしてもワーカーは止めず，失敗として扱う
+    stats.decoding.fetch_add(1, Ordering::Relaxed);
+    let decoded = panic::catch_unwind(AssertUnwindSafe(|| decode_img_with_thumb(path, context)))
+        .unwrap_or_else(|payload| {
+            Err(anyhow!(
+                "panicked while decoding: {}",
+                panic_message(&*payload)
+            ))
+        });
+    match decoded {
+        Ok(d) => {
+            processed.frames = Some(d.frames);
+            processed.render = Some(d.render);
+            processed.thumb = Some(d.thumb);
+            processed.suggestions = d.suggestions;
+            processed.meta = Some(d.meta);
+        }
+        Err(e) => processed.error = Some(format!("{:#}", e)),
+    }
+    // 規則の判定で読み取っていればOCRをやり直さない
+    // OCRは時間がかかるので，欄を隠しているかデコードの間に取りやめていれば行わない
+    processed.text = match context.rule_texts.get(path) {
+        Some(text) => Some(text.clone()),
+        None if context.show_text.load(Ordering::Relaxed) && !context.is_cancelled(idx) => context
+            .ocr
+            .as_deref()
+            .and_then(|ocr| hook::capture(ocr, path).ok()),
+        None => None,
+    }
+    .map(|text| text.trim().to_string())
+    .filter(|text| !text.is_empty());
+    stats.decoding.fetch_sub(1, Ordering::Relaxed);
+    processed
+}
+
+/// パニックの内容の文字列
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// マニフェストのラベルにする分類先の名前．設定したラベルがなければディレクトリ名にする
+///
+/// "sorted/{year}/{month}"のようなテンプレートでは，プレースホルダより前の階層の名前にする
+fn manifest_label(dest: &Dest) -> String {
+    if let Some(label) = &dest.label {
+        return label.clone();
+    }
+    dest.path
+        .components()
+        .take_while(|c| !template::is_template(Path::new(c.as_os_str())))
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .last()
+        .map_or_else(
+            || dest.path.to_string_lossy().into_owned(),
+            |c| c.as_os_str().to_string_lossy().into_owned(),
+        )
+}
+
+/// 説明文を書き出すファイルのパス．ゴミ箱に送った画像には書き出さない
+///
+/// 拡張子だけが違う画像や元からある同じ名前の`.txt`と被らないよう，ファイル名の全体に`.txt`を付ける
+fn caption_path(entry: &SessionEntry) -> Option<PathBuf> {
+    let img = match entry {
+        SessionEntry::Move { dest, .. } => dest,
+        SessionEntry::Skip { src } => src,
+        SessionEntry::Trash { .. } | SessionEntry::Copy { .. } => return None,
+    };
+    let mut file_name = img.file_name()?.to_os_string();
+    file_name.push(".txt");
+    Some(img.with_file_name(file_name))
+}
+
+/// 画像の一覧を指定された基準で並べ替える
+fn sort_images(imgs: &mut [PathBuf], key: SortKey, descending: bool, seed: u64) {
+    match key {
+        SortKey::Name => imgs.sort(),
+        SortKey::Mtime => {
+            imgs.sort_by_cached_key(|img| fs::metadata(img).and_then(|m| m.modified()).ok())
+        }
+        SortKey::Size => imgs.sort_by_cached_key(|img| fs::metadata(img).map(|m| m.len()).ok()),
+        SortKey::Random => imgs.shuffle(&mut StdRng::seed_from_u64(seed)),
+        // 似ている順の並べ替えは画像を読むので，規則で分類した後に行う
+        SortKey::Similarity => imgs.sort(),
+    }
+    if descending && !matches!(key, SortKey::Random | SortKey::Similarity) {
+        imgs.reverse();
+    }
+}
+
+/// 指定されたディレクトリから画像ファイルの一覧を取得する
+/// 設定された分類元のディレクトリやglobのパターンから画像を探す．重複は除く
+fn find_images(config: &Config) -> Result<Vec<PathBuf>> {
+    let excludes = excluded_dirs(config);
+    let mut seen = HashSet::new();
+    let mut images = Vec::new();
+    if let Some(list) = &config.files {
+        push_unique(&mut images, &mut seen, read_file_list(list, config)?);
+        return Ok(images);
+    }
+    for source in &config.dir {
+        let found = if config::is_glob(source) {
+            let pattern = source.to_string_lossy();
+            glob::glob(&pattern)
+                .with_context(|| format!("invalid glob pattern: {}", pattern))?
+                .filter_map(Result::ok)
+                .filter(|path| is_target(path, config))
+                .collect()
+        } else if !source.is_dir() {
+            return Err(anyhow!("dir is not valid: {}", source.display()));
+        } else if config.recursive {
+            find_images_in_dir_recursive(source, &excludes, config)?
+        } else {
+            find_images_in_dir(source, config)?
+        };
+        push_unique(&mut images, &mut seen, found);
+    }
+    Ok(images)
+}
+
+/// 探索しない分類先のディレクトリ
+fn excluded_dirs(config: &Config) -> Vec<PathBuf> {
+    config
+        .dests
+        .values()
+        .map(|dest| &dest.path)
+        .chain(config.rules.iter().map(|rule| &rule.dest))
+        .filter_map(|dest| dest.canonicalize().ok())
+        .collect()
+}
+
+/// 見つけた画像を加える．重なった分類元で同じ画像が見つかっても1回だけにする
+fn push_unique(images: &mut Vec<PathBuf>, seen: &mut HashSet<PathBuf>, found: Vec<PathBuf>) {
+    for img in found {
+        let canonical = img.canonicalize().unwrap_or_else(|_| img.clone());
+        if seen.insert(canonical) {
+            images.push(img);
+        }
+    }
+}
+
+/// 画像のパスを1行ずつ書いたファイルを読む．"-"の場合は標準入力から読む
+fn read_file_list(list: &Path, config: &Config) -> Result<Vec<PathBuf>> {
+    let list_str = if list == Path::new("-") {
+        let mut list_str = String::new();
+        io::stdin()
+            .read_to_string(&mut list_str)
+            .context("cannot read file list from stdin")?;
+        list_str
+    } else {
+        fs::read_to_string(list)
+            .with_context(|| format!("cannot read file list: {}", list.display()))?
+    };
+    Ok(list_str
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .filter(|path| is_target(path, config))
+        .collect())
+}
+
+fn find_images_in_dir(dir: &Path, config: &Config) -> Result<Vec<PathBuf>> {
+    let images = fs::read_dir(dir)
+        .with_context(|| format!("cannot read dir: {}", dir.display()))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| is_target(path, config))
+        .collect();
+    Ok(images)
+}
+
+/// 分類の対象にするファイルか
+fn is_target(path: &Path, config: &Config) -> bool {
+    path.is_file() && is_image(path, config) && within_limits(path, config)
+}
+
+/// 分類の対象にする画像か
+fn is_image(path: &Path, config: &Config) -> bool {
+    let allowed = |ext: &str| match &config.extensions {
+        Some(extensions) => extensions
+            .iter()
+            .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext)),
+        None => {
+            let ext = ext.to_lowercase();
+            IMG_EXTENSIONS.contains(&ext.as_str())
+                || raw::EXTENSIONS.contains(&ext.as_str())
+                || svg::EXTENSIONS.contains(&ext.as_str())
+                || (heif::is_available() && heif::EXTENSIONS.contains(&ext.as_str()))
+                || (pdf::is_available() && pdf::EXTENSIONS.contains(&ext.as_str()))
+                || (video::is_available() && video::EXTENSIONS.contains(&ext.as_str()))
+        }
+    };
+    if config.sniff {
+        // 拡張子によらず，ファイルの先頭のバイト列から判定した形式で選ぶ
+        return sniff_format(path)
+            .is_some_and(|format| format.extensions_str().iter().any(|ext| allowed(ext)))
+            || (heif::sniff(path) && heif::EXTENSIONS.iter().any(|ext| allowed(ext)))
+            || (pdf::sniff(path) && pdf::EXTENSIONS.iter().any(|ext| allowed(ext)))
+            // RAWは中身ではTIFFと見分けられないので拡張子も見る
+            || (raw::is_raw(path)
+                && sniff_format(path) == Some(ImageFormat::Tiff)
+                && path.extension().and_then(|s| s.to_str()).is_some_and(allowed))
+            // SVGはテキストなので，動画は形式が多いので拡張子で見分ける
+            || ((svg::is_svg(path) || video::is_video(path))
+                && path.extension().and_then(|s| s.to_str()).is_some_and(allowed));
+    }
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(allowed)
+}
+
+/// ファイルサイズと画像の幅・高さが設定された範囲に収まるか
+fn within_limits(path: &Path, config: &Config) -> bool {
+    if config.min_bytes.is_some() || config.max_bytes.is_some() {
+        let Ok(bytes) = fs::metadata(path).map(|metadata| metadata.len()) else {
+            return false;
+        };
+        if !within(bytes, config.min_bytes, config.max_bytes) {
+            return false;
+        }
+    }
+    let sizes = [
+        config.min_width,
+        config.max_width,
+        config.min_height,
+        config.max_height,
+    ];
+    if sizes.iter().any(Option::is_some) {
+        // ヘッダだけを読むのでデコードはしない
+        let dimensions = ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        let Some((width, height)) = dimensions else {
+            return false;
+        };
+        if !within(width, config.min_width, config.max_width)
+            || !within(height, config.min_height, config.max_height)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+fn within<T: PartialOrd>(value: T, min: Option<T>, max: Option<T>) -> bool {
+    min.is_none_or(|min| min <= value) && max.is_none_or(|max| value <= max)
+}
+
+/// ファイルの先頭のバイト列から画像の形式を判定する
+fn sniff_format(path: &Path) -> Option<ImageFormat> {
+    let mut head = [0; SNIFF_LEN];
+    let len = File::open(path).ok()?.read(&mut head).ok()?;
+    image::guess_format(&head[..len]).ok()
+}
+
+/// 指定されたディレクトリ以下から再帰的に画像ファイルの一覧を取得する
+fn find_images_in_dir_recursive(
+    dir: &Path,
+    excludes: &[PathBuf],
+    config: &Config,
+) -> Result<Vec<PathBuf>> {
+    let mut images = find_images_in_dir(dir, config)?;
+    let sub_dirs = fs::read_dir(dir)
+        .with_context(|| format!("cannot read dir: {}", dir.display()))?
+        .filter_map(Result::ok)
+        // シンボリックリンクは循環しうるので辿らない
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.canonicalize()
+                .map_or(true, |path| !excludes.contains(&path))
+        });
+    for sub_dir in sub_dirs {
+        images.extend(find_images_in_dir_recursive(&sub_dir, excludes, config)?);
+    }
+    Ok(images)
+}
+
+/// ワーカーと共有している表示用の設定
+fn read_picker(picker: &RwLock<Picker>) -> Picker {
+    match picker.read() {
+        Ok(picker) => picker.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+/// 画像を読み込み，表示用にデコードする
+fn decode_img(
+    path: &Path,
+    picker: &Picker,
+    cache: Option<&ImgCache>,
+    low_bandwidth: bool,
+    background: AlphaBackground,
+) -> Result<Vec<Frame>> {
+    let (raw, _) = decode_fitted(path, picker, cache)?;
+    Ok(to_frames(raw, picker, low_bandwidth, background))
+}
+
+/// 画像を読み込み，表示用のフレームとサムネイルを作る
+fn decode_img_with_thumb(path: &Path, context: &DecodeContext) -> Result<Decoded> {
+    let picker = &read_picker(&context.picker);
+    let low_bandwidth = context.low_bandwidth.load(Ordering::Relaxed);
+    let (raw, source) = decode_fitted(path, picker, context.cache.as_deref())?;
+    let (first, _) = raw
+        .first()
+        .with_context(|| format!("no frames in image {}", path.display()))?;
+    // 色の分布はサムネイルから求める
+    let thumb_img = first.thumbnail(THUMB_SIZE, THUMB_SIZE);
+    let colors = colors::analyze(&thumb_img);
+    let thumb = picker.new_resize_protocol(flatten_alpha(thumb_img, context.alpha_background));
+    // 候補を出せなくても画像は表示する
+    let suggestions = context
+        .suggester
+        .as_ref()
+        .and_then(|suggester| suggester.suggest(first).ok())
+        .unwrap_or_default();
+    let format = sniff_format(path).or_else(|| ImageFormat::from_path(path).ok());
+    // 複数ページのTIFFは最初のページだけをデコードし，他のページは表示するときに読む
+    let pages = if format == Some(ImageFormat::Tiff) && !raw::is_raw(path) {
+        tiff_page_count(path)
+    } else {
+        1
+    };
+    let meta = ImgMeta {
+        width: source.width,
+        height: source.height,
+        bytes: fs::metadata(path).map_or(0, |m| m.len()),
+        format,
+        bit_depth: source.bit_depth,
+        alpha: source.alpha,
+        colors,
+        pages,
+    };
+    Ok(Decoded {
+        frames: to_frames(raw, picker, low_bandwidth, context.alpha_background),
+        render: RenderParams {
+            font_size: picker.font_size(),
+            low_bandwidth,
+        },
+        thumb,
+        suggestions,
+        meta,
+    })
+}
+
+/// 画像を読み込み，端末に表示できる大きさまで縮小した各フレームと元の画像の情報を返す
+///
+/// キャッシュに縮小済みの画像があれば元の画像はデコードしない
+fn decode_fitted(
+    path: &Path,
+    picker: &Picker,
+    cache: Option<&ImgCache>,
+) -> Result<(Vec<(DynamicImage, Duration)>, SourceInfo)> {
+    let max_size = terminal_pixels(picker);
+    if let Some((img, source)) = cache
+        .zip(max_size)
+        .and_then(|(cache, max_size)| cache.load(path, max_size))
+    {
+        return Ok((vec![(img, Duration::ZERO)], source));
+    }
+
+    let raw = decode_raw(path)?;
+    let (first, _) = raw
+        .first()
+        .with_context(|| format!("no frames in image {}", path.display()))?;
+    let color = first.color();
+    let source = SourceInfo {
+        width: first.width(),
+        height: first.height(),
+        bit_depth: color.bits_per_pixel() / u16::from(color.channel_count()),
+        alpha: color.has_alpha(),
+    };
+    let fitted: Vec<_> = raw
+        .into_iter()
+        .map(|(img, delay)| (fit_within(img, max_size), delay))
+        .collect();
+    // 縮小した静止画だけを保存する．小さな画像はデコードし直すほうが速い
+    if let (Some(cache), Some(max_size), [(img, _)]) = (cache, max_size, fitted.as_slice()) {
+        if img.width() < source.width || img.height() < source.height {
+            // 保存できなくても表示はする
+            let _ = cache.store(path, max_size, img, &source);
+        }
+    }
+    Ok((fitted, source))
+}
+
+fn to_frames(
+    raw: Vec<(DynamicImage, Duration)>,
+    picker: &Picker,
+    low_bandwidth: bool,
+    background: AlphaBackground,
+) -> Vec<Frame> {
+    raw.into_iter()
+        .map(|(img, delay)| {
+            let img = flatten_alpha(img, background);
+            Frame {
+                state: picker.new_resize_protocol(if low_bandwidth {
+                    reduce_for_bandwidth(img)
+                } else {
+                    img
+                }),
+                delay,
+            }
+        })
+        .collect()
+}
+
+/// SSH越しなどで端末に送る量を減らすため，解像度と色数を落とした画像
+///
+/// 大きさは変えずに画素をまとめるので，sixelなどでは同じ色が続いて短く送れる
+fn reduce_for_bandwidth(img: DynamicImage) -> DynamicImage {
+    let (width, height) = (img.width(), img.height());
+    let small = img.resize_exact(
+        (width / LOW_BANDWIDTH_SCALE).max(1),
+        (height / LOW_BANDWIDTH_SCALE).max(1),
+        FilterType::Triangle,
+    );
+    let mut rgb = small.to_rgb8();
+    let step = 255 / (LOW_BANDWIDTH_LEVELS - 1);
+    for pixel in rgb.pixels_mut() {
+        for value in pixel.0.iter_mut() {
+            *value = ((u32::from(*value) + step / 2) / step * step) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(rgb).resize_exact(width, height, FilterType::Nearest)
+}
+
+/// 端末全体のピクセル数．画像はこれより大きく表示されることはない
+fn terminal_pixels(picker: &Picker) -> Option<(u32, u32)> {
+    let (columns, rows) = crossterm::terminal::size().ok()?;
+    let (font_width, font_height) = picker.font_size();
+    let size = (
+        u32::from(columns) * u32::from(font_width),
+        u32::from(rows) * u32::from(font_height),
+    );
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+/// 表示できる大きさを超える画像を縦横比を保って縮小する
+///
+/// 大きな写真をそのまま表示用に変換すると時間もメモリもかかるので，デコードした直後に小さくしておく
+fn fit_within(img: DynamicImage, max_size: Option<(u32, u32)>) -> DynamicImage {
+    match max_size {
+        Some((width, height)) if img.width() > width || img.height() > height => {
+            img.resize(width, height, FilterType::Triangle)
+        }
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_label_uses_dir_before_placeholders() {
+        let dest = |path: &str| Dest::from(PathBuf::from(path));
+        assert_eq!(manifest_label(&dest("pics/cats")), "cats");
+        assert_eq!(manifest_label(&dest("sorted/{year}/{month}")), "sorted");
+        assert_eq!(manifest_label(&dest("{year}")), "{year}");
+        let labeled = Dest {
+            label: Some("ねこ".into()),
+            ..dest("sorted/{year}")
+        };
+        assert_eq!(manifest_label(&labeled), "ねこ");
+    }
 }
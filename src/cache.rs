@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// 縮小する前の画像の情報
+#[derive(Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub width: u32,
+    pub height: u32,
+    // 1チャンネルあたりのビット数
+    pub bit_depth: u16,
+    pub alpha: bool,
+}
+
+/// 表示用に縮小した画像をキャッシュディレクトリに保存しておく
+///
+/// 画像のパスと更新日時，表示する大きさで見分けるので，画像を書き換えると使われなくなる
+pub struct ImgCache {
+    dir: PathBuf,
+}
+
+impl ImgCache {
+    /// OSのキャッシュディレクトリの下に作る
+    pub fn open() -> Result<Self> {
+        let dir = dirs::cache_dir()
+            .context("cannot find cache dir")?
+            .join("shotclassif");
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create cache dir {}", dir.display()))?;
+        Ok(ImgCache { dir })
+    }
+
+    /// 保存してある縮小した画像と元の画像の情報
+    pub fn load(&self, path: &Path, max_size: (u32, u32)) -> Option<(DynamicImage, SourceInfo)> {
+        let entry = self.entry(path, max_size)?;
+        // 情報は画像の後に書くので，情報があれば画像は書き終わっている
+        let source = serde_json::from_slice(&fs::read(entry.with_extension("json")).ok()?).ok()?;
+        let img = image::open(entry.with_extension("png")).ok()?;
+        Some((img, source))
+    }
+
+    /// 縮小した画像と元の画像の情報を保存する
+    pub fn store(
+        &self,
+        path: &Path,
+        max_size: (u32, u32),
+        img: &DynamicImage,
+        source: &SourceInfo,
+    ) -> Result<()> {
+        let entry = self
+            .entry(path, max_size)
+            .with_context(|| format!("cannot read modified time of {}", path.display()))?;
+        img.save_with_format(entry.with_extension("png"), ImageFormat::Png)
+            .with_context(|| format!("cannot write cache of {}", path.display()))?;
+        fs::write(entry.with_extension("json"), serde_json::to_vec(source)?)
+            .with_context(|| format!("cannot write cache of {}", path.display()))
+    }
+
+    /// 拡張子を除いたキャッシュのファイルのパス
+    fn entry(&self, path: &Path, max_size: (u32, u32)) -> Option<PathBuf> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        // ハッシュの値はRustのバージョンで変わりうるが，その場合は作り直すだけ
+        let mut hasher = DefaultHasher::new();
+        path.canonicalize().ok()?.hash(&mut hasher);
+        modified.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        max_size.hash(&mut hasher);
+        Some(self.dir.join(format!("{:016x}", hasher.finish())))
+    }
+}
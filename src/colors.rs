@@ -0,0 +1,58 @@
+use image::DynamicImage;
+
+use std::cmp::Reverse;
+
+// 明るさのヒストグラムの区間の数
+const HISTOGRAM_BINS: usize = 64;
+// 代表色として返す色の数
+const DOMINANT_NUM: usize = 5;
+// 代表色を数えるときに残すチャンネルごとの上位ビット数
+const QUANTIZE_BITS: u8 = 3;
+
+/// 画像の明るさの分布と代表色
+#[derive(Clone)]
+pub struct ColorStats {
+    // 暗いほうから順に並べた明るさごとの画素数
+    pub histogram: Vec<u64>,
+    // 多い順の代表色
+    pub dominant: Vec<[u8; 3]>,
+}
+
+/// 明るさの分布と代表色を求める．縮小した画像を渡す
+pub fn analyze(img: &DynamicImage) -> ColorStats {
+    let rgba = img.to_rgba8();
+    let mut histogram = vec![0; HISTOGRAM_BINS];
+    // 色を粗く分けた区間ごとの画素数と色の合計
+    let mut buckets: Vec<(u64, [u64; 3])> = vec![(0, [0; 3]); 1 << (QUANTIZE_BITS * 3)];
+    for pixel in rgba.pixels() {
+        let [r, g, b, a] = pixel.0;
+        // 透明な部分は数えない
+        if a < 128 {
+            continue;
+        }
+        let luma = (299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b)) / 1000;
+        histogram[luma as usize * HISTOGRAM_BINS / 256] += 1;
+
+        let shift = 8 - QUANTIZE_BITS;
+        let bucket = (usize::from(r >> shift) << (QUANTIZE_BITS * 2))
+            | (usize::from(g >> shift) << QUANTIZE_BITS)
+            | usize::from(b >> shift);
+        let (count, sum) = &mut buckets[bucket];
+        *count += 1;
+        for (sum, value) in sum.iter_mut().zip([r, g, b]) {
+            *sum += u64::from(value);
+        }
+    }
+
+    buckets.sort_by_key(|(count, _)| Reverse(*count));
+    let dominant = buckets
+        .iter()
+        .take_while(|(count, _)| *count > 0)
+        .take(DOMINANT_NUM)
+        .map(|(count, sum)| sum.map(|sum| (sum / count) as u8))
+        .collect();
+    ColorStats {
+        histogram,
+        dominant,
+    }
+}
@@ -0,0 +1,804 @@
+use anyhow::{anyhow, Context, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::style::Color;
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    app::{Action, AlphaBackground, CollisionPolicy, DestMode, Mode, Protocol, SortKey},
+    heif, model, pdf,
+    rules::Rule,
+    template,
+    theme::Theme,
+    ui::PanelLayout,
+    video,
+};
+
+// 自動で分類先に割り当てるキーの候補 (操作キーと，枚数の入力に使う数字は除く)
+pub const FREE_KEYS: &str = "abdehijlmprstvwxyz";
+// 操作に使うので分類先に割り当てられないキー
+const RESERVED_KEYS: &[&str] = &[
+    "q", "u", "f", "c", "o", "n", "g", "?", "+", "=", "-", "0", "[", "]", "Space", "Left", "Right",
+    "Tab", "Esc", "Z", "B", "R", "Enter", ":", "/", "F", "S", "PageUp", "PageDown", "U", "Ctrl+r",
+    "P", "{", "}", ",", ".",
+];
+
+/// TOML file structure
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    // 読み込んだ設定ファイルのパス
+    #[serde(skip)]
+    pub path: PathBuf,
+    // 書き方が違うだけで同じキーになる分類先のキーの組 (どちらか一方だけが使われる)
+    #[serde(skip)]
+    duplicate_keys: Vec<(String, String)>,
+    // 分類元のディレクトリやglobのパターン (1つだけなら文字列でもよい)
+    #[serde(default, deserialize_with = "deserialize_dirs")]
+    pub dir: Vec<PathBuf>,
+    #[serde(deserialize_with = "deserialize_dests")]
+    pub dests: HashMap<KeyBind, Dest>,
+    // 分類先ごとの移動後のファイル名のテンプレート
+    #[serde(default)]
+    pub rename: HashMap<KeyBind, String>,
+    // 分類元の代わりに画像のパスを1行ずつ書いたファイル ("-"なら標準入力)
+    pub files: Option<PathBuf>,
+    // 分類元のディレクトリに新しく作られた画像を続けて分類するか
+    #[serde(default)]
+    pub watch: bool,
+    // サブディレクトリも探索するか
+    #[serde(default)]
+    pub recursive: bool,
+    // 対象にする画像の拡張子 (省略時はjpg，jpeg，png，gif，webp，bmp)
+    pub extensions: Option<Vec<String>>,
+    // 拡張子ではなくファイルの中身から画像かを判定するか
+    #[serde(default)]
+    pub sniff: bool,
+    // 対象にする画像の幅と高さの範囲 (ピクセル)
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    // 対象にするファイルサイズの範囲 (バイト)
+    pub min_bytes: Option<u64>,
+    pub max_bytes: Option<u64>,
+    // 操作を記録するジャーナルファイル
+    pub journal: Option<PathBuf>,
+    // 移動先に同名のファイルがある場合の扱い
+    #[serde(default)]
+    pub collision: CollisionPolicy,
+    // OSのゴミ箱の代わりに画像を送るディレクトリ
+    pub trash_dir: Option<PathBuf>,
+    // 画像を表示する順番
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub descending: bool,
+    // sort = "random"や実行中のシャッフルで使う乱数の種 (省略時は毎回変わる)
+    pub seed: Option<u64>,
+    // 終了時に集計を書き出すファイル
+    pub summary: Option<PathBuf>,
+    // 画像を移動するか，ラベルを付けるだけにするか
+    #[serde(default)]
+    pub mode: Mode,
+    // タグ付けモードでラベルを書き出すファイル (省略時はdir内のlabels.json)
+    pub labels: Option<PathBuf>,
+    // 画像のパスとラベルをCSVで書き出すファイル
+    pub manifest: Option<PathBuf>,
+    // マニフェストにマニフェストからの相対パスで書くか
+    #[serde(default)]
+    pub manifest_relative: bool,
+    // マニフェストでvalに割り当てる画像の割合 (0ならsplitの列を出さない)
+    #[serde(default)]
+    pub val_ratio: f64,
+    // 画像を開く外部のビューアのコマンド (省略時はOS既定のアプリ)
+    pub viewer: Option<String>,
+    // 移動するたびに実行するコマンド
+    pub hook: Option<String>,
+    // 終了時に実行するコマンド
+    pub end_hook: Option<String>,
+    // 後で見直すために印を付けた画像の一覧を書き出すファイル
+    pub flagged: Option<PathBuf>,
+    // 残りの画像の一覧を書き出すファイル (省略時はdir内のremaining.txt)
+    pub remaining: Option<PathBuf>,
+    // 終了するたびに残りの画像の一覧を書き出すか
+    #[serde(default)]
+    pub write_remaining: bool,
+    // 表示する前に自動で分類する規則 (上から順に判定する)
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    // 見た目が似ている画像をまとめて続けて表示するか
+    #[serde(default)]
+    pub group_similar: bool,
+    // 似ているとみなすハッシュの距離 (0〜64，省略時は8)
+    pub similar_threshold: Option<u32>,
+    // kキーや比較表示で残さなかった画像の分類先 (省略時はtrash)
+    pub similar_dest: Option<PathBuf>,
+    // 分類の候補を出す画像分類のONNXモデル (onnx機能を有効にしてビルドした場合のみ)
+    pub model: Option<PathBuf>,
+    // モデルの出力の順にラベルを1行ずつ書いたファイル
+    pub model_labels: Option<PathBuf>,
+    // 表示する候補の数 (省略時は3)
+    pub model_top_k: Option<usize>,
+    // sort = "similarity"で使う，CLIPなどの画像エンコーダのONNXモデル (省略時は差分ハッシュ)
+    pub embedding_model: Option<PathBuf>,
+    // 画像から文字列を読み取るOCRのコマンド ("{path}"は画像のパス，標準出力を読み取った文字列とする)
+    pub ocr: Option<String>,
+    // 表示用に縮小した画像をキャッシュディレクトリに保存して次回から使うか
+    #[serde(default)]
+    pub cache: bool,
+    // デコード済みで表示を待てる画像の数 (省略時は7)
+    pub prefetch: Option<usize>,
+    // 画像をデコードするワーカーのスレッド数 (省略時はCPUの数-1)
+    pub workers: Option<usize>,
+    // 画像を表示する方式 (省略時は端末に問い合わせる)
+    pub protocol: Option<Protocol>,
+    // 解像度と色数を落として端末に送る量を減らした状態で始めるか
+    #[serde(default)]
+    pub low_bandwidth: bool,
+    // 透明な部分の下に敷く背景 ("checkerboard"，"none"，"#rrggbb"，省略時は"checkerboard")
+    #[serde(default)]
+    pub alpha_background: AlphaBackground,
+    // 画像と情報欄の並べ方
+    #[serde(default)]
+    pub layout: PanelLayout,
+    // 画面のうち画像を表示する領域の割合 (%，省略時は70)
+    pub image_percent: Option<u16>,
+    // UIの色 (プリセットと個別の色)
+    #[serde(default)]
+    pub theme: Theme,
+    // 移動を保留しておき，最後にまとめて反映するか
+    #[serde(default)]
+    pub staged: bool,
+    // 実行中に追加した分類先を設定ファイルに書き戻すか
+    #[serde(default)]
+    pub save_dests: bool,
+    // 分類先のキーと一緒に押すと，移動せずにコピーだけする修飾キー
+    #[serde(default)]
+    pub copy_modifier: Modifier,
+    // 同じキーを続けて押すと確認なしで同じ分類先に送る連続入力モードで始めるか
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+impl Config {
+    /// 設定ファイルを読み込む
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let config_str =
+            fs::read_to_string(&path).context("config.toml not found or unreadable")?;
+        let mut config: Config =
+            toml::from_str(&config_str).context("config.toml is not valid toml")?;
+        config.path = path;
+        config.duplicate_keys = duplicate_keys(&config_str);
+        Ok(config)
+    }
+
+    /// セッションなどの状態を置く分類元のディレクトリ．最初に指定したものを使う
+    pub fn base_dir(&self) -> PathBuf {
+        let Some(first) = self.dir.first() else {
+            // ファイルの一覧だけを指定した場合はカレントディレクトリに置く
+            return PathBuf::from(".");
+        };
+        // globのパターンはワイルドカードを含まない部分までにする
+        first
+            .components()
+            .take_while(|c| !is_glob(Path::new(c.as_os_str())))
+            .collect()
+    }
+
+    /// 操作キーと被ったり，同じキーに重ねて割り当てたりした分類先
+    pub fn key_conflicts(&self) -> Vec<String> {
+        let mut conflicts: Vec<String> = RESERVED_KEYS
+            .iter()
+            .filter_map(|name| {
+                let key: KeyBind = name.parse().ok()?;
+                let dest = self.dests.get(&key)?;
+                Some(format!(
+                    "key [{}] is reserved and cannot be used for {}",
+                    key,
+                    dest.path.display()
+                ))
+            })
+            .collect();
+        // 機能を有効にしたときだけ操作に使うキー
+        let optional_keys = [
+            ('k', "group_similar", self.group_similar),
+            ('T', "ocr", self.ocr.is_some()),
+        ];
+        for (c, option, enabled) in optional_keys {
+            let key = KeyBind::new(KeyCode::Char(c), KeyModifiers::NONE);
+            if let Some(dest) = self.dests.get(&key).filter(|_| enabled) {
+                conflicts.push(format!(
+                    "key [{}] is reserved by {} and cannot be used for {}",
+                    key,
+                    option,
+                    dest.path.display()
+                ));
+            }
+        }
+        // コピーの修飾キーを付けると操作キーになる分類先には，修飾キーでコピーできない
+        let mut control_keys: Vec<KeyBind> = RESERVED_KEYS
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .chain(
+                optional_keys
+                    .iter()
+                    .filter(|(_, _, enabled)| *enabled)
+                    .map(|&(c, _, _)| KeyBind::new(KeyCode::Char(c), KeyModifiers::NONE)),
+            )
+            .collect();
+        // ジョブ制御のあるOSではCtrl+Zで中断する
+        if cfg!(unix) {
+            control_keys.push(KeyBind::new(KeyCode::Char('z'), KeyModifiers::CONTROL));
+        }
+        let mut copy_conflicts: Vec<String> = self
+            .dests
+            .iter()
+            .filter(|(_, dest)| matches!(Action::from_dest(&dest.path), Action::Move(_)))
+            .filter_map(|(key, dest)| {
+                let copy = key.with(self.copy_modifier);
+                if self.dests.contains_key(&copy) {
+                    return None;
+                }
+                // 操作キーは修飾キーを見ずに照らし合わせるので，同じ文字なら被る
+                control_keys
+                    .iter()
+                    .any(|control| {
+                        control.code == copy.code && copy.modifiers.contains(control.modifiers)
+                    })
+                    .then(|| {
+                        format!(
+                            "key [{}] is reserved and cannot copy to {}",
+                            copy,
+                            dest.path.display()
+                        )
+                    })
+            })
+            .collect();
+        copy_conflicts.sort();
+        conflicts.append(&mut copy_conflicts);
+        for (first, second) in &self.duplicate_keys {
+            conflicts.push(format!(
+                "keys \"{}\" and \"{}\" are the same key; only one dest is used",
+                first, second
+            ));
+        }
+        conflicts
+    }
+
+    /// dir直下のサブディレクトリを分類先として，キーを自動で割り当てた設定を作る
+    pub fn auto(dir: PathBuf) -> Result<Self> {
+        let mut subdirs: Vec<PathBuf> = fs::read_dir(&dir)
+            .with_context(|| format!("cannot read dir {}", dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| entry.path())
+            .collect();
+        subdirs.sort();
+
+        let mut dests = HashMap::new();
+        for subdir in subdirs {
+            let name = subdir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_lowercase();
+            // 名前の頭文字，名前に含まれる文字，残りのキーの順に空いているものを使う
+            let key = name
+                .chars()
+                .chain(FREE_KEYS.chars())
+                .filter(|c| FREE_KEYS.contains(*c))
+                .map(|c| KeyBind::new(KeyCode::Char(c), KeyModifiers::NONE))
+                .find(|key| !dests.contains_key(key));
+            match key {
+                Some(key) => {
+                    dests.insert(key, Dest::from(subdir));
+                }
+                None => break,
+            }
+        }
+        if dests.is_empty() {
+            return Err(anyhow!("no subdirectories found in dir: {}", dir.display()));
+        }
+
+        Ok(Config {
+            dir: vec![dir],
+            dests,
+            ..Default::default()
+        })
+    }
+
+    /// 設定の誤りを調べ，見つかった問題を返す
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for dir in &self.dir {
+            if is_glob(dir) {
+                if let Err(e) = glob::Pattern::new(&dir.to_string_lossy()) {
+                    problems.push(format!("invalid glob pattern {}: {}", dir.display(), e));
+                }
+            } else if !dir.is_dir() {
+                problems.push(format!("dir is not a directory: {}", dir.display()));
+            }
+        }
+        if self.dests.is_empty() {
+            problems.push("no dests are set".to_string());
+        }
+        let mut dests: Vec<(&KeyBind, &PathBuf)> = self
+            .dests
+            .iter()
+            .map(|(key, dest)| (key, &dest.path))
+            .collect();
+        dests.sort_by_key(|(key, _)| key.to_string());
+        let rule_dests = self
+            .rules
+            .iter()
+            .map(|rule| ("rule".to_string(), &rule.dest));
+        for (name, dest) in dests
+            .into_iter()
+            .map(|(key, dest)| (format!("[{}]", key), dest))
+            .chain(rule_dests)
+        {
+            if let Err(e) = template::check(&dest.to_string_lossy()) {
+                problems.push(format!("dest of {}: {}", name, e));
+            } else if dest.exists() && !dest.is_dir() {
+                problems.push(format!(
+                    "dest of {} is not a directory: {}",
+                    name,
+                    dest.display()
+                ));
+            }
+        }
+        for (key, dest) in &self.dests {
+            let rewrites = dest.strip_exif || dest.max_dimension.is_some();
+            if rewrites && matches!(dest.mode, DestMode::Symlink | DestMode::Hardlink) {
+                problems.push(format!(
+                    "strip_exif and max_dimension of [{}] cannot be used with {:?} mode",
+                    key, dest.mode
+                ));
+            }
+            if dest.max_dimension == Some(0) {
+                problems.push(format!("max_dimension of [{}] must be at least 1", key));
+            }
+        }
+        for (key, rename) in &self.rename {
+            if !self.dests.contains_key(key) {
+                problems.push(format!("rename of [{}] has no dest", key));
+            }
+            if let Err(e) = template::check(rename) {
+                problems.push(format!("rename of [{}]: {}", key, e));
+            }
+        }
+        let features = [
+            ("HEIF", "heif", &heif::EXTENSIONS[..], heif::is_available()),
+            ("PDF", "pdf", &pdf::EXTENSIONS[..], pdf::is_available()),
+            (
+                "video",
+                "video",
+                &video::EXTENSIONS[..],
+                video::is_available(),
+            ),
+        ];
+        for (format, feature, extensions, available) in features {
+            let included = self.extensions.iter().flatten().any(|ext| {
+                extensions
+                    .iter()
+                    .any(|e| ext.trim_start_matches('.').eq_ignore_ascii_case(e))
+            });
+            if included && !available {
+                problems.push(format!(
+                    "extensions include {} but shotclassif was built without the {} feature",
+                    format, feature
+                ));
+            }
+        }
+        if self.workers == Some(0) {
+            problems.push("workers must be at least 1".to_string());
+        }
+        if let Some(percent) = self.image_percent.filter(|p| !(10..=90).contains(p)) {
+            problems.push(format!("image_percent must be in 10..=90: {}", percent));
+        }
+        if self.ocr.is_none() && self.rules.iter().any(Rule::uses_text) {
+            problems.push("rules use text but ocr is not set".to_string());
+        }
+        if !(0.0..1.0).contains(&self.val_ratio) {
+            problems.push(format!("val_ratio must be in 0..1: {}", self.val_ratio));
+        }
+        let models = [
+            ("model", &self.model),
+            ("embedding_model", &self.embedding_model),
+        ];
+        for (name, path) in models {
+            let Some(path) = path else {
+                continue;
+            };
+            if !model::is_available() {
+                problems.push(format!(
+                    "{} is set but shotclassif was built without the onnx feature",
+                    name
+                ));
+            } else if !path.is_file() {
+                problems.push(format!("{} file not found: {}", name, path.display()));
+            }
+        }
+        if let Some(threshold) = self.similar_threshold.filter(|&t| t > 64) {
+            problems.push(format!(
+                "similar_threshold must be in 0..=64: {}",
+                threshold
+            ));
+        }
+        problems.extend(self.key_conflicts());
+        problems
+    }
+}
+
+/// ワイルドカードを含むglobのパターンか
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// 分類先．設定ファイルではパスだけの文字列か，pathとオプションを書いたテーブルで指定する
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Dest {
+    pub path: PathBuf,
+    // キーの一覧やラベルに使う名前 (省略時はパス)
+    pub label: Option<String>,
+    // 画像の置き方
+    #[serde(default)]
+    pub mode: DestMode,
+    // キーの一覧の色 (省略時はテーマの色)
+    #[serde(default, deserialize_with = "deserialize_color")]
+    pub color: Option<Color>,
+    // この分類先に置くたびに実行するコマンド ("{src}"と"{dest}"は移動元と移動先のパス)
+    pub hook: Option<String>,
+    // 送る前に確認するか
+    #[serde(default)]
+    pub confirm: bool,
+    // 置くときに画像を書き直してEXIFやGPS，XMPなどのメタデータを取り除くか
+    #[serde(default)]
+    pub strip_exif: bool,
+    // 置くときに長い辺をこのピクセル数まで縮める
+    pub max_dimension: Option<u32>,
+}
+
+impl Dest {
+    /// 表示に使う名前
+    pub fn name(&self) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => self.path.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+impl From<PathBuf> for Dest {
+    fn from(path: PathBuf) -> Self {
+        Dest {
+            path,
+            label: None,
+            mode: DestMode::default(),
+            color: None,
+            hook: None,
+            confirm: false,
+            strip_exif: false,
+            max_dimension: None,
+        }
+    }
+}
+
+/// 分類先を文字列でもテーブルでも書けるようにする
+fn deserialize_dests<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<KeyBind, Dest>, D::Error> {
+    struct DestValue(Dest);
+
+    impl<'de> Deserialize<'de> for DestValue {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(DestVisitor)
+        }
+    }
+
+    struct DestVisitor;
+
+    impl<'de> Visitor<'de> for DestVisitor {
+        type Value = DestValue;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a path or a table with path")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<DestValue, E> {
+            Ok(DestValue(Dest::from(PathBuf::from(value))))
+        }
+
+        // テーブルの誤りはそのまま伝える
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<DestValue, A::Error> {
+            Dest::deserialize(MapAccessDeserializer::new(map)).map(DestValue)
+        }
+    }
+
+    let dests = HashMap::<KeyBind, DestValue>::deserialize(deserializer)?;
+    Ok(dests
+        .into_iter()
+        .map(|(key, DestValue(dest))| (key, dest))
+        .collect())
+}
+
+/// "red"などの色名，"#rrggbb"，0〜255の番号で書いた色
+fn deserialize_color<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Color>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Color::from_str(&value)
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("invalid color: {}", value)))
+}
+
+/// 分類元を1つの文字列でもリストでも書けるようにする
+fn deserialize_dirs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Dirs {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    let dirs = match Dirs::deserialize(deserializer)? {
+        Dirs::One(dir) => vec![dir],
+        Dirs::Many(dirs) => dirs,
+    };
+    Ok(dirs.into_iter().map(expand_home).collect())
+}
+
+/// 先頭の"~"をホームディレクトリに置き換える
+fn expand_home(path: PathBuf) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path;
+    };
+    match env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+        Some(home) => PathBuf::from(home).join(rest),
+        None => path,
+    }
+}
+
+/// 設定ファイルの分類先のうち，書き方が違うだけで同じキーになるものの組
+fn duplicate_keys(config_str: &str) -> Vec<(String, String)> {
+    let Ok(table) = config_str.parse::<toml::Table>() else {
+        return Vec::new();
+    };
+    let Some(dests) = table.get("dests").and_then(|dests| dests.as_table()) else {
+        return Vec::new();
+    };
+    let mut seen: HashMap<KeyBind, &str> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for name in dests.keys() {
+        let Ok(key) = name.parse::<KeyBind>() else {
+            continue;
+        };
+        if let Some(first) = seen.insert(key, name) {
+            duplicates.push((first.to_string(), name.clone()));
+        }
+    }
+    duplicates
+}
+
+/// コマンドライン引数の"a=keep"のような分類先の指定を解釈する
+pub fn parse_dest(s: &str) -> Result<(KeyBind, PathBuf)> {
+    let (key, dest) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("dest must be KEY=DIR: {}", s))?;
+    Ok((key.parse()?, PathBuf::from(dest)))
+}
+
+/// 設定ファイルの書式を保ったまま分類先を追記する
+pub fn save_dest(path: &Path, key: KeyBind, dest: &Path) -> Result<()> {
+    let config_str = fs::read_to_string(path)
+        .with_context(|| format!("cannot read config {}", path.display()))?;
+    let mut doc: toml_edit::DocumentMut = config_str
+        .parse()
+        .with_context(|| format!("config is not valid toml: {}", path.display()))?;
+    doc["dests"][key.to_string().as_str()] = toml_edit::value(dest.to_string_lossy().as_ref());
+    fs::write(path, doc.to_string())
+        .with_context(|| format!("cannot write config {}", path.display()))
+}
+
+/// 分類先のキーと一緒に押す修飾キー
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Modifier {
+    #[default]
+    Alt,
+    Ctrl,
+    Shift,
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Modifier::Alt => write!(f, "Alt"),
+            Modifier::Ctrl => write!(f, "Ctrl"),
+            Modifier::Shift => write!(f, "Shift"),
+        }
+    }
+}
+
+/// 分類先に割り当てるキー ("a", "F1", "Shift+a", "Ctrl+d" など)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyBind {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyBind {
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let modifiers =
+            modifiers & (KeyModifiers::SHIFT | KeyModifiers::CONTROL | KeyModifiers::ALT);
+        // Shift付きの文字は大文字として扱う
+        match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => KeyBind {
+                code: KeyCode::Char(c.to_ascii_uppercase()),
+                modifiers: modifiers - KeyModifiers::SHIFT,
+            },
+            _ => KeyBind { code, modifiers },
+        }
+    }
+
+    /// 修飾キーを押しながら押したキー．Shift付きの文字は大文字になる
+    pub fn with(self, modifier: Modifier) -> KeyBind {
+        let modifiers = match modifier {
+            Modifier::Alt => KeyModifiers::ALT,
+            Modifier::Ctrl => KeyModifiers::CONTROL,
+            Modifier::Shift => KeyModifiers::SHIFT,
+        };
+        KeyBind::new(self.code, self.modifiers | modifiers)
+    }
+
+    /// 修飾キーを押していれば，それを除いたキー
+    pub fn without(self, modifier: Modifier) -> Option<KeyBind> {
+        let modifiers = match modifier {
+            Modifier::Alt => KeyModifiers::ALT,
+            Modifier::Ctrl => KeyModifiers::CONTROL,
+            // Shift付きの文字は大文字になっているので小文字に戻す
+            Modifier::Shift => {
+                return match self.code {
+                    KeyCode::Char(c) if c.is_ascii_uppercase() => Some(KeyBind {
+                        code: KeyCode::Char(c.to_ascii_lowercase()),
+                        ..self
+                    }),
+                    _ => None,
+                }
+            }
+        };
+        self.modifiers.contains(modifiers).then(|| KeyBind {
+            modifiers: self.modifiers - modifiers,
+            ..self
+        })
+    }
+}
+
+impl From<KeyEvent> for KeyBind {
+    fn from(key: KeyEvent) -> Self {
+        KeyBind::new(key.code, key.modifiers)
+    }
+}
+
+impl FromStr for KeyBind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // "+"そのものも割り当てられるよう最後の要素はそのままキーとする
+        let (mods, key) = match s.rsplit_once('+') {
+            Some((mods, "")) => (mods.strip_suffix('+').unwrap_or(mods), "+"),
+            Some((mods, key)) => (mods, key),
+            None => ("", s),
+        };
+
+        let mut modifiers = KeyModifiers::NONE;
+        for m in mods.split('+').filter(|m| !m.is_empty()) {
+            modifiers |= match m.to_ascii_lowercase().as_str() {
+                "shift" => KeyModifiers::SHIFT,
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                _ => return Err(anyhow!("unknown modifier in keybind: {}", s)),
+            };
+        }
+
+        let mut chars = key.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match key.to_ascii_lowercase().as_str() {
+                "space" => KeyCode::Char(' '),
+                "enter" => KeyCode::Enter,
+                "tab" => KeyCode::Tab,
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" | "ins" => KeyCode::Insert,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "esc" => KeyCode::Esc,
+                f => match f.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n) if (1..=24).contains(&n) => KeyCode::F(n),
+                    _ => return Err(anyhow!("unknown key in keybind: {}", s)),
+                },
+            },
+        };
+        Ok(KeyBind::new(code, modifiers))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyBind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for KeyBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "Shift+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::PageUp => write!(f, "PageUp"),
+            KeyCode::PageDown => write!(f, "PageDown"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(modifier: Modifier, keys: &[&str]) -> Config {
+        let mut config = Config {
+            copy_modifier: modifier,
+            ..Config::default()
+        };
+        for key in keys {
+            config
+                .dests
+                .insert(key.parse().unwrap(), PathBuf::from(key).into());
+        }
+        config
+    }
+
+    #[test]
+    fn copy_modifier_conflicts_with_control_keys() {
+        let conflicts = config(Modifier::Shift, &["s", "a"]).key_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("[S]"));
+
+        let conflicts = config(Modifier::Ctrl, &["r", "a"]).key_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("[Ctrl+r]"));
+
+        assert!(config(Modifier::Alt, &["s", "r"])
+            .key_conflicts()
+            .is_empty());
+    }
+}
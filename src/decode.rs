@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    metadata::Orientation,
+    AnimationDecoder, DynamicImage, ImageBuffer, ImageDecoder, ImageFormat, ImageReader,
+    ImageResult, Rgb, RgbImage,
+};
+
+use std::{fs::File, io::BufReader, path::Path, time::Duration};
+
+use crate::{app::AlphaBackground, heif, icc, pdf, raw, svg, video};
+
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+// 透明な部分の市松模様の1辺あたりのマス目の数
+const CHECKER_CELLS: u32 = 32;
+// 市松模様の明るいマスと暗いマスの色
+const CHECKER_LIGHT: [u8; 3] = [204, 204, 204];
+const CHECKER_DARK: [u8; 3] = [153, 153, 153];
+
+/// TIFFのページ数．各ページの画素は読まない
+pub fn tiff_page_count(path: &Path) -> usize {
+    let Ok(mut decoder) = File::open(path)
+        .map_err(tiff::TiffError::from)
+        .and_then(|file| tiff::decoder::Decoder::new(BufReader::new(file)))
+    else {
+        return 1;
+    };
+    let mut pages = 1;
+    while decoder.more_images() && decoder.next_image().is_ok() {
+        pages += 1;
+    }
+    pages
+}
+
+/// 複数ページのTIFFのpage番目 (0から) のページ．imageクレートは最初のページしか読まない
+pub fn decode_tiff_page(path: &Path, page: usize) -> Result<DynamicImage> {
+    let mut decoder = File::open(path)
+        .map_err(tiff::TiffError::from)
+        .and_then(|file| tiff::decoder::Decoder::new(BufReader::new(file)))
+        .and_then(|mut decoder| decoder.seek_to_image(page).map(|()| decoder))
+        .with_context(|| format!("cannot open page {} of {}", page + 1, path.display()))?;
+    tiff_page(&mut decoder)
+        .with_context(|| format!("cannot decode page {} of {}", page + 1, path.display()))
+}
+
+/// TIFFのページ数が2以上か
+pub fn is_multipage_tiff(path: &Path) -> bool {
+    File::open(path)
+        .map_err(tiff::TiffError::from)
+        .and_then(|file| tiff::decoder::Decoder::new(BufReader::new(file)))
+        .is_ok_and(|decoder| decoder.more_images())
+}
+
+/// TIFFの今のページをデコードする
+fn tiff_page(decoder: &mut tiff::decoder::Decoder<BufReader<File>>) -> Option<DynamicImage> {
+    use tiff::{decoder::DecodingResult, ColorType};
+
+    let (width, height) = decoder.dimensions().ok()?;
+    let color = decoder.colortype().ok()?;
+    Some(match (color, decoder.read_image().ok()?) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data)?)
+        }
+        _ => return None,
+    })
+}
+
+/// EXIFの向きの情報を反映して画像をデコードする
+pub fn decode_oriented(reader: ImageReader<BufReader<File>>, path: &Path) -> Result<DynamicImage> {
+    let mut decoder = reader
+        .into_decoder()
+        .with_context(|| format!("cannot decode image {}", path.display()))?;
+    // 向きの情報やICCプロファイルが読めなくても表示はする
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc = decoder.icc_profile().ok().flatten();
+    let mut dynamic_img = DynamicImage::from_decoder(decoder)
+        .with_context(|| format!("cannot decode image {}", path.display()))?;
+    if let Some(icc) = icc {
+        icc::to_srgb(&mut dynamic_img, &icc);
+    }
+    dynamic_img.apply_orientation(orientation);
+    Ok(dynamic_img)
+}
+
+/// 透明な部分を背景と合成した不透明な画像
+///
+/// 端末の背景が透けると，透明なPNGと白い背景のPNGを見分けられない
+pub fn flatten_alpha(img: DynamicImage, background: AlphaBackground) -> DynamicImage {
+    if !img.color().has_alpha() || background == AlphaBackground::None {
+        return img;
+    }
+    // 表示する大きさによらず同じ数のマス目にする
+    let cell = (img.width().max(img.height()) / CHECKER_CELLS).max(1);
+    let rgba = img.to_rgba8();
+    let rgb = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let back = match background {
+            AlphaBackground::Color(color) => color,
+            _ if (x / cell + y / cell).is_multiple_of(2) => CHECKER_LIGHT,
+            _ => CHECKER_DARK,
+        };
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |front: u8, back: u8| {
+            ((u16::from(front) * u16::from(a) + u16::from(back) * u16::from(255 - a) + 127) / 255)
+                as u8
+        };
+        Rgb([blend(r, back[0]), blend(g, back[1]), blend(b, back[2])])
+    });
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// 画像を読み込み，各フレームと表示する時間を返す
+pub fn decode_raw(path: &Path) -> Result<Vec<(DynamicImage, Duration)>> {
+    // imageクレートが読めない形式
+    if heif::sniff(path) {
+        return Ok(vec![(heif::decode(path)?, Duration::ZERO)]);
+    }
+    // RAWはTIFFとして読めてしまうので先に見分ける
+    if raw::is_raw(path) {
+        return Ok(vec![(raw::decode_preview(path)?, Duration::ZERO)]);
+    }
+    if svg::is_svg(path) {
+        return Ok(vec![(svg::render(path)?, Duration::ZERO)]);
+    }
+    if pdf::sniff(path) {
+        return Ok(vec![(pdf::render(path)?, Duration::ZERO)]);
+    }
+    if video::is_video(path) {
+        return Ok(vec![(video::frame(path)?, Duration::ZERO)]);
+    }
+    let reader = ImageReader::open(path)
+        .with_context(|| format!("cannot open file {}", path.display()))?
+        .with_guessed_format()
+        .with_context(|| format!("cannot open file {}", path.display()))?;
+
+    // アニメーションは全フレームをデコードする
+    let animation = reader
+        .format()
+        .filter(|&format| format == ImageFormat::Gif || is_animated_img(path, format));
+    if let Some(format) = animation {
+        let frames = decode_frames(reader.into_inner(), format)
+            .with_context(|| format!("cannot decode image {}", path.display()))?;
+        return Ok(frames
+            .into_iter()
+            .map(|frame| {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let delay = Duration::from_millis(u64::from(numer / denom.max(1)));
+                // ブラウザと同様に極端に短い間隔は補正する
+                let delay = if delay < MIN_FRAME_DELAY {
+                    DEFAULT_FRAME_DELAY
+                } else {
+                    delay
+                };
+                (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+            })
+            .collect());
+    }
+
+    Ok(vec![(decode_oriented(reader, path)?, Duration::ZERO)])
+}
+
+/// アニメーションGIF，WebP，APNGの全フレーム
+fn decode_frames(file: BufReader<File>, format: ImageFormat) -> ImageResult<Vec<image::Frame>> {
+    match format {
+        ImageFormat::Gif => GifDecoder::new(file)?.into_frames().collect_frames(),
+        ImageFormat::WebP => WebPDecoder::new(file)?.into_frames().collect_frames(),
+        _ => PngDecoder::new(file)?
+            .apng()?
+            .into_frames()
+            .collect_frames(),
+    }
+}
+
+/// アニメーションするWebPかAPNGか (GIFは常にアニメーションとして扱う)
+pub fn is_animated_img(path: &Path, format: ImageFormat) -> bool {
+    let Ok(file) = File::open(path).map(BufReader::new) else {
+        return false;
+    };
+    match format {
+        ImageFormat::WebP => WebPDecoder::new(file).is_ok_and(|decoder| decoder.has_animation()),
+        ImageFormat::Png => PngDecoder::new(file)
+            .and_then(|decoder| decoder.is_apng())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
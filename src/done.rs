@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+
+use std::{
+    collections::BTreeSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::session::replace_file;
+
+/// 分類元ディレクトリに置くスキップ済みの一覧のファイルの名前
+const DONE_FILE_NAME: &str = ".shotclassif_done";
+
+/// 過去のセッションでスキップした画像の一覧．次回以降は表示しない
+pub struct DoneList {
+    path: PathBuf,
+    dir: PathBuf,
+    // 分類元ディレクトリからの相対パス
+    files: BTreeSet<PathBuf>,
+}
+
+impl DoneList {
+    /// 一覧を読み込む．存在しない場合は空の一覧にする
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(DONE_FILE_NAME);
+        let files = if path.exists() {
+            fs::read_to_string(&path)
+                .with_context(|| format!("cannot read done file: {}", path.display()))?
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        } else {
+            BTreeSet::new()
+        };
+        Ok(DoneList {
+            path,
+            dir: dir.to_path_buf(),
+            files,
+        })
+    }
+
+    /// 過去にスキップした画像か
+    pub fn contains(&self, src: &Path) -> bool {
+        self.files.contains(self.relative(src))
+    }
+
+    /// スキップした画像を追加して，ファイルに追記する
+    pub fn insert(&mut self, src: &Path) -> Result<()> {
+        let file = self.relative(src).to_path_buf();
+        let line = format!("{}\n", file.to_string_lossy());
+        if self.files.insert(file) {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .and_then(|mut f| f.write_all(line.as_bytes()))
+                .with_context(|| format!("cannot write done file: {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// スキップを取り消した画像を削除して保存する
+    pub fn remove(&mut self, src: &Path) -> Result<()> {
+        let file = self.relative(src).to_path_buf();
+        if self.files.remove(&file) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn relative<'a>(&self, src: &'a Path) -> &'a Path {
+        src.strip_prefix(&self.dir).unwrap_or(src)
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut done_str = String::new();
+        for file in &self.files {
+            done_str.push_str(&file.to_string_lossy());
+            done_str.push('\n');
+        }
+        replace_file(&self.path, &done_str)
+            .with_context(|| format!("cannot write done file: {}", self.path.display()))
+    }
+}
@@ -0,0 +1,765 @@
+use anyhow::{anyhow, bail, Context, Result};
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageFormat, ImageReader,
+    ImageResult,
+};
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufReader, Cursor},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    app::DestMode,
+    decode::{decode_oriented, is_animated_img, is_multipage_tiff},
+    raw,
+};
+
+// コピーした後も分類先のファイルシステムに残しておく空き容量
+const FREE_SPACE_MARGIN: u64 = 16 * 1024 * 1024;
+// JPEGを書き直すときの画質
+const REWRITE_JPEG_QUALITY: u8 = 95;
+// JPEGのAPP1セグメントでEXIFの前に置く識別子
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+// EXIFの向きの値．鏡像にするかと，その後に時計回りに回す回数で並べる
+const ORIENTATIONS: [[u16; 4]; 2] = [[1, 6, 3, 8], [2, 7, 4, 5]];
+
+/// 分類先に置くときに画像を書き直す設定
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rewrite {
+    // メタデータを取り除く
+    pub strip_exif: bool,
+    // 長い辺をこのピクセル数まで縮める
+    pub max_dimension: Option<u32>,
+}
+
+impl Rewrite {
+    /// 書き直さずにそのまま置くか
+    pub fn is_none(&self) -> bool {
+        !self.strip_exif && self.max_dimension.is_none()
+    }
+}
+
+/// 移動した画像を元の場所に戻す
+fn move_back(dest: &Path, src: &Path) -> Result<()> {
+    if src.exists() {
+        return Err(anyhow!("undo destination has same name file"));
+    }
+    fs::rename(dest, src).with_context(|| {
+        format!(
+            "Failed to move image back from {} to {}",
+            dest.display(),
+            src.display()
+        )
+    })
+}
+
+/// 画像を送るゴミ箱．ディレクトリを設定すればOSのゴミ箱の代わりにそこに送る
+pub struct Trash {
+    dir: Option<PathBuf>,
+}
+
+impl Trash {
+    /// dirがなければOSのゴミ箱を使う
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Trash { dir }
+    }
+
+    /// ファイルをゴミ箱に送る
+    pub fn delete(&self, path: &Path) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(trash::delete(path)?);
+        };
+        let slots = trash_slots(dir, path)?;
+        fs::create_dir_all(&slots)
+            .with_context(|| format!("cannot create trash dir {}", slots.display()))?;
+        let slot = last_trash_slot(&slots).map_or(0, |n| n + 1);
+        fs::rename(path, slots.join(slot.to_string()))
+            .with_context(|| format!("cannot move {} to {}", path.display(), dir.display()))
+    }
+
+    /// ゴミ箱に送った画像を元の場所に戻す．同名のファイルが複数あれば最後に送ったものを戻す
+    pub fn restore(&self, src: &Path) -> Result<()> {
+        if src.exists() {
+            return Err(anyhow!("undo destination has same name file"));
+        }
+        let Some(dir) = &self.dir else {
+            return restore_from_trash(src);
+        };
+        let slots = trash_slots(dir, src)?;
+        let slot = last_trash_slot(&slots)
+            .with_context(|| format!("{} is not found in trash", src.display()))?;
+        fs::rename(slots.join(slot.to_string()), src)
+            .with_context(|| format!("Failed to restore image from trash: {}", src.display()))
+    }
+}
+
+/// 設定したゴミ箱の中で，元の場所ごとにファイルを番号で並べるディレクトリ
+fn trash_slots(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+    .canonicalize()
+    .with_context(|| format!("cannot resolve dir of {}", path.display()))?;
+    let file_name = path.file_name().context("Failed to get file name")?;
+    let mut hasher = DefaultHasher::new();
+    parent.hash(&mut hasher);
+    Ok(dir
+        .join(format!("{:016x}", hasher.finish()))
+        .join(file_name))
+}
+
+/// 設定したゴミ箱に最後に送ったファイルの番号
+fn last_trash_slot(slots: &Path) -> Option<u64> {
+    fs::read_dir(slots)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .max()
+}
+
+/// ファイルの大きさと中身のハッシュ
+pub fn content_hash(path: &Path) -> Option<(u64, u64)> {
+    let bytes = fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    Some((bytes.len() as u64, hasher.finish()))
+}
+
+/// ディレクトリの中のファイルを大きさと中身のハッシュで引く索引
+pub fn index_dir(dir: &Path) -> HashMap<(u64, u64), PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| {
+            let path = entry.path();
+            Some((content_hash(&path)?, path))
+        })
+        .collect()
+}
+
+/// 2つのファイルの中身が同じか．ハッシュが一致したときに確かめる
+pub fn same_content(a: &Path, b: &Path) -> bool {
+    match (fs::read(a), fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// 分類先の設定に従って画像を移動，コピー，リンクする．書き直して移動元をゴミ箱に送った場合はtrue
+pub fn place_img(
+    src: &Path,
+    dest: &Path,
+    mode: DestMode,
+    rewrite: Rewrite,
+    trash: &Trash,
+) -> Result<bool> {
+    // リンクは元のファイルを指すので書き直せない
+    if !rewrite.is_none() && matches!(mode, DestMode::Move | DestMode::Copy) {
+        return rewrite_img(src, dest, mode, rewrite, trash);
+    }
+    place_as_is(src, dest, mode)?;
+    Ok(false)
+}
+
+/// 画像を書き直さずにそのまま置く
+fn place_as_is(src: &Path, dest: &Path, mode: DestMode) -> Result<()> {
+    match mode {
+        DestMode::Move => move_img(src, dest),
+        // 移動は同じファイルシステム内で名前を変えるだけなので空きは要らない
+        DestMode::Copy => {
+            create_dest_dir(dest)?;
+            check_free_space(src, dest)?;
+            fs::copy(src, dest).map_err(|e| {
+                // 書きかけのファイルを残さない
+                let _ = fs::remove_file(dest);
+                anyhow::Error::new(e).context(format!(
+                    "Failed to copy image from {} to {}",
+                    src.display(),
+                    dest.display()
+                ))
+            })?;
+            Ok(())
+        }
+        DestMode::Symlink => {
+            create_dest_dir(dest)?;
+            // リンクの場所からの相対パスにならないよう絶対パスを指す
+            let target = src
+                .canonicalize()
+                .with_context(|| format!("cannot resolve path {}", src.display()))?;
+            symlink(&target, dest).with_context(|| {
+                format!(
+                    "Failed to link image from {} to {}",
+                    src.display(),
+                    dest.display()
+                )
+            })
+        }
+        DestMode::Hardlink => {
+            create_dest_dir(dest)?;
+            fs::hard_link(src, dest).with_context(|| {
+                format!(
+                    "Failed to hard link image from {} to {}",
+                    src.display(),
+                    dest.display()
+                )
+            })
+        }
+    }
+}
+
+/// 画像をデコードして書き直して置く．移動なら取り消せるよう元のファイルをゴミ箱に送る
+///
+/// imageクレートはメタデータを書き出さないので，書き直すだけでEXIFやXMPは落ちる．
+/// 向きの情報も落ちるので画素に反映しておく．縮めるだけのときはJPEGのEXIFを入れ直す
+fn rewrite_img(
+    src: &Path,
+    dest: &Path,
+    mode: DestMode,
+    rewrite: Rewrite,
+    trash: &Trash,
+) -> Result<bool> {
+    let reader = ImageReader::open(src)
+        .and_then(|reader| reader.with_guessed_format())
+        .with_context(|| format!("cannot open image {}", src.display()))?;
+    let format = reader
+        .format()
+        .with_context(|| format!("unknown image format: {}", src.display()))?;
+    if raw::is_raw(src) {
+        bail!("cannot rewrite RAW image {}", src.display());
+    }
+    // 2ページ目以降が落ちる
+    if format == ImageFormat::Tiff && is_multipage_tiff(src) {
+        bail!("cannot rewrite multi-page TIFF {}", src.display());
+    }
+    // アニメーションは最初のコマしか残らないので書き直さない (GIFはEXIFを持たない)
+    if format == ImageFormat::Gif {
+        return place_as_is(src, dest, mode).map(|()| false);
+    }
+    // WebPとAPNGはメタデータを持ちうるので，消す分類先には置かない
+    if is_animated_img(src, format) {
+        if rewrite.strip_exif {
+            bail!(
+                "cannot strip metadata from animated image {}",
+                src.display()
+            );
+        }
+        return place_as_is(src, dest, mode).map(|()| false);
+    }
+    let mut img = decode_oriented(reader, src)?;
+    let max = rewrite
+        .max_dimension
+        .filter(|&max| img.width().max(img.height()) > max);
+    match max {
+        Some(max) => img = img.resize(max, max, FilterType::Lanczos3),
+        // 縮めるだけの分類先なら十分小さい画像はそのまま置く
+        None if !rewrite.strip_exif => return place_as_is(src, dest, mode).map(|()| false),
+        None => {}
+    }
+    // 縮めるだけならJPEGの撮影日時などは残す
+    let exif = if rewrite.strip_exif || format != ImageFormat::Jpeg {
+        None
+    } else {
+        rewritten_exif(src)
+    };
+    create_dest_dir(dest)?;
+    check_free_space(src, dest)?;
+    write_img(&img, dest, format, exif).inspect_err(|_| {
+        // 書きかけのファイルを残さない
+        let _ = fs::remove_file(dest);
+    })?;
+    if mode != DestMode::Move {
+        return Ok(false);
+    }
+    // 書き直すと元の画質やメタデータは戻らないので，消さずにゴミ箱に送る
+    trash.delete(src).map_err(|e| {
+        let _ = fs::remove_file(dest);
+        e.context(format!(
+            "Failed to move rewritten image to trash: {}",
+            src.display()
+        ))
+    })?;
+    Ok(true)
+}
+
+/// 元の画像と同じ形式で書き出す．JPEGは画質を落としすぎないようにし，EXIFがあれば埋め込む
+fn write_img(
+    img: &DynamicImage,
+    dest: &Path,
+    format: ImageFormat,
+    exif: Option<Vec<u8>>,
+) -> Result<()> {
+    let mut bytes = encode_img(img, format)
+        .with_context(|| format!("cannot encode image {}", dest.display()))?;
+    if let Some(segment) = exif.and_then(|exif| exif_segment(&exif)) {
+        // SOIの直後に置く
+        bytes.splice(2..2, segment);
+    }
+    fs::write(dest, bytes).with_context(|| format!("cannot write image {}", dest.display()))
+}
+
+/// 画像を指定した形式のバイト列にする
+fn encode_img(img: &DynamicImage, format: ImageFormat) -> ImageResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match format {
+        // JPEGはアルファを持てない
+        ImageFormat::Jpeg => img
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(
+                &mut bytes,
+                REWRITE_JPEG_QUALITY,
+            ))?,
+        _ => img.write_to(&mut Cursor::new(&mut bytes), format)?,
+    }
+    Ok(bytes)
+}
+
+/// 書き直した画像に合わせたEXIF．向きは画素に反映し，大きさやサムネイルは古くなるので除く
+fn rewritten_exif(src: &Path) -> Option<Vec<u8>> {
+    let file = File::open(src).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    let stale = [
+        exif::Tag::Orientation,
+        exif::Tag::ImageWidth,
+        exif::Tag::ImageLength,
+        exif::Tag::PixelXDimension,
+        exif::Tag::PixelYDimension,
+    ];
+    let mut writer = exif::experimental::Writer::new();
+    for field in exif.fields() {
+        if field.ifd_num == exif::In::PRIMARY && !stale.contains(&field.tag) {
+            writer.push_field(field);
+        }
+    }
+    let mut buf = Cursor::new(Vec::new());
+    // 残すタグがなかったり書けなかったりすればEXIFなしで置く
+    writer.write(&mut buf, exif.little_endian()).ok()?;
+    Some(buf.into_inner())
+}
+
+/// EXIFを入れたJPEGのAPP1セグメント．セグメントに収まらなければNone
+fn exif_segment(exif: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::try_from(2 + EXIF_HEADER.len() + exif.len()).ok()?;
+    let mut segment = vec![0xff, 0xe1];
+    segment.extend_from_slice(&len.to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(exif);
+    Some(segment)
+}
+
+/// 画像のファイルを90度回転する
+///
+/// JPEGはEXIFの向きを書き換えて画素を劣化させずに回す．他の形式は画素を回して書き直す
+pub fn rotate_img(path: &Path, clockwise: bool, trash: &Trash) -> Result<bool> {
+    if raw::is_raw(path) {
+        bail!("cannot rotate RAW image {}", path.display());
+    }
+    let reader = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .with_context(|| format!("cannot open image {}", path.display()))?;
+    let (bytes, rewritten) = match reader.format() {
+        Some(ImageFormat::Jpeg) => {
+            let mut bytes =
+                fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+            rotate_jpeg(&mut bytes, clockwise)
+                .with_context(|| format!("cannot rotate {}", path.display()))?;
+            (bytes, false)
+        }
+        // アニメーションは最初のコマしか残らなくなる
+        Some(ImageFormat::Gif) => bail!("cannot rotate GIF {}", path.display()),
+        Some(format) if is_animated_img(path, format) => {
+            bail!("cannot rotate animated image {}", path.display())
+        }
+        Some(ImageFormat::Tiff) if is_multipage_tiff(path) => {
+            bail!("cannot rotate multi-page TIFF {}", path.display())
+        }
+        Some(format) => {
+            let img = decode_oriented(reader, path)?;
+            let img = if clockwise {
+                img.rotate90()
+            } else {
+                img.rotate270()
+            };
+            let bytes = encode_img(&img, format)
+                .with_context(|| format!("cannot encode image {}", path.display()))?;
+            (bytes, true)
+        }
+        None => bail!("unknown image format: {}", path.display()),
+    };
+    // 書きかけで元の画像を壊さないよう隣に書いてから置き換える
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+    fs::write(&tmp, bytes)
+        .with_context(|| format!("cannot write image {}", path.display()))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })?;
+    // 書き直すと元の画質は戻らないので，元の画像は消さずにゴミ箱に送る
+    if rewritten {
+        trash.delete(path).map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            e.context(format!(
+                "Failed to move rotated image to trash: {}",
+                path.display()
+            ))
+        })?;
+    }
+    fs::rename(&tmp, path).with_context(|| format!("cannot write image {}", path.display()))?;
+    Ok(rewritten)
+}
+
+/// 回転を取り消す．書き直した画像はゴミ箱から元の画像を戻し，JPEGは逆向きに回す
+pub fn unrotate_img(path: &Path, clockwise: bool, rewritten: bool, trash: &Trash) -> Result<()> {
+    if !rewritten {
+        rotate_img(path, !clockwise, trash)?;
+        return Ok(());
+    }
+    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    trash.restore(path)
+}
+
+/// JPEGのEXIFの向きを90度回した値にする
+fn rotate_jpeg(bytes: &mut Vec<u8>, clockwise: bool) -> Result<()> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&bytes))
+        .ok();
+    let current = exif
+        .as_ref()
+        .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY))
+        .and_then(|field| field.value.get_uint(0))
+        .and_then(|value| u16::try_from(value).ok())
+        .unwrap_or(1);
+    let next = rotated_orientation(current, clockwise);
+    let segment = exif_range(bytes);
+    if let Some(range) = &segment {
+        if patch_orientation(&mut bytes[range.start + EXIF_HEADER.len()..range.end], next) {
+            return Ok(());
+        }
+    }
+
+    // 向きのタグがなければEXIFを作り直す．サムネイルは引き継がない
+    let orientation = exif::Field {
+        tag: exif::Tag::Orientation,
+        ifd_num: exif::In::PRIMARY,
+        value: exif::Value::Short(vec![next]),
+    };
+    let mut writer = exif::experimental::Writer::new();
+    writer.push_field(&orientation);
+    if let Some(exif) = &exif {
+        for field in exif.fields() {
+            if field.ifd_num == exif::In::PRIMARY && field.tag != exif::Tag::Orientation {
+                writer.push_field(field);
+            }
+        }
+    }
+    let mut buf = Cursor::new(Vec::new());
+    writer.write(
+        &mut buf,
+        exif.as_ref().is_some_and(|exif| exif.little_endian()),
+    )?;
+    let new_segment = exif_segment(&buf.into_inner()).context("EXIF is too large")?;
+    // 古いセグメントはマーカーと長さの4バイトも含めて取り除く
+    if let Some(range) = segment {
+        bytes.drain(range.start - 4..range.end);
+    }
+    bytes.splice(2..2, new_segment);
+    Ok(())
+}
+
+/// 向きの値にさらに90度の回転を加えた値
+fn rotated_orientation(current: u16, clockwise: bool) -> u16 {
+    let (mirrored, turns) = ORIENTATIONS
+        .iter()
+        .enumerate()
+        .find_map(|(mirrored, values)| {
+            let turns = values.iter().position(|&value| value == current)?;
+            Some((mirrored, turns))
+        })
+        .unwrap_or((0, 0));
+    let turns = if clockwise { turns + 1 } else { turns + 3 };
+    ORIENTATIONS[mirrored][turns % 4]
+}
+
+/// JPEGのEXIFのAPP1セグメントの中身の範囲 (識別子を含む)
+fn exif_range(bytes: &[u8]) -> Option<Range<usize>> {
+    let mut pos = 2;
+    // 画像のデータ (SOS) より前のセグメントを順に見る
+    while bytes.get(pos) == Some(&0xff) && bytes.get(pos + 1) != Some(&0xda) {
+        let len = usize::from(u16::from_be_bytes([
+            *bytes.get(pos + 2)?,
+            *bytes.get(pos + 3)?,
+        ]));
+        let range = pos + 4..pos + 2 + len;
+        if bytes.get(pos + 1) == Some(&0xe1) && bytes.get(range.clone())?.starts_with(EXIF_HEADER) {
+            return Some(range);
+        }
+        pos = range.end;
+    }
+    None
+}
+
+/// TIFFの形のEXIFにある向きのタグを書き換える．タグがなければfalse
+fn patch_orientation(tiff: &mut [u8], value: u16) -> bool {
+    let little_endian = tiff.starts_with(b"II");
+    let read_u16 = |tiff: &[u8], pos: usize| -> Option<u16> {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let Some(ifd) = tiff.get(4..8).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    }) else {
+        return false;
+    };
+    let ifd = ifd as usize;
+    let Some(count) = read_u16(tiff, ifd) else {
+        return false;
+    };
+    for i in 0..usize::from(count) {
+        let entry = ifd + 2 + i * 12;
+        // SHORTで書かれた0x0112が向き
+        if read_u16(tiff, entry) == Some(0x0112) && read_u16(tiff, entry + 2) == Some(3) {
+            let bytes = if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            };
+            let Some(slot) = tiff.get_mut(entry + 8..entry + 10) else {
+                return false;
+            };
+            slot.copy_from_slice(&bytes);
+            return true;
+        }
+    }
+    false
+}
+
+/// 移動先にあるファイルをゴミ箱に送ってから画像を置く．置けなければゴミ箱から戻す
+pub fn replace_img(
+    src: &Path,
+    dest: &Path,
+    mode: DestMode,
+    rewrite: Rewrite,
+    trash: &Trash,
+) -> Result<bool> {
+    if dest.exists() {
+        trash.delete(dest).with_context(|| {
+            format!("Failed to move replaced file to trash: {}", dest.display())
+        })?;
+    }
+    place_img(src, dest, mode, rewrite, trash).inspect_err(|_| {
+        let _ = trash.restore(dest);
+    })
+}
+
+/// 置いた画像を取り除く．移動した画像は元の場所に戻し，上書きしたファイルはゴミ箱から戻す
+///
+/// 書き直して移動した画像は，書き直したファイルを消してゴミ箱から元の画像を戻す
+pub fn unplace_img(
+    dest: &Path,
+    src: &Path,
+    mode: DestMode,
+    replaced: bool,
+    rewritten: bool,
+    trash: &Trash,
+) -> Result<()> {
+    match mode {
+        DestMode::Move if rewritten => {
+            if src.exists() {
+                return Err(anyhow!("undo destination has same name file"));
+            }
+            fs::remove_file(dest)
+                .with_context(|| format!("Failed to remove {}", dest.display()))?;
+            trash.restore(src)?
+        }
+        DestMode::Move => move_back(dest, src)?,
+        DestMode::Copy | DestMode::Symlink | DestMode::Hardlink => {
+            fs::remove_file(dest).with_context(|| format!("Failed to remove {}", dest.display()))?
+        }
+    }
+    if replaced {
+        trash.restore(dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+/// 画像を移動する．移動先のディレクトリがなければ作成する
+fn move_img(src: &Path, dest: &Path) -> Result<()> {
+    create_dest_dir(dest)?;
+    fs::rename(src, dest).with_context(|| {
+        format!(
+            "Failed to move image from {} to {}",
+            src.display(),
+            dest.display()
+        )
+    })
+}
+
+/// 置く先のファイルシステムに画像を書き込むだけの空きがあるか確かめる
+fn check_free_space(src: &Path, dest: &Path) -> Result<()> {
+    let size = fs::metadata(src)
+        .with_context(|| format!("cannot read size of {}", src.display()))?
+        .len();
+    let dir = dest.parent().unwrap_or(Path::new("."));
+    // 空きを調べられないファイルシステムでは確かめずに書き込む
+    let Some(free) = free_space(dir) else {
+        return Ok(());
+    };
+    if free < size + FREE_SPACE_MARGIN {
+        bail!(
+            "not enough free space in {} ({} bytes free, {} bytes needed)",
+            dir.display(),
+            free,
+            size
+        );
+    }
+    Ok(())
+}
+
+/// ディレクトリがあるファイルシステムの空き容量．まだないディレクトリは作られる先の親で調べる
+pub fn free_space(dir: &Path) -> Option<u64> {
+    let existing = dir.ancestors().find(|dir| dir.is_dir())?;
+    fs4::available_space(existing).ok()
+}
+
+/// 置く先のファイルのディレクトリを作る
+fn create_dest_dir(dest: &Path) -> Result<()> {
+    if let Some(dest_dir) = dest.parent() {
+        fs::create_dir_all(dest_dir).with_context(|| {
+            format!(
+                "Failed to create destination directory: {}",
+                dest_dir.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// 既存のファイルと被らないよう，ファイル名の末尾に番号を付けたパスを返す
+pub fn numbered_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{}_{}{}", stem, n, ext));
+        if !candidate.exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// ゴミ箱に送った画像を元の場所に戻す
+#[cfg(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+fn restore_from_trash(src: &Path) -> Result<()> {
+    let parent = match src.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+    .canonicalize()
+    .with_context(|| format!("cannot resolve dir of {}", src.display()))?;
+    let file_name = src.file_name().context("Failed to get file name")?;
+
+    // 同名のファイルが複数ある場合は最後に削除したものを戻す
+    let item = trash::os_limited::list()
+        .context("cannot list trash")?
+        .into_iter()
+        .filter(|item| {
+            item.name == file_name
+                && item
+                    .original_parent
+                    .canonicalize()
+                    .is_ok_and(|p| p == parent)
+        })
+        .max_by_key(|item| item.time_deleted)
+        .with_context(|| format!("{} is not found in trash", src.display()))?;
+    trash::os_limited::restore_all([item])
+        .with_context(|| format!("Failed to restore image from trash: {}", src.display()))
+}
+
+/// ゴミ箱からの復元に対応していない環境
+#[cfg(not(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+fn restore_from_trash(src: &Path) -> Result<()> {
+    Err(anyhow!(
+        "restoring from trash is not supported on this platform: {}",
+        src.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_orientation_turns_and_keeps_mirroring() {
+        assert_eq!(rotated_orientation(1, true), 6);
+        assert_eq!(rotated_orientation(1, false), 8);
+        assert_eq!(rotated_orientation(8, true), 1);
+        assert_eq!(rotated_orientation(2, true), 7);
+        assert_eq!(rotated_orientation(5, true), 2);
+        // 不正な値は回転なしとして扱う
+        assert_eq!(rotated_orientation(0, true), 6);
+    }
+
+    #[test]
+    fn patch_orientation_rewrites_tag_in_both_byte_orders() {
+        // 8バイトのヘッダの直後に，向きのタグだけを持つIFDを置く
+        let mut little = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x01\0\0\0".to_vec();
+        assert!(patch_orientation(&mut little, 6));
+        assert_eq!(little[18..20], [6, 0]);
+
+        let mut big = b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x01\0\0".to_vec();
+        assert!(patch_orientation(&mut big, 8));
+        assert_eq!(big[18..20], [0, 8]);
+
+        // 向きのタグがなければ書き換えない
+        let mut other = b"II*\0\x08\0\0\0\x01\0\x0f\x01\x03\0\x01\0\0\0\x01\0\0\0".to_vec();
+        assert!(!patch_orientation(&mut other, 6));
+        assert!(!patch_orientation(&mut [], 6));
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::{backend::TestBackend, Terminal};
+use ratatui_image::picker::Picker;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    app::App,
+    config::Config,
+    input::{handle_event, InputState, Next},
+    ui::ui,
+    viewmodel::ViewModel,
+};
+
+// デコードを待つときに確かめる間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// 端末なしでメインループと同じようにイベントを送り，画面やファイルの状態を確かめる
+///
+/// 描画はratatuiのTestBackendに行うので，画面の内容は文字列として読める
+pub struct Harness {
+    pub app: App,
+    pub viewmodel: ViewModel,
+    input: InputState,
+    terminal: Terminal<TestBackend>,
+}
+
+impl Harness {
+    /// 指定した大きさの仮の画面で始める．端末には問い合わせない
+    pub fn new(config: Config, width: u16, height: u16) -> Result<Self> {
+        let mut app = App::with_picker(config, false, Picker::from_fontsize((8, 14)))?;
+        let viewmodel = ViewModel::new_from_app(&mut app)?;
+        let mut harness = Harness {
+            app,
+            viewmodel,
+            input: InputState::default(),
+            terminal: Terminal::new(TestBackend::new(width, height))?,
+        };
+        harness.draw()?;
+        Ok(harness)
+    }
+
+    /// メインループの1回分の更新と描画を行う
+    pub fn draw(&mut self) -> Result<()> {
+        self.viewmodel.tick(&mut self.app);
+        self.terminal.draw(|f| ui(f, &mut self.viewmodel))?;
+        Ok(())
+    }
+
+    /// イベントを処理してから描画する
+    pub fn send(&mut self, event: Event) -> Result<Next> {
+        let next = handle_event(event, &mut self.app, &mut self.viewmodel, &mut self.input);
+        self.draw()?;
+        Ok(next)
+    }
+
+    /// キーを押して離す
+    pub fn press(&mut self, code: KeyCode) -> Result<Next> {
+        self.press_with(code, KeyModifiers::NONE)
+    }
+
+    /// 修飾キーと一緒にキーを押して離す
+    pub fn press_with(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Result<Next> {
+        let next = self.send(Event::Key(KeyEvent::new(code, modifiers)))?;
+        let mut release = KeyEvent::new(code, modifiers);
+        release.kind = KeyEventKind::Release;
+        self.send(Event::Key(release))?;
+        Ok(next)
+    }
+
+    /// 次の画像のデコードが終わって表示されるまで待つ
+    pub fn wait_for_image(&mut self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        while self.viewmodel.is_loading() {
+            if started.elapsed() > timeout {
+                return Err(anyhow!("timed out waiting for image"));
+            }
+            thread::sleep(POLL_INTERVAL);
+            self.draw()?;
+        }
+        Ok(())
+    }
+
+    /// 最後に描画した画面の各行
+    pub fn screen(&self) -> Vec<String> {
+        let buffer = self.terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// 最後に描画した画面に文字列が含まれるか
+    pub fn screen_contains(&self, text: &str) -> bool {
+        self.screen().iter().any(|line| line.contains(text))
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use image::DynamicImage;
+
+use std::{fs::File, io::Read, path::Path};
+
+/// HEIC/HEIFの画像の拡張子
+pub const EXTENSIONS: [&str; 3] = ["heic", "heif", "hif"];
+
+// ftypボックスに書かれるHEIFのブランド
+const BRANDS: [&[u8; 4]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"mif1", b"msf1"];
+
+/// HEIC/HEIFの画像をデコードする．向きなどの変換はlibheifが反映する
+#[cfg(feature = "heif")]
+pub fn decode(path: &Path) -> Result<DynamicImage> {
+    use anyhow::Context;
+    use image::RgbaImage;
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let error = || format!("cannot decode image {}", path.display());
+    let context = HeifContext::read_from_file(&path.to_string_lossy()).with_context(error)?;
+    let handle = context.primary_image_handle().with_context(error)?;
+    let img = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .with_context(error)?;
+    let plane = img.planes().interleaved.with_context(error)?;
+    // 行の末尾には詰め物がありうる
+    let row_len = plane.width as usize * 4;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    let buffer = RgbaImage::from_raw(plane.width, plane.height, pixels).with_context(error)?;
+    Ok(DynamicImage::ImageRgba8(buffer))
+}
+
+/// heif機能なしでビルドした場合はデコードできない
+#[cfg(not(feature = "heif"))]
+pub fn decode(path: &Path) -> Result<DynamicImage> {
+    anyhow::bail!(
+        "shotclassif was built without the heif feature: {}",
+        path.display()
+    )
+}
+
+/// heif機能を有効にしてビルドしたか
+pub fn is_available() -> bool {
+    cfg!(feature = "heif")
+}
+
+/// ファイルの先頭のftypボックスからHEIFの画像か判定する
+pub fn sniff(path: &Path) -> bool {
+    let mut head = [0; 12];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut head));
+    read.is_ok() && &head[4..8] == b"ftyp" && BRANDS.iter().any(|brand| head[8..12] == **brand)
+}
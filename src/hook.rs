@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Context, Result};
+
+use std::{
+    io::Read,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// 標準出力を読み取るコマンドを待つ最長の時間．過ぎたら打ち切る
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// コマンドのプレースホルダを置き換えて，終了を待たずに実行する
+///
+/// シェルは経由しないので，パイプなどを使う場合は`sh -c "..."`のように指定する
+pub fn run(template: &str, vars: &[(&str, &Path)]) -> Result<()> {
+    // 出力するとTUIの表示が崩れるので捨てる
+    let mut child = command(template, vars)?
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("cannot run hook: {}", template))?;
+    // 終了したプロセスが残らないよう別スレッドで待つ
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// 画像を外部のビューアで開き，閉じるまで待つ．指定がなければOS既定のアプリで開く
+///
+/// ビューアのコマンドの"{path}"は画像のパスに置き換え，なければ末尾にパスを付け足す
+pub fn open_viewer(path: &Path, viewer: Option<&str>) -> Result<()> {
+    let mut command = match viewer {
+        Some(viewer) => path_command(viewer, path)?,
+        None => default_opener(path),
+    };
+    let status = command
+        .status()
+        .with_context(|| format!("cannot open viewer for {}", path.display()))?;
+    if !status.success() {
+        return Err(anyhow!("viewer exited with {}", status));
+    }
+    Ok(())
+}
+
+/// 画像を渡してコマンドを実行し，終了を待って標準出力を返す
+///
+/// "{path}"は画像のパスに置き換え，なければ末尾にパスを付け足す．終わらなければ打ち切ってエラーにする
+pub fn capture(template: &str, path: &Path) -> Result<String> {
+    let mut child = path_command(template, path)?
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("cannot run command: {}", template))?;
+    // 出力がパイプに溜まって止まらないよう，待つ間も別スレッドで読み続ける
+    let mut stdout = child.stdout.take().context("cannot read stdout")?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + CAPTURE_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "{} timed out after {}s",
+                template,
+                CAPTURE_TIMEOUT.as_secs()
+            ));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}", template, status));
+    }
+    let output = reader
+        .join()
+        .map_err(|_| anyhow!("cannot read output of {}", template))??;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// "{path}"を画像のパスに置き換えたコマンド．なければ末尾にパスを付け足す
+fn path_command(template: &str, path: &Path) -> Result<Command> {
+    if template.contains("{path}") {
+        return command(template, &[("path", path)]);
+    }
+    let mut command = command(template, &[])?;
+    command.arg(path);
+    Ok(command)
+}
+
+/// OS既定のアプリで開くコマンド
+fn default_opener(path: &Path) -> Command {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+    command.arg(path);
+    command
+}
+
+/// プレースホルダを置き換えたコマンドを作る
+fn command(template: &str, vars: &[(&str, &Path)]) -> Result<Command> {
+    let mut args = split_command(template)?.into_iter().map(|arg| {
+        vars.iter().fold(arg, |arg, (name, value)| {
+            arg.replace(&format!("{{{}}}", name), &value.to_string_lossy())
+        })
+    });
+    let program = args.next().context("command is empty")?;
+    let mut command = Command::new(program);
+    command.args(args);
+    Ok(command)
+}
+
+/// コマンドを空白で引数に分ける．引用符で囲んだ部分は1つの引数にする
+fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(anyhow!("unclosed quote in hook: {}", command));
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_command_keeps_quoted_args_together() {
+        assert_eq!(
+            split_command("tesseract {path} - -l jpn+eng").unwrap(),
+            ["tesseract", "{path}", "-", "-l", "jpn+eng"]
+        );
+        assert_eq!(
+            split_command(r#"sh -c "echo 'a b'"  ''"#).unwrap(),
+            ["sh", "-c", "echo 'a b'", ""]
+        );
+        assert!(split_command("  ").unwrap().is_empty());
+        assert!(split_command("echo \"a").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use image::DynamicImage;
+use moxcms::{ColorProfile, Layout, TransformOptions};
+
+/// 埋め込まれたICCプロファイルの色をsRGBに変換する
+///
+/// 端末はsRGBとして表示するので，広色域の写真が色あせたり鮮やかすぎたりしないようにする．
+/// 読めないプロファイルやRGBでない画素はそのままにする
+pub fn to_srgb(img: &mut DynamicImage, icc: &[u8]) {
+    let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+        return;
+    };
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    match img {
+        DynamicImage::ImageRgb8(buffer) => {
+            if let Ok(transform) =
+                profile.create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, options)
+            {
+                let src = buffer.to_vec();
+                let _ = transform.transform(&src, buffer);
+            }
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            if let Ok(transform) =
+                profile.create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgba, options)
+            {
+                let src = buffer.to_vec();
+                let _ = transform.transform(&src, buffer);
+            }
+        }
+        DynamicImage::ImageRgb16(buffer) => {
+            if let Ok(transform) =
+                profile.create_transform_16bit(Layout::Rgb, &srgb, Layout::Rgb, options)
+            {
+                let src = buffer.to_vec();
+                let _ = transform.transform(&src, buffer);
+            }
+        }
+        DynamicImage::ImageRgba16(buffer) => {
+            if let Ok(transform) =
+                profile.create_transform_16bit(Layout::Rgba, &srgb, Layout::Rgba, options)
+            {
+                let src = buffer.to_vec();
+                let _ = transform.transform(&src, buffer);
+            }
+        }
+        _ => {}
+    }
+}
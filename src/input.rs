@@ -0,0 +1,394 @@
+use anyhow::Result;
+use crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEventKind,
+};
+
+use std::collections::HashSet;
+
+use crate::{
+    app::{App, CollisionPolicy},
+    config::KeyBind,
+    viewmodel::{Prompt, RetryChoice, ViewModel},
+};
+
+// 1回の操作で拡大・縮小する倍率
+const ZOOM_STEP: f64 = 1.5;
+// 1回の操作で表示位置を動かす量 (表示範囲に対する割合)
+const PAN_STEP: f64 = 0.1;
+// 1回の操作で操作の履歴を遡る件数
+const HISTORY_PAGE: usize = 5;
+
+/// イベントを処理した後にメインループで行うこと
+pub enum Next {
+    Continue,
+    Quit,
+    // 表示中の画像を外部のビューアで開く
+    OpenViewer,
+    // Ctrl+Zでシェルに戻る
+    Suspend,
+}
+
+/// イベントをまたいで保持する入力の状態
+#[derive(Default)]
+pub struct InputState {
+    // 押しっぱなしによる連続入力を防ぐため押下中のキーを覚えておく
+    pub pressed_keys: HashSet<KeyCode>,
+    // ドラッグ中の直前のマウス位置
+    drag_from: Option<(u16, u16)>,
+}
+
+/// イベントを処理し，メインループで次に行うことを返す
+pub fn handle_event(
+    event: Event,
+    app: &mut App,
+    viewmodel: &mut ViewModel,
+    input: &mut InputState,
+) -> Next {
+    match event {
+        Event::Key(key) if key.kind == KeyEventKind::Press => {
+            return handle_key(key, app, viewmodel, input)
+        }
+        // 押しっぱなしの入力は連続入力モードでのみ受け付ける
+        Event::Key(key) if key.kind == KeyEventKind::Repeat && viewmodel.sticky => {
+            return handle_key(key, app, viewmodel, input)
+        }
+        Event::Key(key) if key.kind == KeyEventKind::Release => {
+            input.pressed_keys.remove(&key.code);
+        }
+        Event::Resize(_, _) => {
+            let _ = viewmodel.on_resize(app);
+        }
+        Event::Mouse(mouse) => match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                input.drag_from = Some((mouse.column, mouse.row));
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                if let Some((column, row)) = input.drag_from {
+                    let dx = i32::from(mouse.column) - i32::from(column);
+                    let dy = i32::from(mouse.row) - i32::from(row);
+                    let _ = viewmodel.on_drag(app, dx, dy);
+                }
+                input.drag_from = Some((mouse.column, mouse.row));
+            }
+            MouseEventKind::Up(MouseButton::Left) => input.drag_from = None,
+            MouseEventKind::ScrollUp => {
+                let _ = viewmodel.on_zoom(app, ZOOM_STEP);
+            }
+            MouseEventKind::ScrollDown => {
+                let _ = viewmodel.on_zoom(app, 1.0 / ZOOM_STEP);
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+    Next::Continue
+}
+
+/// キー入力を処理し，メインループで次に行うことを返す
+fn handle_key(
+    key: KeyEvent,
+    app: &mut App,
+    viewmodel: &mut ViewModel,
+    input: &mut InputState,
+) -> Next {
+    // 端末を生のモードにしているのでCtrl+Zはキーとして届く．ジョブ制御のあるOSでのみ扱う
+    if cfg!(unix) && key.code == KeyCode::Char('z') && key.modifiers.contains(KeyModifiers::CONTROL)
+    {
+        return Next::Suspend;
+    }
+
+    if viewmodel.show_help {
+        // ヘルプ表示中は閉じる操作のみ受け付ける
+        if matches!(key.code, KeyCode::Char('?') | KeyCode::Esc) {
+            viewmodel.show_help = false;
+        }
+        return Next::Continue;
+    }
+
+    if let Some((_, input)) = &mut viewmodel.prompt {
+        // テキストの入力中
+        match key.code {
+            KeyCode::Char(c) => {
+                input.push(c);
+                viewmodel.picker_cursor = 0;
+            }
+            KeyCode::Backspace => {
+                input.pop();
+                viewmodel.picker_cursor = 0;
+            }
+            KeyCode::Up => viewmodel.move_picker(false),
+            KeyCode::Down => viewmodel.move_picker(true),
+            KeyCode::Enter => {
+                let result = viewmodel.on_prompt(app);
+                report(result, app, viewmodel);
+            }
+            KeyCode::Esc => viewmodel.prompt = None,
+            _ => {}
+        }
+        return Next::Continue;
+    }
+
+    if viewmodel.quit_confirm.is_some() {
+        // 未決定の画像を残して終了する前の確認中
+        match key.code {
+            KeyCode::Char('y') | KeyCode::Char('q') => return quit(app, viewmodel),
+            KeyCode::Char('w') => match app.write_remaining() {
+                Ok(_) => return Next::Quit,
+                Err(e) => {
+                    viewmodel.quit_confirm = None;
+                    viewmodel.on_error(app, e);
+                }
+            },
+            KeyCode::Char('n') | KeyCode::Esc => viewmodel.quit_confirm = None,
+            _ => {}
+        }
+        return Next::Continue;
+    }
+
+    if viewmodel.peek.is_some() {
+        // 分類先を覗いている間はどのキーでも閉じる
+        viewmodel.peek = None;
+        return Next::Continue;
+    }
+
+    if viewmodel.peek_pending {
+        // 覗く分類先のキーを待っている
+        viewmodel.peek_pending = false;
+        let key = KeyBind::from(key);
+        if app.has_dest(key) {
+            let result = viewmodel.on_peek(app, key);
+            report(result, app, viewmodel);
+        }
+        return Next::Continue;
+    }
+
+    if viewmodel.retry.is_some() {
+        // ファイルの操作に失敗してやり直すかを尋ねている
+        let choice = match key.code {
+            KeyCode::Char('r') => RetryChoice::Retry,
+            KeyCode::Char('s') => RetryChoice::Skip,
+            KeyCode::Char('a') | KeyCode::Esc => RetryChoice::Abort,
+            _ => return Next::Continue,
+        };
+        let result = viewmodel.on_retry(app, choice);
+        report(result, app, viewmodel);
+        return Next::Continue;
+    }
+
+    if viewmodel.confirm.is_some() {
+        // 分類先に送る前の確認中
+        let result = match key.code {
+            KeyCode::Char('y') => viewmodel.on_confirm(app, true),
+            KeyCode::Char('n') | KeyCode::Esc => viewmodel.on_confirm(app, false),
+            _ => Ok(()),
+        };
+        report(result, app, viewmodel);
+        return Next::Continue;
+    }
+
+    if viewmodel.compare.is_some() {
+        // 比較表示中
+        let result = match key.code {
+            KeyCode::Char('q') => return quit(app, viewmodel),
+            KeyCode::Tab | KeyCode::Esc => {
+                viewmodel.close_compare();
+                Ok(())
+            }
+            KeyCode::Left => viewmodel.on_compare_keep(app, true),
+            KeyCode::Right => viewmodel.on_compare_keep(app, false),
+            _ => Ok(()),
+        };
+        report(result, app, viewmodel);
+        return Next::Continue;
+    }
+
+    if viewmodel.gallery && viewmodel.conflict.is_none() {
+        // ギャラリー表示中
+        let result = match key.code {
+            KeyCode::Char('q') => return quit(app, viewmodel),
+            KeyCode::Char('g') | KeyCode::Esc => {
+                viewmodel.toggle_gallery();
+                Ok(())
+            }
+            KeyCode::Char(' ') => {
+                viewmodel.on_gallery_mark();
+                Ok(())
+            }
+            KeyCode::Tab => viewmodel.open_compare_marked(app),
+            KeyCode::Left => {
+                viewmodel.on_gallery_move(false);
+                Ok(())
+            }
+            KeyCode::Right => {
+                viewmodel.on_gallery_move(true);
+                Ok(())
+            }
+            _ if !input.pressed_keys.contains(&key.code) => {
+                let result = viewmodel.on_gallery_key(app, KeyBind::from(key));
+                if result.is_ok() {
+                    input.pressed_keys.insert(key.code);
+                }
+                result
+            }
+            _ => Ok(()),
+        };
+        report(result, app, viewmodel);
+        return Next::Continue;
+    }
+
+    if let Some((_, duplicate)) = viewmodel.conflict {
+        // 移動先のファイルとの衝突の確認中
+        let result = match key.code {
+            KeyCode::Char('r') => viewmodel.on_conflict(app, CollisionPolicy::RenameWithSuffix),
+            // 中身が同じ場合は上書きではなく移動元を消す
+            KeyCode::Char('d') if duplicate => viewmodel.on_conflict_delete(app),
+            KeyCode::Char('o') if !duplicate => {
+                viewmodel.on_conflict(app, CollisionPolicy::Overwrite)
+            }
+            KeyCode::Char('s') => viewmodel.on_conflict(app, CollisionPolicy::Skip),
+            KeyCode::Esc => {
+                viewmodel.on_conflict_cancel(app);
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        report(result, app, viewmodel);
+        return Next::Continue;
+    }
+
+    // 分類先に割り当てていない数字は，次の分類先をまとめて適用する枚数にする
+    if let Some(digit) = key.code.as_char().and_then(|c| c.to_digit(10)) {
+        if viewmodel.push_count(app, KeyBind::from(key), digit) {
+            return Next::Continue;
+        }
+    }
+
+    // 失敗しても終了はしない
+    let result = match key.code {
+        KeyCode::Char('q') => return quit(app, viewmodel),
+        KeyCode::Esc if viewmodel.count.is_some() => {
+            viewmodel.count = None;
+            Ok(())
+        }
+        KeyCode::Char('?') => {
+            viewmodel.show_help = true;
+            Ok(())
+        }
+        KeyCode::Char('u') => viewmodel.on_undo(app),
+        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            viewmodel.on_redo(app)
+        }
+        KeyCode::Char('g') if !viewmodel.is_fin => {
+            viewmodel.toggle_gallery();
+            Ok(())
+        }
+        KeyCode::Char('f') => viewmodel.on_flag(app),
+        KeyCode::Char('k') if viewmodel.similar_num > 0 => viewmodel.on_similar(app),
+        KeyCode::Tab => viewmodel.open_compare(app),
+        KeyCode::Char('T') if app.has_ocr() => {
+            viewmodel.toggle_text(app);
+            Ok(())
+        }
+        KeyCode::Char('Z') => {
+            viewmodel.zen = !viewmodel.zen;
+            Ok(())
+        }
+        KeyCode::Char('B') => viewmodel.toggle_low_bandwidth(app),
+        KeyCode::Char('R') => {
+            viewmodel.toggle_sticky();
+            Ok(())
+        }
+        KeyCode::Char('o') if !viewmodel.is_fin && !viewmodel.is_loading() => {
+            return Next::OpenViewer
+        }
+        KeyCode::Char('n') => {
+            viewmodel.open_prompt(Prompt::NewDest);
+            Ok(())
+        }
+        KeyCode::Char('c') if !viewmodel.is_fin && !viewmodel.is_loading() => {
+            viewmodel.open_prompt(Prompt::Caption);
+            Ok(())
+        }
+        KeyCode::Char(' ') => {
+            viewmodel.toggle_pause();
+            Ok(())
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => viewmodel.on_zoom(app, ZOOM_STEP),
+        KeyCode::Char('-') => viewmodel.on_zoom(app, 1.0 / ZOOM_STEP),
+        KeyCode::Char('0') => viewmodel.reset_zoom(app),
+        KeyCode::Char('[') => viewmodel.on_rotate(app, false),
+        KeyCode::Char(']') => viewmodel.on_rotate(app, true),
+        KeyCode::Char(',') => viewmodel.on_page(app, false),
+        KeyCode::Char('.') => viewmodel.on_page(app, true),
+        KeyCode::Char('{') => viewmodel.on_rotate_file(app, false),
+        KeyCode::Char('}') => viewmodel.on_rotate_file(app, true),
+        KeyCode::Enter if viewmodel.is_fin => viewmodel.on_commit(app),
+        // Shiftと一緒なら1件ずつ動かして取り消す項目を選ぶ
+        KeyCode::PageUp | KeyCode::PageDown => {
+            let lines = if key.modifiers.contains(KeyModifiers::SHIFT) {
+                1
+            } else {
+                HISTORY_PAGE
+            };
+            viewmodel.scroll_history(key.code == KeyCode::PageUp, lines);
+            Ok(())
+        }
+        KeyCode::Char('U') => viewmodel.on_undo_selected(app),
+        KeyCode::Char('P') => {
+            viewmodel.peek_pending = true;
+            Ok(())
+        }
+        KeyCode::Char('S') => {
+            viewmodel.toggle_shuffle(app);
+            Ok(())
+        }
+        KeyCode::Char('F') => {
+            viewmodel.open_prompt(Prompt::Filter);
+            Ok(())
+        }
+        KeyCode::Char(c @ (':' | '/')) => {
+            viewmodel.open_goto(c);
+            Ok(())
+        }
+        KeyCode::Enter if !viewmodel.is_loading() => {
+            viewmodel.open_prompt(Prompt::PickDest);
+            Ok(())
+        }
+        // 拡大中は矢印キーで表示位置を動かす
+        KeyCode::Left if viewmodel.is_zoomed() => viewmodel.on_pan(app, -PAN_STEP, 0.0),
+        KeyCode::Right if viewmodel.is_zoomed() => viewmodel.on_pan(app, PAN_STEP, 0.0),
+        KeyCode::Up if viewmodel.is_zoomed() => viewmodel.on_pan(app, 0.0, -PAN_STEP),
+        KeyCode::Down if viewmodel.is_zoomed() => viewmodel.on_pan(app, 0.0, PAN_STEP),
+        KeyCode::Left => viewmodel.on_prev(app),
+        KeyCode::Right => viewmodel.on_next(app),
+        // 連続入力モードでは押しっぱなしでも同じ分類先に送り続ける
+        _ if !input.pressed_keys.contains(&key.code)
+            || viewmodel.is_sticky_repeat(KeyBind::from(key)) =>
+        {
+            let result = viewmodel.on_key(app, KeyBind::from(key));
+            if result.is_ok() {
+                input.pressed_keys.insert(key.code);
+            }
+            result
+        }
+        _ => Ok(()),
+    };
+    report(result, app, viewmodel);
+    Next::Continue
+}
+
+/// 未決定の画像が残っていれば確認画面を開き，残っていなければ終了する
+fn quit(app: &App, viewmodel: &mut ViewModel) -> Next {
+    if viewmodel.request_quit(app) {
+        Next::Quit
+    } else {
+        Next::Continue
+    }
+}
+
+/// 失敗しても終了はせず，操作の履歴に残す
+fn report(result: Result<()>, app: &mut App, viewmodel: &mut ViewModel) {
+    if let Err(e) = result {
+        viewmodel.on_error(app, e);
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::session::SessionEntry;
+
+/// 操作を1行ずつJSONで追記する監査用のファイル
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+/// 読み込んだジャーナルの1行
+#[derive(Deserialize)]
+struct RecordedLine {
+    #[serde(default)]
+    undo: bool,
+    #[serde(flatten)]
+    entry: SessionEntry,
+}
+
+/// ジャーナルの1行
+#[derive(Serialize)]
+struct JournalLine<'a> {
+    // UNIX時間 (秒)
+    time: u64,
+    // 取り消しの記録か
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    undo: bool,
+    #[serde(flatten)]
+    entry: &'a SessionEntry,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open journal file: {}", path.display()))?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// 決定を記録する
+    pub fn write(&mut self, entry: &SessionEntry) -> Result<()> {
+        self.write_line(entry, false)
+    }
+
+    /// 決定の取り消しを記録する
+    pub fn write_undo(&mut self, entry: &SessionEntry) -> Result<()> {
+        self.write_line(entry, true)
+    }
+
+    /// 記録された決定を読み込む．取り消された決定は除く
+    pub fn read(path: &Path) -> Result<Vec<SessionEntry>> {
+        let journal_str = fs::read_to_string(path)
+            .with_context(|| format!("cannot read journal file: {}", path.display()))?;
+        let mut entries: Vec<SessionEntry> = Vec::new();
+        for line in journal_str.lines().filter(|line| !line.trim().is_empty()) {
+            let line: RecordedLine = serde_json::from_str(line)
+                .with_context(|| format!("journal file is not valid json: {}", path.display()))?;
+            if line.undo {
+                // 最後に記録された同じ画像の決定 (またはコピー) を取り消す
+                if let Some(pos) = entries.iter().rposition(|entry| {
+                    entry.src() == line.entry.src() && entry.is_copy() == line.entry.is_copy()
+                }) {
+                    entries.remove(pos);
+                }
+            } else {
+                entries.push(line.entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_line(&mut self, entry: &SessionEntry, undo: bool) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut line = serde_json::to_string(&JournalLine { time, undo, entry })
+            .context("cannot serialize journal line")?;
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .with_context(|| format!("cannot write journal file: {}", self.path.display()))
+    }
+}
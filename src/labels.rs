@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// タグ付けモードで画像ごとに付けたラベル
+pub struct Labels {
+    path: PathBuf,
+    dir: PathBuf,
+    // 分類元ディレクトリからの相対パスごとのラベル
+    labels: BTreeMap<PathBuf, BTreeSet<String>>,
+}
+
+impl Labels {
+    /// ラベルのファイルを読み込む．存在しない場合は空にする
+    pub fn load(path: &Path, dir: &Path) -> Result<Self> {
+        let labels = if path.exists() {
+            let labels_str = fs::read_to_string(path)
+                .with_context(|| format!("cannot read labels file: {}", path.display()))?;
+            serde_json::from_str(&labels_str)
+                .with_context(|| format!("labels file is not valid json: {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Labels {
+            path: path.to_path_buf(),
+            dir: dir.to_path_buf(),
+            labels,
+        })
+    }
+
+    /// 画像に付けたラベル
+    pub fn get(&self, src: &Path) -> Vec<String> {
+        self.labels
+            .get(self.relative(src))
+            .map(|labels| labels.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// ラベルを付け外しして保存する．付けた場合はtrueを返す
+    pub fn toggle(&mut self, src: &Path, label: &str) -> Result<bool> {
+        let file = self.relative(src).to_path_buf();
+        let labels = self.labels.entry(file.clone()).or_default();
+        let added = labels.insert(label.to_string());
+        if !added {
+            labels.remove(label);
+            if labels.is_empty() {
+                self.labels.remove(&file);
+            }
+        }
+        self.save()?;
+        Ok(added)
+    }
+
+    /// ラベルを付けて保存する
+    pub fn add(&mut self, src: &Path, label: &str) -> Result<()> {
+        let file = self.relative(src).to_path_buf();
+        if self
+            .labels
+            .entry(file)
+            .or_default()
+            .insert(label.to_string())
+        {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// 画像とラベルの組
+    pub fn rows(&self) -> Vec<(PathBuf, String)> {
+        self.labels
+            .iter()
+            .flat_map(|(file, labels)| {
+                labels
+                    .iter()
+                    .map(move |label| (self.dir.join(file), label.clone()))
+            })
+            .collect()
+    }
+
+    /// ラベルを付けた画像の数
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// ラベルごとの画像の数
+    pub fn counts(&self) -> HashMap<PathBuf, usize> {
+        let mut counts = HashMap::new();
+        for label in self.labels.values().flatten() {
+            *counts.entry(PathBuf::from(label)).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    fn relative<'a>(&self, src: &'a Path) -> &'a Path {
+        src.strip_prefix(&self.dir).unwrap_or(src)
+    }
+
+    fn save(&self) -> Result<()> {
+        let labels_str =
+            serde_json::to_string_pretty(&self.labels).context("cannot serialize labels")?;
+        fs::write(&self.path, labels_str)
+            .with_context(|| format!("cannot write labels file: {}", self.path.display()))
+    }
+}
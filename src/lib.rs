@@ -0,0 +1,43 @@
+//! スクリーンショットなどの画像をキーを押して分類先のディレクトリに振り分ける
+//!
+//! バイナリはこのクレートを使うTUIのフロントエンドで，他のツールに組み込んだり端末なしでテストしたりするときは直接使える
+//!
+//! - [`app::App`]は画像の一覧と分類の状態を持ち，ワーカーでのデコードと移動を行う
+//! - [`viewmodel::ViewModel`]は`App`の状態を表示用にまとめ，キー操作を`App`に伝える
+//! - [`ui::ui`]は`ViewModel`を描画する
+//! - [`config::Config`]は設定ファイルの内容
+//!
+//! 端末に問い合わせずに始めるには[`app::App::with_picker`]を使う．
+//! [`harness::Harness`]は端末の代わりにTestBackendに描画し，キーを送って分類の流れを確かめられる
+
+pub mod app;
+pub mod cache;
+pub mod colors;
+pub mod config;
+pub mod decode;
+pub mod done;
+pub mod fileops;
+pub mod harness;
+pub mod heif;
+pub mod hook;
+pub mod icc;
+pub mod input;
+pub mod journal;
+pub mod labels;
+pub mod lock;
+pub mod manifest;
+pub mod model;
+pub mod pdf;
+pub mod raw;
+pub mod rules;
+pub mod session;
+pub mod similar;
+pub mod suggest;
+pub mod summary;
+pub mod svg;
+pub mod template;
+pub mod theme;
+pub mod ui;
+pub mod video;
+pub mod viewmodel;
+pub mod watch;
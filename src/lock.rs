@@ -0,0 +1,54 @@
+use anyhow::{bail, Context, Result};
+use fs4::fs_std::FileExt;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
+
+/// 分類元ディレクトリに置くロックファイルの名前
+const LOCK_FILE_NAME: &str = ".shotclassif.lock";
+
+/// 同じディレクトリを複数のセッションで同時に分類しないためのロック．破棄すると解除する
+///
+/// OSのアドバイザリロックを使うので，異常終了してもプロセスがなくなればロックは外れる
+pub struct SessionLock {
+    _file: File,
+}
+
+impl SessionLock {
+    /// ロックを取る．他のセッションがロックしていればエラーにし，forceならロックせずに始める
+    pub fn acquire(dir: &Path, force: bool) -> Result<Self> {
+        let path = dir.join(LOCK_FILE_NAME);
+        // ロックを取るまでは他のセッションが書いたpidを消さないよう，切り詰めずに開く
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("cannot create lock file: {}", path.display()))?;
+        let locked = file
+            .try_lock_exclusive()
+            .with_context(|| format!("cannot lock {}", path.display()))?;
+        if !locked {
+            if force {
+                return Ok(SessionLock { _file: file });
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            bail!(
+                "another session (pid {}) is sorting {}; pass --force to start anyway",
+                pid.trim(),
+                dir.display()
+            );
+        }
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{}", process::id()))
+            .with_context(|| format!("cannot write lock file: {}", path.display()))?;
+        Ok(SessionLock { _file: file })
+    }
+}
@@ -1,95 +1,407 @@
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    cursor,
+    event::{self, DisableMouseCapture, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
-use serde::Deserialize;
 use std::{
-    collections::{HashMap, HashSet},
-    fs,
-    io::{self},
-    path::PathBuf,
+    io::{self, Stdout, Write},
+    panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
     time::Duration,
 };
 
-use crate::viewmodel::ViewModel;
-use crate::{app::App, ui::ui};
-
-pub mod app;
-pub mod ui;
-pub mod viewmodel;
+use shotclassif::{
+    app::{App, Protocol},
+    config::{self, parse_dest, Config, KeyBind},
+    hook,
+    input::{handle_event, InputState, Next},
+    journal::Journal,
+    lock::SessionLock,
+    ui::ui,
+    viewmodel::ViewModel,
+};
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Cli {
-    #[arg(help = "path to config.toml", value_name = "FILE")]
-    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    // サブコマンドを省略した場合はsortとして扱う
+    #[command(flatten)]
+    sort: SortArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "sort images interactively in the TUI (default)")]
+    Sort(SortArgs),
+    #[command(about = "print the counts of the session in dir")]
+    Stats {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    #[command(about = "check config for mistakes without sorting")]
+    Validate {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    #[command(about = "undo the last decisions of the session in dir")]
+    Undo {
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[arg(
+            short = 'n',
+            long = "num",
+            help = "number of decisions to undo",
+            value_name = "N",
+            default_value_t = 1
+        )]
+        num: usize,
+        #[arg(long, help = "undo all decisions", conflicts_with = "num")]
+        all: bool,
+    },
+    #[command(about = "sort images by the rules in config without the TUI")]
+    Apply {
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[arg(
+            long,
+            help = "replay the decisions recorded in a journal FILE instead of the rules",
+            value_name = "FILE"
+        )]
+        decisions: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct SortArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[arg(long, help = "resume the previous session in dir")]
+    resume: bool,
+    #[arg(long, help = "start even if another session seems to be sorting dir")]
+    force: bool,
+    #[arg(long, help = "keep running and add images newly created in dir")]
+    watch: bool,
+    #[arg(
+        long,
+        help = "number of decoded images to buffer ahead (overrides config)",
+        value_name = "N"
+    )]
+    prefetch: Option<usize>,
+    #[arg(
+        long,
+        help = "number of decode worker threads (overrides config)",
+        value_name = "N"
+    )]
+    workers: Option<usize>,
+    #[arg(
+        long,
+        help = "graphics protocol to draw images with instead of querying the terminal (overrides config)"
+    )]
+    protocol: Option<Protocol>,
+    #[arg(
+        long,
+        help = "seed for random order and shuffle, to reproduce the same order (overrides config)",
+        value_name = "N"
+    )]
+    seed: Option<u64>,
 }
 
-/// TOML file structure
-#[derive(Deserialize, Debug)]
-pub struct Config {
-    dir: PathBuf,
-    dests: HashMap<char, PathBuf>,
+// 設定ファイルとそれを上書きする引数
+#[derive(Args)]
+struct ConfigArgs {
+    #[arg(help = "path to config.toml", value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "sort DIR into its subdirectories with keys assigned automatically",
+        value_name = "DIR",
+        conflicts_with_all = ["config", "dir"]
+    )]
+    auto: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "directory or glob pattern to sort (overrides config, repeatable)",
+        value_name = "DIR"
+    )]
+    dir: Vec<PathBuf>,
+    #[arg(
+        long,
+        help = "read image paths line by line from FILE instead of dir (- for stdin)",
+        value_name = "FILE"
+    )]
+    files: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "write path,label rows as CSV to FILE",
+        value_name = "FILE"
+    )]
+    manifest: Option<PathBuf>,
+    #[arg(
+        long = "dest",
+        help = "bind KEY to DIR (overrides config, repeatable)",
+        value_name = "KEY=DIR",
+        value_parser = parse_dest
+    )]
+    dests: Vec<(KeyBind, PathBuf)>,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Sort(cli.sort)) {
+        Command::Sort(args) => sort(args),
+        Command::Stats { config } => stats(load_config(config)?),
+        Command::Validate { config } => validate(load_config(config)?),
+        Command::Undo { config, num, all } => {
+            undo(load_config(config)?, if all { usize::MAX } else { num })
+        }
+        Command::Apply { config, decisions } => apply(load_config(config)?, decisions),
+    }
+}
 
-    // 設定ファイルの読み込み
-    let config_str = fs::read_to_string(cli.config.unwrap_or("config.toml".into()))
-        .context("config.toml not found or unreadable")?;
-    let config: Config = toml::from_str(&config_str).context("config.toml is not valid toml")?;
+/// TUIで画像を分類する
+fn sort(args: SortArgs) -> Result<()> {
+    let mut config = load_config(args.config)?;
+    config.watch |= args.watch;
+    config.prefetch = args.prefetch.or(config.prefetch);
+    config.workers = args.workers.or(config.workers);
+    config.protocol = args.protocol.or(config.protocol);
+    config.seed = args.seed.or(config.seed);
+    if config.dests.is_empty() {
+        return Err(anyhow!(
+            "no dests are set; write them in config.toml or pass --dest"
+        ));
+    }
+    // 操作キーが分類先に隠れたまま気づかずに始めないよう確認する
+    let conflicts = config.key_conflicts();
+    if !conflicts.is_empty() {
+        for conflict in &conflicts {
+            eprintln!("{}", conflict);
+        }
+        if !confirm("Continue anyway? [y/N] ")? {
+            return Err(anyhow!("aborted because of keybind conflicts"));
+        }
+    }
+    // 他のセッションと同じ画像を取り合わないよう，終了するまでロックしておく
+    let _locks = lock_dirs(&config, args.force)?;
 
-    // ターミナル設定
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    // 終了のシグナルを受けたら通常どおり後片付けをしてから終了する
+    let terminated = Arc::new(AtomicBool::new(false));
+    for &signal in signal_hook::consts::TERM_SIGNALS {
+        signal_hook::flag::register(signal, terminated.clone())?;
+    }
+    install_panic_hook();
 
-    let app = &mut App::new(config)?;
+    // ターミナル設定．以降はエラーで抜けても落とすときに元に戻る
+    let mut terminal = TerminalGuard::new()?;
+
+    let app = &mut App::new(config, args.resume)?;
+    let result = run_loop(&mut terminal.0, app, &terminated);
+
+    // 終了処理
+    drop(terminal);
+    // 既定のパニック時の表示に戻す
+    drop(panic::take_hook());
+    // 画面を戻してからデコードできなかった画像を知らせる
+    for (src, error) in app.decode_errors() {
+        eprintln!("cannot decode {}: {}", src.display(), error);
+    }
+
+    // 途中で終了した場合やエラーで抜けた場合もそこまでの分類を書き出す
+    let finished = app
+        .write_manifest()
+        .and_then(|()| app.write_remaining_on_quit())
+        .and_then(|()| app.run_end_hook());
+    result.and(finished)
+}
+
+/// 終了するまでキー操作を受けて描画する
+fn run_loop(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    app: &mut App,
+    terminated: &AtomicBool,
+) -> Result<()> {
     let viewmodel = &mut ViewModel::new_from_app(app)?;
-    let mut pressed_keys: HashSet<KeyCode> = HashSet::new();
+    let mut input = InputState::default();
     // メインループ
-    loop {
+    while !terminated.load(Ordering::Relaxed) {
         // 描画
+        viewmodel.tick(app);
         terminal.draw(|f| ui(f, viewmodel))?;
 
         // イベントのポーリング
-        if event::poll(Duration::from_millis(10))? {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    match key.code {
-                        KeyCode::Char('q') => break,
-                        KeyCode::Char(c) => {
-                            if !pressed_keys.contains(&key.code) {
-                                let Ok(_) = viewmodel.on_key(app, c) else {
-                                    continue;
-                                };
-                                pressed_keys.insert(key.code);
-                            }
-                        }
-                        _ => {}
-                    }
-                } else if key.kind == KeyEventKind::Release {
-                    match key.code {
-                        KeyCode::Char(_) => {
-                            if pressed_keys.contains(&key.code) {
-                                pressed_keys.remove(&key.code);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+        if !event::poll(Duration::from_millis(10))? {
+            continue;
+        }
+        match handle_event(event::read()?, app, viewmodel, &mut input) {
+            Next::Continue => {}
+            Next::Quit => break,
+            Next::OpenViewer => {
+                // 外部のビューアを開いている間はTUIを中断する
+                suspend_terminal(terminal)?;
+                // 開けなくても終了はしない
+                let _ = hook::open_viewer(&viewmodel.img_path, app.viewer());
+                resume_terminal(terminal)?;
+            }
+            Next::Suspend => {
+                suspend_terminal(terminal)?;
+                // fgで再開されるまでここで止まる
+                #[cfg(unix)]
+                signal_hook::low_level::raise(signal_hook::consts::SIGTSTP)?;
+                // 止まっている間に離したキーは届かない
+                input.pressed_keys.clear();
+                resume_terminal(terminal)?;
             }
         }
     }
+    Ok(())
+}
 
-    // 終了処理
+/// 設定ファイルを読み込み，引数で指定された値で上書きする
+fn load_config(args: ConfigArgs) -> Result<Config> {
+    let mut config = match (args.auto, args.config) {
+        (Some(dir), _) => Config::auto(dir)?,
+        (None, Some(path)) => Config::load(path)?,
+        // 設定ファイルがなくても引数だけで実行できる
+        (None, None) if Path::new("config.toml").exists() => Config::load("config.toml".into())?,
+        (None, None) => Config::default(),
+    };
+
+    // 引数で指定された値で上書きする
+    if !args.dir.is_empty() {
+        config.dir = args.dir;
+    }
+    if let Some(files) = args.files {
+        config.files = Some(files);
+    }
+    config
+        .dests
+        .extend(args.dests.into_iter().map(|(key, dest)| (key, dest.into())));
+    if let Some(manifest) = args.manifest {
+        config.manifest = Some(manifest);
+    }
+    if config.dir.is_empty() && config.files.is_none() {
+        return Err(anyhow!(
+            "dir is not set; write it in config.toml or pass --dir or --files"
+        ));
+    }
+    Ok(config)
+}
+
+/// セッションを置くディレクトリと分類元のディレクトリをすべてロックする
+fn lock_dirs(config: &Config, force: bool) -> Result<Vec<SessionLock>> {
+    let base_dir = config.base_dir();
+    let mut dirs: Vec<PathBuf> = config
+        .dir
+        .iter()
+        .filter(|dir| !config::is_glob(dir) && dir.is_dir())
+        .chain([&base_dir])
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect();
+    // 同じディレクトリを2回ロックすると自分とぶつかる
+    dirs.sort();
+    dirs.dedup();
+    dirs.iter()
+        .map(|dir| SessionLock::acquire(dir, force))
+        .collect()
+}
+
+/// 端末でyかnの答えを尋ねる．yと答えた場合はtrue
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// TUIを使わずに規則か記録した決定で分類し，集計を表示する
+fn apply(config: Config, decisions: Option<PathBuf>) -> Result<()> {
+    let _locks = lock_dirs(&config, false)?;
+    let mut app = App::headless(config, true)?;
+    match decisions {
+        Some(path) => app.apply_decisions(&Journal::read(&path)?)?,
+        None if app.has_rules() => app.apply_rules()?,
+        None => return Err(anyhow!("no rules are set; write them in config.toml")),
+    }
+    let summary = app.summary();
+    app.write_summary(&summary)?;
+    app.write_manifest()?;
+    app.run_end_hook()?;
+    println!("{}", summary);
+    println!("Left: {}", app.rest_num());
+    Ok(())
+}
+
+/// 保存されたセッションの集計を表示する
+fn stats(config: Config) -> Result<()> {
+    let app = App::headless(config, true)?;
+    let summary = app.summary();
+    for (dest, count) in &summary.dests {
+        println!("{}: {}", dest.display(), count);
+    }
+    println!("Skipped: {}", summary.skipped);
+    println!("Trashed: {}", summary.trashed);
+    println!("Left: {}", app.rest_num());
+    Ok(())
+}
+
+/// 設定の誤りを表示する
+fn validate(config: Config) -> Result<()> {
+    let problems = config.problems();
+    if problems.is_empty() {
+        println!("config is valid");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(anyhow!("{} problems found in config", problems.len()))
+}
+
+/// 保存されたセッションの最後の決定を取り消す
+fn undo(config: Config, num: usize) -> Result<()> {
+    let _locks = lock_dirs(&config, false)?;
+    let mut app = App::headless(config, true)?;
+    let undone = app.undo_session(num)?;
+    println!("Undid {} decisions", undone);
+    Ok(())
+}
+
+/// TUIを中断して通常の端末に戻す
+/// TUIを表示している端末．落とすと端末を元に戻す
+struct TerminalGuard(Terminal<CrosstermBackend<Stdout>>);
+
+impl TerminalGuard {
+    fn new() -> Result<Self> {
+        let mut guard = TerminalGuard(Terminal::new(CrosstermBackend::new(io::stdout()))?);
+        enable_raw_mode()?;
+        execute!(
+            guard.0.backend_mut(),
+            EnterAlternateScreen,
+            EnableMouseCapture
+        )?;
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = suspend_terminal(&mut self.0);
+    }
+}
+
+fn suspend_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
@@ -97,6 +409,37 @@ fn main() -> Result<()> {
         DisableMouseCapture
     )?;
     terminal.show_cursor()?;
+    Ok(())
+}
+
+/// パニックしたときは端末を元に戻してからメッセージを表示する
+///
+/// ワーカーのパニックはデコードの失敗として扱うので，画面を崩さないよう表示しない
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().name() != Some("main") {
+            return;
+        }
+        let _ = disable_raw_mode();
+        let _ = execute!(
+            io::stdout(),
+            LeaveAlternateScreen,
+            DisableMouseCapture,
+            cursor::Show
+        );
+        default_hook(info);
+    }));
+}
 
+/// 中断したTUIを再開する
+fn resume_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    enable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        EnterAlternateScreen,
+        EnableMouseCapture
+    )?;
+    terminal.clear()?;
     Ok(())
 }
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// 機械学習のデータセット用に画像のパスとラベルをCSVで書き出す
+///
+/// relativeがtrueの場合はマニフェストのあるディレクトリからの相対パスにする．
+/// val_ratioが0より大きい場合はその割合の画像をvalにする列を加える
+pub fn write(
+    path: &Path,
+    rows: &[(PathBuf, String)],
+    relative: bool,
+    val_ratio: f64,
+) -> Result<()> {
+    let base = manifest_dir(path);
+    let split = val_ratio > 0.0;
+
+    let mut csv = String::from(if split {
+        "path,label,split\n"
+    } else {
+        "path,label\n"
+    });
+    for (img, label) in rows {
+        let img = if relative {
+            relative_path(img, base)
+        } else {
+            img.canonicalize().unwrap_or_else(|_| img.clone())
+        };
+        let img = img.to_string_lossy();
+        csv.push_str(&escape(&img));
+        csv.push(',');
+        csv.push_str(&escape(label));
+        if split {
+            csv.push(',');
+            csv.push_str(if is_val(&img, val_ratio) {
+                "val"
+            } else {
+                "train"
+            });
+        }
+        csv.push('\n');
+    }
+    fs::write(path, csv).with_context(|| format!("cannot write manifest: {}", path.display()))
+}
+
+/// マニフェストのあるディレクトリ．ファイル名だけならカレントディレクトリにする
+fn manifest_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// baseからの相対パス．求められない場合はそのまま返す
+fn relative_path(img: &Path, base: &Path) -> PathBuf {
+    let (Ok(img_abs), Ok(base_abs)) = (img.canonicalize(), base.canonicalize()) else {
+        return img.to_path_buf();
+    };
+    let common = img_abs
+        .components()
+        .zip(base_abs.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in base_abs.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(img_abs.components().skip(common));
+    relative
+}
+
+/// 実行ごとに変わらないよう，パスのハッシュでvalに入れるかを決める
+fn is_val(img: &str, val_ratio: f64) -> bool {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in img.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash % 10000) as f64 / 10000.0 < val_ratio
+}
+
+/// CSVのフィールドとして書けるようにする
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    #[test]
+    fn manifest_dir_of_bare_file_name_is_current_dir() {
+        assert_eq!(manifest_dir(Path::new("out.csv")), Path::new("."));
+        assert_eq!(manifest_dir(Path::new("data/out.csv")), Path::new("data"));
+    }
+
+    #[test]
+    fn relative_path_walks_up_to_common_dir() {
+        let dir = env::temp_dir().join(format!("shotclassif-manifest-{}", process::id()));
+        fs::create_dir_all(dir.join("sorted/cats")).unwrap();
+        fs::create_dir_all(dir.join("data")).unwrap();
+        let img = dir.join("sorted/cats/1.png");
+        fs::write(&img, b"").unwrap();
+
+        assert_eq!(
+            relative_path(&img, &dir),
+            Path::new("sorted").join("cats").join("1.png")
+        );
+        assert_eq!(
+            relative_path(&img, &dir.join("data")),
+            Path::new("..").join("sorted").join("cats").join("1.png")
+        );
+        // 存在しないファイルはそのまま
+        let missing = dir.join("missing.png");
+        assert_eq!(relative_path(&missing, &dir), missing);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn escape_quotes_only_when_needed() {
+        assert_eq!(escape("a.png"), "a.png");
+        assert_eq!(escape("a,b.png"), "\"a,b.png\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape("a\nb"), "\"a\nb\"");
+    }
+
+    #[test]
+    fn is_val_is_stable_and_follows_ratio() {
+        assert!(!is_val("a.png", 0.0));
+        assert!(is_val("a.png", 1.0));
+        assert_eq!(is_val("a.png", 0.5), is_val("a.png", 0.5));
+        let val = (0..1000)
+            .filter(|i| is_val(&format!("{}.png", i), 0.2))
+            .count();
+        assert!((100..300).contains(&val));
+    }
+}
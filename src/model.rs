@@ -0,0 +1,100 @@
+use anyhow::Result;
+use image::DynamicImage;
+
+use std::path::Path;
+
+/// 画像を入力するONNXモデル
+#[cfg(feature = "onnx")]
+pub struct Model {
+    // 実行には可変参照が要るので，ワーカー間で共有するためにロックする
+    session: std::sync::Mutex<ort::session::Session>,
+    // モデルに入力する画像の幅と高さ
+    size: (u32, u32),
+    // 入力の正規化に使うチャンネルごとの平均と標準偏差
+    mean: [f32; 3],
+    std: [f32; 3],
+}
+
+/// onnx機能なしでビルドした場合はモデルを読み込めない
+#[cfg(not(feature = "onnx"))]
+pub struct Model;
+
+// 入力の大きさがモデルで決まっていない場合に使う幅と高さ
+#[cfg(feature = "onnx")]
+const DEFAULT_SIZE: u32 = 224;
+
+#[cfg(feature = "onnx")]
+impl Model {
+    /// モデルを読み込む．入力は[1, 3, 高さ, 幅]のRGB画像とみなす
+    pub fn load(path: &Path, mean: [f32; 3], std: [f32; 3]) -> Result<Self> {
+        use anyhow::Context;
+
+        let session = ort::session::Session::builder()
+            .and_then(|builder| builder.commit_from_file(path))
+            .with_context(|| format!("cannot load model: {}", path.display()))?;
+        let shape = session
+            .inputs
+            .first()
+            .and_then(|input| input.input_type.tensor_shape())
+            .with_context(|| format!("model has no tensor input: {}", path.display()))?;
+        let dim = |i: usize| {
+            shape
+                .get(i)
+                .and_then(|&d| u32::try_from(d).ok())
+                .filter(|&d| d > 0)
+                .unwrap_or(DEFAULT_SIZE)
+        };
+        let size = (dim(3), dim(2));
+        Ok(Model {
+            session: std::sync::Mutex::new(session),
+            size,
+            mean,
+            std,
+        })
+    }
+
+    /// 画像を入力し，最初の出力を平たく並べて返す
+    pub fn run(&self, img: &DynamicImage) -> Result<Vec<f32>> {
+        use anyhow::anyhow;
+        use image::imageops::FilterType;
+
+        let (width, height) = self.size;
+        let rgb = img
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_rgb8();
+        // チャンネルごとに並べて正規化する
+        let plane = (width * height) as usize;
+        let mut data = vec![0.0; 3 * plane];
+        for (i, pixel) in rgb.pixels().enumerate() {
+            for c in 0..3 {
+                data[c * plane + i] = (f32::from(pixel[c]) / 255.0 - self.mean[c]) / self.std[c];
+            }
+        }
+        let input =
+            ort::value::Tensor::from_array(([1, 3, height as usize, width as usize], data))?;
+
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|_| anyhow!("model session is poisoned"))?;
+        let outputs = session.run(ort::inputs![input])?;
+        let (_, values) = outputs[0].try_extract_tensor::<f32>()?;
+        Ok(values.to_vec())
+    }
+}
+
+#[cfg(not(feature = "onnx"))]
+impl Model {
+    pub fn load(_path: &Path, _mean: [f32; 3], _std: [f32; 3]) -> Result<Self> {
+        anyhow::bail!("model is set but shotclassif was built without the onnx feature")
+    }
+
+    pub fn run(&self, _img: &DynamicImage) -> Result<Vec<f32>> {
+        Ok(Vec::new())
+    }
+}
+
+/// モデルを使えるようにビルドされているか
+pub fn is_available() -> bool {
+    cfg!(feature = "onnx")
+}
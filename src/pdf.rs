@@ -0,0 +1,65 @@
+use anyhow::Result;
+use image::DynamicImage;
+
+use std::{fs::File, io::Read, path::Path};
+
+/// 最初のページを表示するPDFの拡張子
+pub const EXTENSIONS: [&str; 1] = ["pdf"];
+
+/// PDFの最初のページを白い背景に描く
+///
+/// PDFiumのライブラリは実行ファイルと同じディレクトリか，システムのライブラリから読み込む
+#[cfg(feature = "pdf")]
+pub fn render(path: &Path) -> Result<DynamicImage> {
+    use anyhow::{anyhow, Context};
+    use pdfium_render::prelude::{PdfRenderConfig, Pdfium};
+    use std::{env, sync::OnceLock};
+
+    // 長い辺をこのピクセル数にして描く
+    const RENDER_SIZE: i32 = 2048;
+    // ライブラリの初期化と終了はプロセスで1回ずつにする
+    static PDFIUM: OnceLock<Result<Pdfium, String>> = OnceLock::new();
+
+    let pdfium = PDFIUM
+        .get_or_init(|| {
+            let exe_dir = env::current_exe()
+                .ok()
+                .and_then(|exe| exe.parent().map(Path::to_path_buf))
+                .unwrap_or_default();
+            Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(&exe_dir))
+                .or_else(|_| Pdfium::bind_to_system_library())
+                .map(Pdfium::new)
+                .map_err(|e| format!("cannot load PDFium library: {}", e))
+        })
+        .as_ref()
+        .map_err(|e| anyhow!("{}", e))?;
+    let error = || format!("cannot render PDF {}", path.display());
+    let document = pdfium.load_pdf_from_file(path, None).with_context(error)?;
+    let page = document.pages().first().with_context(error)?;
+    let config = PdfRenderConfig::new()
+        .set_target_width(RENDER_SIZE)
+        .set_maximum_height(RENDER_SIZE);
+    let bitmap = page.render_with_config(&config).with_context(error)?;
+    Ok(bitmap.as_image())
+}
+
+/// pdf機能なしでビルドした場合は描けない
+#[cfg(not(feature = "pdf"))]
+pub fn render(path: &Path) -> Result<DynamicImage> {
+    anyhow::bail!(
+        "shotclassif was built without the pdf feature: {}",
+        path.display()
+    )
+}
+
+/// pdf機能を有効にしてビルドしたか
+pub fn is_available() -> bool {
+    cfg!(feature = "pdf")
+}
+
+/// ファイルの先頭のバイト列からPDFか判定する
+pub fn sniff(path: &Path) -> bool {
+    let mut head = [0; 5];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut head));
+    read.is_ok() && &head == b"%PDF-"
+}
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use image::{metadata::Orientation, DynamicImage, ImageFormat};
+
+use std::{collections::HashSet, fs, ops::Range, path::Path};
+
+/// 埋め込まれたプレビューを表示するカメラのRAWの拡張子
+pub const EXTENSIONS: [&str; 4] = ["cr2", "nef", "arw", "dng"];
+
+// 辿るIFDの数の上限 (壊れたファイルで回り続けないように)
+const MAX_IFDS: usize = 64;
+
+// TIFFのタグ
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014a;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+/// 拡張子がRAWの画像か
+pub fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|raw| ext.eq_ignore_ascii_case(raw)))
+}
+
+/// RAWに埋め込まれたJPEGのプレビューのうち，読める中で最も大きいものをデコードする
+///
+/// 現像はしないので，カメラが作ったプレビューの色と大きさで表示される
+pub fn decode_preview(path: &Path) -> Result<DynamicImage> {
+    let bytes = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let tiff =
+        Tiff::new(&bytes).with_context(|| format!("not a TIFF based RAW: {}", path.display()))?;
+    let (mut previews, orientation) = tiff.previews();
+    previews.sort_by_key(|range| std::cmp::Reverse(range.len()));
+    // 可逆JPEGで圧縮したRAWのデータ本体などデコードできないものは飛ばす
+    let mut img = previews
+        .into_iter()
+        .find_map(|range| {
+            image::load_from_memory_with_format(&bytes[range], ImageFormat::Jpeg).ok()
+        })
+        .with_context(|| format!("no JPEG preview in {}", path.display()))?;
+    if let Some(orientation) = orientation.and_then(Orientation::from_exif) {
+        img.apply_orientation(orientation);
+    }
+    Ok(img)
+}
+
+/// TIFFの形のファイル
+struct Tiff<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(bytes: &'a [u8]) -> Option<Self> {
+        let little_endian = match bytes.get(..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            bytes,
+            little_endian,
+        })
+    }
+
+    /// 全てのIFDを辿って見つけたJPEGの範囲と，最初のIFDの向き
+    fn previews(&self) -> (Vec<Range<usize>>, Option<u8>) {
+        let mut previews = Vec::new();
+        let mut orientation = None;
+        let mut queue: Vec<u32> = self.u32(4).into_iter().collect();
+        let mut visited = HashSet::new();
+        while let Some(ifd) = queue.pop() {
+            if visited.len() >= MAX_IFDS || !visited.insert(ifd) {
+                continue;
+            }
+            let ifd = ifd as usize;
+            let Some(count) = self.u16(ifd) else {
+                continue;
+            };
+            let entries: Vec<_> = (0..usize::from(count))
+                .filter_map(|i| self.entry(ifd + 2 + i * 12))
+                .collect();
+            let value = |tag: u16| entries.iter().find(|e| e.0 == tag).map(|e| e.1);
+            if let (Some(offset), Some(len)) = (value(TAG_JPEG_OFFSET), value(TAG_JPEG_LENGTH)) {
+                previews.extend(self.jpeg(offset, len));
+            }
+            // 1つのストリップにJPEGで入っているプレビュー (CR2やDNG)
+            if matches!(value(TAG_COMPRESSION), Some(6 | 7)) {
+                if let (Some(offset), Some(len)) =
+                    (value(TAG_STRIP_OFFSETS), value(TAG_STRIP_BYTE_COUNTS))
+                {
+                    previews.extend(self.jpeg(offset, len));
+                }
+            }
+            if orientation.is_none() {
+                orientation = value(TAG_ORIENTATION).and_then(|v| u8::try_from(v).ok());
+            }
+            if let Some(&(_, first, count, pointer)) = entries.iter().find(|e| e.0 == TAG_SUB_IFDS)
+            {
+                // 1つなら値の欄に，複数なら指す先に並ぶ
+                if count == 1 {
+                    queue.push(first);
+                } else {
+                    queue.extend((0..count as usize).filter_map(|i| self.u32(pointer + i * 4)));
+                }
+            }
+            queue.extend(
+                self.u32(ifd + 2 + usize::from(count) * 12)
+                    .filter(|&next| next != 0),
+            );
+        }
+        (previews, orientation)
+    }
+
+    /// IFDの項目のタグ，最初の値，値の数，値の欄の位置
+    fn entry(&self, pos: usize) -> Option<(u16, u32, u32, usize)> {
+        let tag = self.u16(pos)?;
+        let kind = self.u16(pos + 2)?;
+        let count = self.u32(pos + 4)?;
+        let value = match kind {
+            // SHORT
+            3 => u32::from(self.u16(pos + 8)?),
+            // LONGとIFD
+            4 | 13 => self.u32(pos + 8)?,
+            _ => return Some((tag, 0, count, 0)),
+        };
+        Some((tag, value, count, self.u32(pos + 8)? as usize))
+    }
+
+    /// JPEGの先頭のマーカーで始まる範囲
+    fn jpeg(&self, offset: u32, len: u32) -> Option<Range<usize>> {
+        let range = offset as usize..(offset as usize).checked_add(len as usize)?;
+        self.bytes
+            .get(range.clone())?
+            .starts_with(&[0xff, 0xd8])
+            .then_some(range)
+    }
+
+    fn u16(&self, pos: usize) -> Option<u16> {
+        let bytes = self.bytes.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, pos: usize) -> Option<u32> {
+        let bytes = self.bytes.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+}
@@ -0,0 +1,231 @@
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
+
+use std::{
+    cell::OnceCell,
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use crate::hook;
+
+/// 画像を表示する前に自動で分類する規則．指定した条件をすべて満たす画像をdestに分類する
+#[derive(Deserialize, Debug)]
+pub struct Rule {
+    // ファイル名の正規表現
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    name: Option<Regex>,
+    // 拡張子のいずれか (大文字小文字は区別しない)
+    #[serde(default)]
+    ext: Vec<String>,
+    // 幅と高さの範囲 (ピクセル)
+    min_width: Option<u32>,
+    max_width: Option<u32>,
+    min_height: Option<u32>,
+    max_height: Option<u32>,
+    // EXIFのフィールド名ごとの値の正規表現
+    #[serde(default, deserialize_with = "deserialize_exif")]
+    exif: HashMap<String, Regex>,
+    // OCRで読み取った文字列の正規表現
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    text: Option<Regex>,
+    pub dest: PathBuf,
+}
+
+/// 最初に一致した規則の分類先と，判定のためにOCRで読み取った文字列．ocrはOCRのコマンド
+pub fn classify<'a>(
+    rules: &'a [Rule],
+    src: &Path,
+    ocr: Option<&str>,
+) -> (Option<&'a Path>, Option<String>) {
+    let props = Props::new(src, ocr);
+    let dest = rules
+        .iter()
+        .find(|rule| rule.matches(&props))
+        .map(|rule| rule.dest.as_path());
+    (dest, props.text.into_inner().flatten())
+}
+
+impl Rule {
+    /// OCRで読み取った文字列を条件に使うか
+    pub fn uses_text(&self) -> bool {
+        self.text.is_some()
+    }
+
+    fn matches(&self, props: &Props) -> bool {
+        let src = props.src;
+        if let Some(name) = &self.name {
+            let file_name = src.file_name().unwrap_or_default().to_string_lossy();
+            if !name.is_match(&file_name) {
+                return false;
+            }
+        }
+        if !self.ext.is_empty() {
+            let ext = src.extension().unwrap_or_default().to_string_lossy();
+            if !self
+                .ext
+                .iter()
+                .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+            {
+                return false;
+            }
+        }
+        let sizes = [
+            self.min_width,
+            self.max_width,
+            self.min_height,
+            self.max_height,
+        ];
+        if sizes.iter().any(Option::is_some) {
+            let Some((width, height)) = props.size() else {
+                return false;
+            };
+            let within = |value: u32, min: Option<u32>, max: Option<u32>| {
+                min.is_none_or(|min| min <= value) && max.is_none_or(|max| value <= max)
+            };
+            if !within(width, self.min_width, self.max_width)
+                || !within(height, self.min_height, self.max_height)
+            {
+                return false;
+            }
+        }
+        if let Some(text) = &self.text {
+            if !props.text().is_some_and(|value| text.is_match(value)) {
+                return false;
+            }
+        }
+        self.exif.iter().all(|(name, pattern)| {
+            props
+                .exif_value(name)
+                .is_some_and(|value| pattern.is_match(&value))
+        })
+    }
+}
+
+/// 規則の判定に使う画像の情報．ファイルを読む必要があるものは使うときに一度だけ取得する
+struct Props<'a> {
+    src: &'a Path,
+    ocr: Option<&'a str>,
+    size: OnceCell<Option<(u32, u32)>>,
+    exif: OnceCell<Option<exif::Exif>>,
+    text: OnceCell<Option<String>>,
+}
+
+impl<'a> Props<'a> {
+    fn new(src: &'a Path, ocr: Option<&'a str>) -> Self {
+        Props {
+            src,
+            ocr,
+            size: OnceCell::new(),
+            exif: OnceCell::new(),
+            text: OnceCell::new(),
+        }
+    }
+
+    /// OCRで読み取った文字列．OCRのコマンドがなければNone
+    fn text(&self) -> Option<&str> {
+        self.text
+            .get_or_init(|| hook::capture(self.ocr?, self.src).ok())
+            .as_deref()
+    }
+
+    /// 幅と高さ．ヘッダだけを読むのでデコードはしない
+    fn size(&self) -> Option<(u32, u32)> {
+        *self
+            .size
+            .get_or_init(|| image::image_dimensions(self.src).ok())
+    }
+
+    /// EXIFのフィールドの値を文字列にしたもの
+    fn exif_value(&self, name: &str) -> Option<String> {
+        let exif = self
+            .exif
+            .get_or_init(|| {
+                let mut reader = BufReader::new(File::open(self.src).ok()?);
+                exif::Reader::new().read_from_container(&mut reader).ok()
+            })
+            .as_ref()?;
+        let field = exif
+            .fields()
+            .find(|field| field.ifd_num == exif::In::PRIMARY && field.tag.to_string() == name)?;
+        // 文字列は引用符を付けずにそのまま比べる
+        let value = match &field.value {
+            exif::Value::Ascii(values) => values
+                .iter()
+                .map(|value| String::from_utf8_lossy(value))
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => field.display_value().to_string(),
+        };
+        Some(value)
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map(Some).map_err(D::Error::custom)
+}
+
+fn deserialize_exif<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Regex>, D::Error> {
+    HashMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, pattern)| {
+            let pattern = Regex::new(&pattern).map_err(D::Error::custom)?;
+            Ok((name, pattern))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+    use std::{env, fs, process};
+
+    fn rule(toml: &str) -> Rule {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn matches_name_and_ext() {
+        let rule = rule("name = \"^Screenshot\"\next = [\".PNG\", \"jpg\"]\ndest = \"shots\"");
+        let matches = |path: &str| rule.matches(&Props::new(Path::new(path), None));
+        assert!(matches("Screenshot 1.png"));
+        assert!(matches("Screenshot 2.JPG"));
+        assert!(!matches("Screenshot 3.gif"));
+        assert!(!matches("photo.png"));
+    }
+
+    #[test]
+    fn matches_size_range() {
+        let dir = env::temp_dir().join(format!("shotclassif-rules-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wide = dir.join("wide.png");
+        RgbImage::from_pixel(32, 8, Rgb([0, 0, 0]))
+            .save(&wide)
+            .unwrap();
+
+        let matches = |toml: &str| rule(toml).matches(&Props::new(&wide, None));
+        assert!(matches("min_width = 32\nmax_height = 8\ndest = \"wide\""));
+        assert!(!matches("min_width = 33\ndest = \"wide\""));
+        // 読めない画像は大きさの条件に一致しない
+        let missing = dir.join("missing.png");
+        assert!(!rule("max_width = 100\ndest = \"small\"").matches(&Props::new(&missing, None)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn text_needs_ocr() {
+        let rule = rule("text = \"hello\"\ndest = \"text\"");
+        let path = Path::new("1.png");
+        assert!(!rule.matches(&Props::new(path, None)));
+        assert!(rule.matches(&Props::new(path, Some("echo hello"))));
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::app::DestMode;
+
+/// 分類元ディレクトリに置くセッションファイルの名前
+const SESSION_FILE_NAME: &str = ".shotclassif_session.toml";
+
+/// 1枚の画像に対する決定
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum SessionEntry {
+    Move {
+        src: PathBuf,
+        dest: PathBuf,
+        // 以前のセッションファイルにはないので，なければ移動とする
+        #[serde(default, skip_serializing_if = "DestMode::is_move")]
+        mode: DestMode,
+        // 移動先にあった同名のファイルを上書きした (上書きされたファイルはゴミ箱にある)
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        replaced: bool,
+        // 書き直して置いた (移動元のファイルはゴミ箱にある)
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        rewritten: bool,
+    },
+    Skip {
+        src: PathBuf,
+    },
+    Trash {
+        src: PathBuf,
+    },
+    // 分類せずに分類先にコピーした．画像は未決定のまま残る
+    Copy {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+}
+
+impl SessionEntry {
+    pub fn src(&self) -> &Path {
+        match self {
+            SessionEntry::Move { src, .. }
+            | SessionEntry::Skip { src }
+            | SessionEntry::Trash { src }
+            | SessionEntry::Copy { src, .. } => src,
+        }
+    }
+
+    /// 画像に対する決定ではなく，分類せずにコピーした記録か
+    pub fn is_copy(&self) -> bool {
+        matches!(self, SessionEntry::Copy { .. })
+    }
+}
+
+/// 中断したところから再開するためのセッションの状態
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Session {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    entries: Vec<SessionEntry>,
+    // ファイルの中身がentriesと同じで，追記してよいか
+    #[serde(skip)]
+    synced: bool,
+    // 決定済みの画像の元の場所 (コピーは含めない)
+    #[serde(skip)]
+    done: HashSet<PathBuf>,
+}
+
+impl Session {
+    /// 新しいセッションを始める
+    pub fn new(dir: &Path) -> Self {
+        Session {
+            path: dir.join(SESSION_FILE_NAME),
+            ..Default::default()
+        }
+    }
+
+    /// 前回のセッションを読み込む．存在しない場合は新しいセッションを始める
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(SESSION_FILE_NAME);
+        if !path.exists() {
+            return Ok(Session::new(dir));
+        }
+        let session_str = fs::read_to_string(&path)
+            .with_context(|| format!("cannot read session file: {}", path.display()))?;
+        let (mut session, synced) = match toml::from_str::<Session>(&session_str) {
+            Ok(session) => (session, true),
+            // 追記の途中で落ちていれば，書きかけの最後の記録だけを捨てる
+            Err(e) => session_str
+                .rfind("[[entries]]")
+                .and_then(|end| toml::from_str::<Session>(&session_str[..end]).ok())
+                .map(|session| (session, false))
+                .ok_or(e)
+                .with_context(|| format!("session file is not valid toml: {}", path.display()))?,
+        };
+        session.path = path;
+        session.synced = synced;
+        session.index();
+        Ok(session)
+    }
+
+    /// 決定済みの画像の数
+    pub fn done_num(&self) -> usize {
+        self.entries.iter().filter(|entry| !entry.is_copy()).count()
+    }
+
+    /// これまでの決定
+    pub fn entries(&self) -> &[SessionEntry] {
+        &self.entries
+    }
+
+    /// 決定済みの画像か
+    pub fn is_done(&self, src: &Path) -> bool {
+        self.done.contains(src)
+    }
+
+    /// 決定を記録して保存する．ファイルには追記するだけにする
+    pub fn push(&mut self, entry: SessionEntry) -> Result<()> {
+        if !entry.is_copy() {
+            self.done.insert(entry.src().to_path_buf());
+        }
+        self.entries.push(entry);
+        // 空のセッションは`entries = []`と書かれているので追記できない
+        if !self.synced || self.entries.len() == 1 {
+            return self.save();
+        }
+        let result = self.append();
+        if result.is_err() {
+            self.synced = false;
+        }
+        result
+    }
+
+    /// 取り消された決定を削除して保存する
+    pub fn remove(&mut self, src: &Path) -> Result<Option<SessionEntry>> {
+        let removed = self
+            .entries
+            .iter()
+            .rposition(|entry| !entry.is_copy() && entry.src() == src)
+            .map(|pos| self.entries.remove(pos));
+        self.index();
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// 画像をコピーした記録をすべて削除して保存する．削除した記録を返す
+    pub fn remove_copies(&mut self, src: &Path) -> Result<Vec<SessionEntry>> {
+        let (copies, entries) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| entry.is_copy() && entry.src() == src);
+        self.entries = entries;
+        self.index();
+        self.save()?;
+        Ok(copies)
+    }
+
+    /// 最後の記録を削除して保存する
+    pub fn pop(&mut self) -> Result<Option<SessionEntry>> {
+        let removed = self.entries.pop();
+        self.index();
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// 決定済みの画像を記録から数え直す
+    fn index(&mut self) {
+        self.done = self
+            .entries
+            .iter()
+            .filter(|entry| !entry.is_copy())
+            .map(|entry| entry.src().to_path_buf())
+            .collect();
+    }
+
+    /// 最後の記録をファイルに追記する
+    fn append(&self) -> Result<()> {
+        let last = Session {
+            entries: self.entries.last().into_iter().cloned().collect(),
+            ..Default::default()
+        };
+        let entry_str = toml::to_string(&last).context("cannot serialize session")?;
+        OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(entry_str.as_bytes()))
+            .with_context(|| format!("cannot write session file: {}", self.path.display()))
+    }
+
+    /// ファイル全体を書き直す
+    fn save(&mut self) -> Result<()> {
+        let session_str = toml::to_string(self).context("cannot serialize session")?;
+        let result = replace_file(&self.path, &session_str)
+            .with_context(|| format!("cannot write session file: {}", self.path.display()));
+        self.synced = result.is_ok();
+        result
+    }
+}
+
+/// ファイルの中身を置き換える．書きかけで落ちても元のファイルが残るよう，隣に書いてから名前を変える
+pub fn replace_file(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+    fs::write(&tmp, contents)
+        .and_then(|()| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    fn skip(name: &str) -> SessionEntry {
+        SessionEntry::Skip {
+            src: PathBuf::from(name),
+        }
+    }
+
+    #[test]
+    fn appended_entries_load_and_torn_tail_is_dropped() {
+        let dir = env::temp_dir().join(format!("shotclassif-session-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut session = Session::new(&dir);
+        session.push(skip("1.png")).unwrap();
+        session.push(skip("2.png")).unwrap();
+        session.push(skip("3.png")).unwrap();
+        session.pop().unwrap();
+        session.push(skip("4.png")).unwrap();
+
+        let loaded = Session::load(&dir).unwrap();
+        let srcs: Vec<_> = loaded.entries().iter().map(SessionEntry::src).collect();
+        assert_eq!(
+            srcs,
+            [Path::new("1.png"), Path::new("2.png"), Path::new("4.png")]
+        );
+        assert!(loaded.is_done(Path::new("4.png")));
+        assert!(!loaded.is_done(Path::new("3.png")));
+
+        // 追記の途中で落ちたファイル
+        let path = dir.join(SESSION_FILE_NAME);
+        let mut session_str = fs::read_to_string(&path).unwrap();
+        session_str.push_str("[[entries]]\naction = \"skip\"\nsrc = \"5.p");
+        fs::write(&path, session_str).unwrap();
+        let mut loaded = Session::load(&dir).unwrap();
+        assert_eq!(loaded.done_num(), 3);
+        loaded.push(skip("6.png")).unwrap();
+        assert_eq!(Session::load(&dir).unwrap().done_num(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
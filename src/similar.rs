@@ -0,0 +1,185 @@
+use image::{imageops::FilterType, DynamicImage, ImageReader};
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    thread::{self, available_parallelism},
+};
+
+use crate::{heif, model::Model, pdf, raw, svg, video};
+
+// CLIPの画像エンコーダの入力の正規化に使うチャンネルごとの平均と標準偏差
+const CLIP_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const CLIP_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// 見た目が似ている画像ほど近い値になる差分ハッシュ (dHash)
+pub fn dhash(path: &Path) -> Option<u64> {
+    let img = open(path)?;
+    // 横に隣り合う画素の明るさの大小を64ビットに並べる
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Some(hash)
+}
+
+fn open(path: &Path) -> Option<DynamicImage> {
+    if heif::sniff(path) {
+        return heif::decode(path).ok();
+    }
+    if raw::is_raw(path) {
+        return raw::decode_preview(path).ok();
+    }
+    if svg::is_svg(path) {
+        return svg::render(path).ok();
+    }
+    if pdf::sniff(path) {
+        return pdf::render(path).ok();
+    }
+    if video::is_video(path) {
+        return video::frame(path).ok();
+    }
+    ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()
+}
+
+/// 全ての画像のハッシュを並列に求める．読めない画像はNone
+pub fn hashes(imgs: &[PathBuf]) -> Vec<Option<u64>> {
+    par_map(imgs, dhash)
+}
+
+/// 全ての画像の埋め込みを並列に求める．長さを1にそろえ，読めない画像はNone
+///
+/// モデルがなければ差分ハッシュの各ビットを並べたものを使う
+pub fn embeddings(imgs: &[PathBuf], model: Option<&Model>) -> Vec<Option<Vec<f32>>> {
+    par_map(imgs, |path| {
+        let embedding = match model {
+            Some(model) => model.run(&open(path)?).ok()?,
+            None => {
+                let hash = dhash(path)?;
+                (0..64)
+                    .map(|bit| if hash >> bit & 1 == 1 { 1.0 } else { -1.0 })
+                    .collect()
+            }
+        };
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        (norm > 0.0).then(|| embedding.iter().map(|v| v / norm).collect())
+    })
+}
+
+/// CLIPなどの画像エンコーダのモデルを読み込む
+pub fn load_encoder(path: &Path) -> anyhow::Result<Model> {
+    Model::load(path, CLIP_MEAN, CLIP_STD)
+}
+
+/// 似ている画像が隣り合うよう，直前の画像に最も近い画像を順にたどった並び
+///
+/// 最初の画像から始め，埋め込みがない画像は元の順で最後に並べる
+pub fn chain_order(embeddings: &[Option<Vec<f32>>]) -> Vec<usize> {
+    let mut rest: Vec<usize> = (0..embeddings.len())
+        .filter(|&i| embeddings[i].is_some())
+        .collect();
+    let mut order = Vec::with_capacity(embeddings.len());
+    if !rest.is_empty() {
+        let mut current = rest.remove(0);
+        order.push(current);
+        while !rest.is_empty() {
+            let similarity = |i: usize| dot(&embeddings[current], &embeddings[i]);
+            let pos = (0..rest.len())
+                .reduce(|best, pos| {
+                    if similarity(rest[pos]) > similarity(rest[best]) {
+                        pos
+                    } else {
+                        best
+                    }
+                })
+                .unwrap_or(0);
+            current = rest.remove(pos);
+            order.push(current);
+        }
+    }
+    order.extend((0..embeddings.len()).filter(|&i| embeddings[i].is_none()));
+    order
+}
+
+fn dot(a: &Option<Vec<f32>>, b: &Option<Vec<f32>>) -> f32 {
+    match (a, b) {
+        (Some(a), Some(b)) => a.iter().zip(b).map(|(a, b)| a * b).sum(),
+        _ => f32::NEG_INFINITY,
+    }
+}
+
+/// 画像ごとの処理をスレッドに分けて行う
+fn par_map<T: Send + Clone + Default>(imgs: &[PathBuf], f: impl Fn(&Path) -> T + Sync) -> Vec<T> {
+    let thread_num = available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = imgs.len().div_ceil(thread_num).max(1);
+    let f = &f;
+    thread::scope(|s| {
+        let handles: Vec<_> = imgs
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let handle = s.spawn(move || chunk.iter().map(|img| f(img)).collect());
+                (handle, chunk.len())
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|(handle, len)| handle.join().unwrap_or_else(|_| vec![T::default(); len]))
+            .collect()
+    })
+}
+
+/// ハッシュの距離がthreshold以下の画像をたどってまとめる
+///
+/// 各グループは画像の番号順で，グループは最初の画像の番号順に並ぶ
+pub fn groups(hashes: &[Option<u64>], threshold: u32) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    for (i, a) in hashes.iter().enumerate() {
+        let Some(a) = a else {
+            continue;
+        };
+        for (j, b) in hashes.iter().enumerate().skip(i + 1) {
+            if b.is_some_and(|b| (a ^ b).count_ones() <= threshold) {
+                let (root_i, root_j) = (find(&mut parent, i), find(&mut parent, j));
+                parent[root_j.max(root_i)] = root_j.min(root_i);
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_root = HashMap::new();
+    for i in 0..hashes.len() {
+        let root = find(&mut parent, i);
+        let group = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+    groups
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    // 次にたどるときのために根を直接指しておく
+    let mut i = i;
+    while parent[i] != root {
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    root
+}
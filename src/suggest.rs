@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use image::DynamicImage;
+
+use std::{fs, path::Path};
+
+use crate::model::Model;
+
+/// 分類の候補のラベルと確からしさ (0〜1)
+pub type Suggestion = (String, f32);
+
+// 入力の正規化に使うImageNetのチャンネルごとの平均と標準偏差
+const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+/// 画像分類のONNXモデルで画像ごとに分類の候補を出す
+pub struct Suggester {
+    model: Model,
+    // モデルの出力の順に並べたラベル
+    labels: Vec<String>,
+    top_k: usize,
+}
+
+impl Suggester {
+    /// モデルとラベルのファイルを読み込む．ラベルがなければ出力の番号で表示する
+    pub fn load(model: &Path, labels: Option<&Path>, top_k: usize) -> Result<Self> {
+        let model = Model::load(model, MEAN, STD)?;
+        let labels = match labels {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("cannot read model labels: {}", path.display()))?
+                .lines()
+                .map(|line| line.trim().to_string())
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(Suggester {
+            model,
+            labels,
+            top_k,
+        })
+    }
+
+    /// 確からしい順の候補
+    pub fn suggest(&self, img: &DynamicImage) -> Result<Vec<Suggestion>> {
+        let scores = self.model.run(img)?;
+        Ok(self.top(&scores))
+    }
+
+    fn top(&self, scores: &[f32]) -> Vec<Suggestion> {
+        // 確率を出力するモデルでなければsoftmaxで確率にする
+        let is_probability = scores.iter().all(|s| (0.0..=1.0).contains(s))
+            && (scores.iter().sum::<f32>() - 1.0).abs() < 1e-3;
+        let probs: Vec<f32> = if is_probability {
+            scores.to_vec()
+        } else {
+            let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let exps: Vec<f32> = scores.iter().map(|s| (s - max).exp()).collect();
+            let sum: f32 = exps.iter().sum();
+            exps.iter().map(|e| e / sum).collect()
+        };
+
+        let mut ranked: Vec<(usize, f32)> = probs.into_iter().enumerate().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+            .into_iter()
+            .take(self.top_k)
+            .map(|(i, prob)| {
+                let label = self
+                    .labels
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| format!("#{}", i));
+                (label, prob)
+            })
+            .collect()
+    }
+}
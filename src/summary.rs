@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// セッション終了時の集計
+pub struct Summary {
+    // 移動先ごとの画像の数
+    pub dests: Vec<(PathBuf, usize)>,
+    pub skipped: usize,
+    pub trashed: usize,
+    // 移動に失敗した数
+    pub failures: usize,
+    // デコードに失敗した画像と理由
+    pub errors: Vec<(PathBuf, String)>,
+    // 後で見直すために印を付けた画像
+    pub flagged: Vec<PathBuf>,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    /// 集計をファイルに書き出す
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string())
+            .with_context(|| format!("cannot write summary file: {}", path.display()))
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (dest, count) in &self.dests {
+            writeln!(f, "{}: {}", dest.display(), count)?;
+        }
+        writeln!(f, "Skipped: {}", self.skipped)?;
+        writeln!(f, "Trashed: {}", self.trashed)?;
+        writeln!(f, "Failures: {}", self.failures)?;
+        if !self.errors.is_empty() {
+            writeln!(f, "Decode errors: {}", self.errors.len())?;
+            for (src, error) in &self.errors {
+                writeln!(f, "  {}: {}", src.display(), error)?;
+            }
+        }
+        writeln!(f, "Flagged: {}", self.flagged.len())?;
+        for src in &self.flagged {
+            writeln!(f, "  {}", src.display())?;
+        }
+        let secs = self.elapsed.as_secs();
+        write!(
+            f,
+            "Elapsed: {:02}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
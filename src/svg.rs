@@ -0,0 +1,73 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+/// ラスタライズして表示するSVGの拡張子
+pub const EXTENSIONS: [&str; 2] = ["svg", "svgz"];
+
+// 小さなアイコンでも見やすいよう，長い辺をこのピクセル数まで拡大して描く
+const MIN_SIZE: f32 = 1024.0;
+// 大きな図でもメモリを使いすぎないよう，長い辺をこのピクセル数までに抑える
+const MAX_SIZE: f32 = 4096.0;
+
+/// 拡張子がSVGの画像か
+pub fn is_svg(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|svg| ext.eq_ignore_ascii_case(svg)))
+}
+
+/// SVGを透明な背景に描く
+pub fn render(path: &Path) -> Result<DynamicImage> {
+    let data = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let options = usvg::Options {
+        // 相対パスで参照する画像は同じディレクトリから探す
+        resources_dir: path.parent().map(Path::to_path_buf),
+        fontdb: fonts(),
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_data(&data, &options)
+        .with_context(|| format!("cannot parse SVG {}", path.display()))?;
+    let size = tree.size();
+    let longest = size.width().max(size.height());
+    let scale = longest.clamp(MIN_SIZE, MAX_SIZE) / longest;
+    let width = (size.width() * scale).ceil() as u32;
+    let height = (size.height() * scale).ceil() as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .with_context(|| format!("SVG has no size: {}", path.display()))?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    // tiny-skiaはアルファを乗算済みで持つ
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    let buffer = RgbaImage::from_raw(width, height, pixels)
+        .with_context(|| format!("cannot render SVG {}", path.display()))?;
+    Ok(DynamicImage::ImageRgba8(buffer))
+}
+
+/// テキストの描画に使うシステムのフォント．読み込みに時間がかかるので最初の1回だけ読む
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = usvg::fontdb::Database::new();
+            fonts.load_system_fonts();
+            Arc::new(fonts)
+        })
+        .clone()
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Component, Path, PathBuf},
+};
+
+// テンプレートで使えるプレースホルダ
+const PLACEHOLDERS: &[&str] = &["year", "month", "day", "date", "time", "orig", "ext"];
+
+/// 分類先に"{year}"のようなプレースホルダが含まれているか
+pub fn is_template(dest: &Path) -> bool {
+    dest.to_string_lossy().contains('{')
+}
+
+/// 分類先のプレースホルダを画像ごとの値に置き換える
+pub fn expand(dest: &Path, src: &Path) -> Result<PathBuf> {
+    if !is_template(dest) {
+        return Ok(dest.to_path_buf());
+    }
+    expand_str(&dest.to_string_lossy(), src).map(PathBuf::from)
+}
+
+/// 移動後のファイル名をテンプレートから作る．拡張子は元のものを引き継ぐ
+pub fn rename(template: &str, src: &Path) -> Result<PathBuf> {
+    let mut file_name = expand_str(template, src)?;
+    if let Some(ext) = src.extension() {
+        file_name.push('.');
+        file_name.push_str(&ext.to_string_lossy());
+    }
+    Ok(PathBuf::from(file_name))
+}
+
+/// プレースホルダを置き換える
+///
+/// {year}，{month}，{day}，{date}，{time} はEXIFの撮影日時 (なければ更新日時)，
+/// {orig} は元のファイル名 (拡張子を除く)，{ext} は拡張子になる
+fn expand_str(template: &str, src: &Path) -> Result<String> {
+    // 日時はEXIFを読むので必要になったときだけ取得する
+    let mut datetime = None;
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("unclosed placeholder in template: {}", template))?
+            + start;
+        let name = &rest[start + 1..end];
+        let format = match name {
+            "year" => Some("%Y"),
+            "month" => Some("%m"),
+            "day" => Some("%d"),
+            "date" => Some("%Y-%m-%d"),
+            "time" => Some("%H%M%S"),
+            _ => None,
+        };
+        let value = match (format, name) {
+            (Some(format), _) => {
+                let datetime = match datetime {
+                    Some(datetime) => datetime,
+                    None => *datetime.insert(img_datetime(src)?),
+                };
+                datetime.format(format).to_string()
+            }
+            (None, "orig") => src
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            (None, "ext") => src
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "other".to_string()),
+            _ => return Err(anyhow!("unknown placeholder in template: {{{}}}", name)),
+        };
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// テンプレートのプレースホルダがすべて使えるものか確かめる
+pub fn check(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("unclosed placeholder in template: {}", template))?
+            + start;
+        let name = &rest[start + 1..end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(anyhow!("unknown placeholder in template: {{{}}}", name));
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+/// 展開後のディレクトリがテンプレートから作られたものか
+pub fn matches(template: &Path, dir: &Path) -> bool {
+    let mut template = template.components().filter(|c| *c != Component::CurDir);
+    let mut dir = dir.components().filter(|c| *c != Component::CurDir);
+    loop {
+        match (template.next(), dir.next()) {
+            (None, None) => return true,
+            (Some(t), Some(d)) => {
+                // プレースホルダを含む階層は任意の名前に一致させる
+                if !t.as_os_str().to_string_lossy().contains('{') && t != d {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// 画像の撮影日時．EXIFがなければ更新日時を使う
+fn img_datetime(src: &Path) -> Result<NaiveDateTime> {
+    if let Some(datetime) = exif_datetime(src) {
+        return Ok(datetime);
+    }
+    let modified: DateTime<Local> = fs::metadata(src)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("cannot get modified time of {}", src.display()))?
+        .into();
+    Ok(modified.naive_local())
+}
+
+/// EXIFの撮影日時
+fn exif_datetime(src: &Path) -> Option<NaiveDateTime> {
+    let mut reader = BufReader::new(File::open(src).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let field = exif
+        .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        .or_else(|| exif.get_field(exif::Tag::DateTime, exif::In::PRIMARY))?;
+    let exif::Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let datetime = exif::DateTime::from_ascii(values.first()?).ok()?;
+    // 撮影日時が未設定の場合は0が入っているので日付として解釈できない
+    NaiveDate::from_ymd_opt(
+        i32::from(datetime.year),
+        u32::from(datetime.month),
+        u32::from(datetime.day),
+    )?
+    .and_hms_opt(
+        u32::from(datetime.hour),
+        u32::from(datetime.minute),
+        u32::from(datetime.second),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_accepts_only_known_placeholders() {
+        assert!(check("sorted/{year}/{month}").is_ok());
+        assert!(check("{date}_{orig}.{ext}").is_ok());
+        assert!(check("plain").is_ok());
+        assert!(check("sorted/{yaer}").is_err());
+        assert!(check("sorted/{year").is_err());
+    }
+}
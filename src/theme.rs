@@ -0,0 +1,157 @@
+use ratatui::{
+    style::{Color, Style},
+    widgets::{Block, Borders},
+};
+use serde::{de::Error, Deserialize, Deserializer};
+
+use std::{collections::HashMap, str::FromStr};
+
+/// UIの色
+#[derive(Debug, Clone)]
+pub struct Theme {
+    // 分類先のキー
+    pub dest: Color,
+    // スキップのキー
+    pub skip: Color,
+    // ゴミ箱のキー
+    pub trash: Color,
+    // 分類以外の操作キー
+    pub control: Color,
+    // 終了キー
+    pub quit: Color,
+    // 完了画面や確定の操作
+    pub done: Color,
+    // 確認画面やデコード待ち
+    pub warning: Color,
+    // テキストの入力欄
+    pub prompt: Color,
+    // ギャラリーで選択している画像
+    pub cursor: Color,
+    // ギャラリーで印を付けた画像
+    pub marked: Color,
+    // 読み込み前のサムネイルなど目立たせないもの
+    pub dim: Color,
+    // 明るさのヒストグラム
+    pub histogram: Color,
+    // 枠線
+    pub border: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            dest: Color::Cyan,
+            skip: Color::Yellow,
+            trash: Color::LightRed,
+            control: Color::Magenta,
+            quit: Color::Red,
+            done: Color::Green,
+            warning: Color::Yellow,
+            prompt: Color::Cyan,
+            cursor: Color::Yellow,
+            marked: Color::Green,
+            dim: Color::DarkGray,
+            histogram: Color::Gray,
+            border: Color::Reset,
+        }
+    }
+}
+
+impl Theme {
+    /// 色覚の違いがあっても見分けやすいOkabe-Itoの配色
+    pub fn colorblind() -> Self {
+        let orange = Color::Rgb(230, 159, 0);
+        let sky_blue = Color::Rgb(86, 180, 233);
+        let bluish_green = Color::Rgb(0, 158, 115);
+        let yellow = Color::Rgb(240, 228, 66);
+        let vermillion = Color::Rgb(213, 94, 0);
+        let reddish_purple = Color::Rgb(204, 121, 167);
+        Theme {
+            dest: sky_blue,
+            skip: yellow,
+            trash: vermillion,
+            control: reddish_purple,
+            quit: vermillion,
+            done: bluish_green,
+            warning: orange,
+            prompt: sky_blue,
+            cursor: orange,
+            marked: bluish_green,
+            ..Theme::default()
+        }
+    }
+
+    /// 端末の既定の色だけを使う配色
+    pub fn mono() -> Self {
+        Theme {
+            dest: Color::Reset,
+            skip: Color::Reset,
+            trash: Color::Reset,
+            control: Color::Reset,
+            quit: Color::Reset,
+            done: Color::Reset,
+            warning: Color::Reset,
+            prompt: Color::Reset,
+            cursor: Color::White,
+            marked: Color::Gray,
+            dim: Color::DarkGray,
+            histogram: Color::Reset,
+            border: Color::Reset,
+        }
+    }
+
+    /// 枠線の色を付けた枠
+    pub fn block(&self) -> Block<'static> {
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(self.border))
+    }
+
+    fn color_mut(&mut self, name: &str) -> Option<&mut Color> {
+        let color = match name {
+            "dest" => &mut self.dest,
+            "skip" => &mut self.skip,
+            "trash" => &mut self.trash,
+            "control" => &mut self.control,
+            "quit" => &mut self.quit,
+            "done" => &mut self.done,
+            "warning" => &mut self.warning,
+            "prompt" => &mut self.prompt,
+            "cursor" => &mut self.cursor,
+            "marked" => &mut self.marked,
+            "dim" => &mut self.dim,
+            "histogram" => &mut self.histogram,
+            "border" => &mut self.border,
+            _ => return None,
+        };
+        Some(color)
+    }
+}
+
+/// presetで元にする配色を選び，残りのキーで個別の色を上書きする
+impl<'de> Deserialize<'de> for Theme {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut table = HashMap::<String, String>::deserialize(deserializer)?;
+        let mut theme = match table.remove("preset").as_deref() {
+            None | Some("default") => Theme::default(),
+            Some("colorblind") => Theme::colorblind(),
+            Some("mono") => Theme::mono(),
+            Some(preset) => {
+                return Err(D::Error::custom(format!(
+                    "unknown theme preset: {}",
+                    preset
+                )))
+            }
+        };
+        for (name, value) in table {
+            let color = Color::from_str(&value).map_err(|_| {
+                D::Error::custom(format!("invalid color for theme.{}: {}", name, value))
+            })?;
+            let slot = theme
+                .color_mut(&name)
+                .ok_or_else(|| D::Error::custom(format!("unknown theme color: {}", name)))?;
+            *slot = color;
+        }
+        Ok(theme)
+    }
+}
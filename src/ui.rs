@@ -1,100 +1,905 @@
-use std::path::Path;
+use std::{path::Path, time::Duration};
 
 use ratatui::{
     layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
+    text::{Line, Span},
+    widgets::{Clear, List, ListItem, ListState, Paragraph, Sparkline, Wrap},
     Frame,
 };
 use ratatui_image::StatefulImage;
+use serde::Deserialize;
 
-use crate::{app::AppLog, viewmodel::ViewModel};
+use crate::{
+    app::{AppLog, ImgMeta},
+    colors::ColorStats,
+    config::Dest,
+    theme::Theme,
+    viewmodel::{Prompt, ViewModel},
+};
+
+// デコード待ちの間に表示するスピナー
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+// サムネイルの列の高さ
+const THUMBS_HEIGHT: u16 = 8;
+// 明るさのヒストグラムと代表色の欄の高さ (枠を含む)
+const COLORS_HEIGHT: u16 = 6;
+// 画像だけの表示で直前の操作を知らせる時間
+const FLASH_DURATION: Duration = Duration::from_millis(1500);
+// ギャラリーの列の数
+const GALLERY_COLUMNS: u32 = 3;
+
+/// 画像と情報欄の並べ方
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PanelLayout {
+    // 端末の縦横比で選ぶ
+    #[default]
+    Auto,
+    // 情報欄を画像の右に置く
+    Horizontal,
+    // 情報欄を画像の下に置く
+    Vertical,
+}
 
 /// UIを描画
 pub fn ui(f: &mut Frame, vm: &mut ViewModel) {
-    let main_chunks = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([Constraint::Percentage(70), Constraint::Percentage(30)])
-        .split(f.area());
+    let area = f.area();
+    let image_area = if vm.zen && !vm.is_fin && !vm.gallery && vm.compare.is_none() {
+        draw_zen(f, vm, area);
+        area
+    } else {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)])
+            .split(area);
+        draw_status_bar(f, vm, rows[1]);
+        let area = rows[0];
+        // 文字の大きさから端末の縦横のピクセル数を比べ，縦長なら縦に並べる
+        let (font_width, font_height) = vm.font_size;
+        let portrait = u32::from(area.width) * u32::from(font_width)
+            < u32::from(area.height) * u32::from(font_height);
+        let direction = match vm.layout {
+            PanelLayout::Auto if portrait => Direction::Vertical,
+            PanelLayout::Auto | PanelLayout::Horizontal => Direction::Horizontal,
+            PanelLayout::Vertical => Direction::Vertical,
+        };
+        let main_chunks = Layout::default()
+            .direction(direction)
+            .constraints([
+                Constraint::Percentage(vm.image_percent),
+                Constraint::Percentage(100 - vm.image_percent),
+            ])
+            .split(area);
+
+        draw_image_panel(f, vm, main_chunks[0]);
+        draw_info_panel(f, vm, main_chunks[1]);
+        main_chunks[0]
+    };
+
+    if let Some((existing, duplicate)) = &vm.conflict {
+        draw_conflict_popup(f, &vm.theme, existing, *duplicate, image_area);
+    }
+    if let Some((_, name)) = &vm.confirm {
+        draw_confirm_popup(f, &vm.theme, name, image_area);
+    }
+    if let Some((_, _, error)) = &vm.retry {
+        draw_retry_popup(f, &vm.theme, error, image_area);
+    }
+    if vm.peek.is_some() {
+        draw_peek_popup(f, vm, image_area);
+    }
+    if let Some((remaining, staged)) = vm.quit_confirm {
+        draw_quit_popup(f, &vm.theme, remaining, staged, image_area);
+    }
+    match &vm.prompt {
+        Some((Prompt::PickDest, query)) => draw_picker_popup(f, vm, query, image_area),
+        Some((prompt, input)) => draw_prompt_popup(f, &vm.theme, *prompt, input, image_area),
+        None => {}
+    }
+    if vm.show_help {
+        draw_help_popup(f, vm, f.area());
+    }
+}
+
+/// 保留中の操作を確認する画面を描画
+fn draw_review(f: &mut Frame, vm: &ViewModel, area: Rect) {
+    let area = centered_rect(80, 80, area);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(3)])
+        .split(area);
+
+    let items: Vec<ListItem> = vm
+        .staged
+        .iter()
+        .map(|(src, dest)| ListItem::new(format!("{} -> {}", src.display(), dest.display())))
+        .collect();
+    let list = List::new(items).block(
+        vm.theme
+            .block()
+            .title(format!("Review ({} pending)", vm.staged.len())),
+    );
+    let footer = Paragraph::new("[Enter] apply all  [←] go back")
+        .style(Style::default().fg(vm.theme.done))
+        .block(vm.theme.block())
+        .alignment(Alignment::Center);
+    f.render_widget(Clear, area);
+    f.render_widget(list, chunks[0]);
+    f.render_widget(footer, chunks[1]);
+}
+
+/// 移動先のファイルとの衝突の確認を描画
+fn draw_conflict_popup(f: &mut Frame, theme: &Theme, existing: &Path, duplicate: bool, area: Rect) {
+    let area = centered_rect(60, 30, area);
+    let (title, text) = if duplicate {
+        (
+            "Duplicate",
+            format!(
+                "{} has the same content\n\n[d] delete  [r] keep both  [s] skip  [Esc] cancel",
+                existing.display()
+            ),
+        )
+    } else {
+        (
+            "Conflict",
+            format!(
+                "{} already exists\n\n[r] rename  [o] overwrite  [s] skip  [Esc] cancel",
+                existing.display()
+            ),
+        )
+    };
+    let popup = Paragraph::new(text)
+        .style(Style::default().fg(theme.warning))
+        .block(theme.block().title(title))
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+/// 確認を求める分類先に送る前の確認を描画
+fn draw_confirm_popup(f: &mut Frame, theme: &Theme, name: &str, area: Rect) {
+    let area = centered_rect(60, 20, area);
+    let popup = Paragraph::new(format!("Send to {}?\n\n[y] yes  [n] no", name))
+        .style(Style::default().fg(theme.warning))
+        .block(theme.block().title("Confirm"))
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+/// 分類先に最近置いた画像を格子状に並べて描画
+fn draw_peek_popup(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    let Some(peek) = &mut vm.peek else {
+        return;
+    };
+    let area = centered_rect(90, 90, area);
+    let block = vm
+        .theme
+        .block()
+        .title(format!("Recent in {} (any key to close)", peek.name));
+    let inner = block.inner(area);
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+    if peek.imgs.is_empty() {
+        let text = Paragraph::new("No images yet")
+            .style(Style::default().fg(vm.theme.dim))
+            .alignment(Alignment::Center);
+        f.render_widget(text, inner);
+        return;
+    }
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
+        .split(inner);
+    let cells: Vec<Rect> = rows
+        .iter()
+        .flat_map(|row| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Ratio(1, GALLERY_COLUMNS); GALLERY_COLUMNS as usize])
+                .split(*row)
+                .to_vec()
+        })
+        .collect();
+    for ((path, state), cell) in peek.imgs.iter_mut().zip(cells.iter()) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let block = vm.theme.block().title(name.into_owned());
+        let img_area = block.inner(*cell);
+        f.render_widget(block, *cell);
+        f.render_stateful_widget(StatefulImage::default(), img_area, state);
+    }
+}
 
-    draw_image_panel(f, vm, main_chunks[0]);
-    draw_info_panel(f, vm, main_chunks[1]);
+/// 未決定の画像を残して終了する前の確認を描画
+fn draw_quit_popup(f: &mut Frame, theme: &Theme, remaining: usize, staged: usize, area: Rect) {
+    let area = centered_rect(60, 20, area);
+    let mut text = String::new();
+    if remaining > 0 {
+        text.push_str(&format!("{} images are not sorted yet. ", remaining));
+    }
+    if staged > 0 {
+        text.push_str(&format!(
+            "{} staged moves are not committed and will be lost. ",
+            staged
+        ));
+    }
+    let popup = Paragraph::new(format!(
+        "{}Quit?\n\n[y] quit  [w] save list and quit  [n] no",
+        text
+    ))
+    .style(Style::default().fg(theme.warning))
+    .block(theme.block().title("Quit"))
+    .alignment(Alignment::Center)
+    .wrap(Wrap { trim: true });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+/// ファイルの操作に失敗したときにやり直すかを尋ねる画面を描画
+fn draw_retry_popup(f: &mut Frame, theme: &Theme, error: &str, area: Rect) {
+    // エラーにはパスが含まれて長くなりやすい
+    let area = centered_rect(80, 50, area);
+    let popup = Paragraph::new(format!(
+        "{}\n\n[r] retry  [s] skip this image  [a] abort",
+        error
+    ))
+    .style(Style::default().fg(theme.warning))
+    .block(theme.block().title("Failed"))
+    .alignment(Alignment::Center)
+    .wrap(Wrap { trim: true });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+/// テキストの入力欄を描画
+fn draw_prompt_popup(f: &mut Frame, theme: &Theme, prompt: Prompt, input: &str, area: Rect) {
+    let area = centered_rect(60, 20, area);
+    let (title, submit) = match prompt {
+        Prompt::NewDest => ("New destination", "add"),
+        Prompt::Caption => ("Caption", "save"),
+        Prompt::PickDest => ("Destination", "send"),
+        Prompt::Goto => ("Go to (:number or /name)", "go"),
+        Prompt::Filter => ("Filter by file name (empty to clear)", "apply"),
+    };
+    let popup = Paragraph::new(format!("{}_\n\n[Enter] {}  [Esc] cancel", input, submit))
+        .style(Style::default().fg(theme.prompt))
+        .block(theme.block().title(title))
+        .wrap(Wrap { trim: false });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+/// 名前で絞り込んだ分類先の一覧を描画
+fn draw_picker_popup(f: &mut Frame, vm: &ViewModel, query: &str, area: Rect) {
+    let area = centered_rect(60, 60, area);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0)])
+        .split(area);
+    let input = Paragraph::new(format!("{}_", query))
+        .style(Style::default().fg(vm.theme.prompt))
+        .block(vm.theme.block().title("Destination"));
+    let items: Vec<ListItem> = vm
+        .picker_matches(query)
+        .into_iter()
+        .map(|(key, dest)| {
+            ListItem::new(format!("[{}] -> {}", key, dest.name()))
+                .style(Style::default().fg(dest_color(&vm.theme, dest)))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(
+            vm.theme
+                .block()
+                .title("[↑/↓] select  [Enter] send  [Esc] cancel"),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    // 分類先が多くても選んでいる位置が見えるようにスクロールする
+    let mut state = ListState::default().with_selected(Some(vm.picker_cursor));
+    f.render_widget(Clear, area);
+    f.render_widget(input, chunks[0]);
+    f.render_stateful_widget(list, chunks[1], &mut state);
 }
 
 /// 画像表示エリアを描画
 fn draw_image_panel(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    let thumbs_height = if vm.is_fin || vm.gallery || vm.upcoming.is_empty() {
+        0
+    } else {
+        THUMBS_HEIGHT
+    };
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Min(0), Constraint::Length(3)])
+        .constraints([
+            Constraint::Min(0),
+            Constraint::Length(thumbs_height),
+            // ファイル名と進捗 (と説明文) の行数に枠の分を足す
+            Constraint::Length(
+                4 + u16::from(vm.meta.is_some())
+                    + u16::from(vm.caption.is_some())
+                    + u16::from(vm.duplicate_of.is_some())
+                    + u16::from(!vm.suggestions.is_empty()),
+            ),
+        ])
         .split(area);
 
-    let image_block = Block::default().title("Image").borders(Borders::ALL);
-    f.render_widget(image_block, chunks[0]);
+    let mut title = "Image".to_string();
+    if vm.is_zoomed() {
+        title.push_str(&format!(" (x{:.1})", vm.zoom));
+    }
+    if vm.rotation != 0 {
+        title.push_str(&format!(" ({}°)", u16::from(vm.rotation) * 90));
+    }
+    if let Some((page, pages)) = vm.page() {
+        title.push_str(&format!(" (page {}/{})", page, pages));
+    }
+    if vm.low_bandwidth {
+        title.push_str(" (low bandwidth)");
+    }
+    if let Some(seed) = vm.shuffled {
+        title.push_str(&format!(" (shuffled, seed {})", seed));
+    }
+    if let Some(filter) = &vm.filter {
+        title.push_str(&format!(" (filter: {})", filter));
+    }
+    if vm.sticky {
+        match vm.last_key {
+            Some((key, _)) => title.push_str(&format!(" (sticky: {})", key)),
+            None => title.push_str(" (sticky)"),
+        }
+    }
+    if let Some(count) = vm.count {
+        title.push_str(&format!(" (next {} images)", count));
+    }
+    if vm.peek_pending {
+        title.push_str(" (peek: press a destination key)");
+    }
+    // 読み取った文字列があれば画像の右に並べる
+    let (image_area, text_area) = match &vm.text {
+        Some(_) if vm.show_text && !vm.is_fin && !vm.gallery && vm.compare.is_none() => {
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(65), Constraint::Percentage(35)])
+                .split(chunks[0]);
+            (columns[0], Some(columns[1]))
+        }
+        _ => (chunks[0], None),
+    };
+    let image_block = vm.theme.block().title(title);
+    f.render_widget(image_block, image_area);
+    vm.image_area = image_area;
+    if let (Some(area), Some(text)) = (text_area, &vm.text) {
+        let text = Paragraph::new(text.as_str())
+            .block(vm.theme.block().title("Text"))
+            .wrap(Wrap { trim: false });
+        f.render_widget(text, area);
+    }
 
-    if vm.is_fin {
-        let done_block = Block::default().borders(Borders::ALL).title("Done");
-        let text = Paragraph::new("All images have been sorted!")
-            .style(Style::default().fg(Color::Green))
+    if vm.is_fin && !vm.staged.is_empty() {
+        draw_review(f, vm, chunks[0]);
+    } else if vm.is_fin {
+        let done_block = vm.theme.block().title("Done");
+        let mut text = String::from("All images have been sorted!\n\n");
+        if let Some(summary) = &vm.summary {
+            text.push_str(&summary.to_string());
+        }
+        let text = Paragraph::new(text)
+            .style(Style::default().fg(vm.theme.done))
             .block(done_block)
             .alignment(Alignment::Center);
-        f.render_widget(text, centered_rect(60, 20, chunks[0]));
+        f.render_widget(text, centered_rect(60, 60, chunks[0]));
+    } else if vm.compare.is_some() {
+        draw_compare(f, vm, chunks[0]);
+    } else if vm.gallery {
+        draw_gallery(f, vm, chunks[0]);
+    } else if vm.is_loading() {
+        draw_loading(f, vm, image_area);
     } else {
-        let image = StatefulImage::default();
-        f.render_stateful_widget(image, chunks[0], &mut vm.img);
+        draw_img(f, vm, image_area);
     }
 
-    let file_info_text = format!(
+    let mut file_info_text = format!(
         "File: {}\nProgress: {} / {}",
         vm.img_path.display(),
         vm.progress,
         vm.img_num
     );
-    let file_info_widget =
-        Paragraph::new(file_info_text).block(Block::default().title("Info").borders(Borders::ALL));
-    f.render_widget(file_info_widget, chunks[1]);
+    if !vm.labels.is_empty() {
+        file_info_text.push_str(&format!("  Labels: {}", vm.labels.join(", ")));
+    }
+    if vm.similar_num > 0 {
+        file_info_text.push_str(&format!("  Similar: {}", vm.similar_num));
+    }
+    if let Some(meta) = &vm.meta {
+        file_info_text.push_str(&format!("\nImage: {}", format_meta(meta)));
+    }
+    if let Some(caption) = &vm.caption {
+        file_info_text.push_str(&format!("\nCaption: {}", caption));
+    }
+    if let Some(original) = &vm.duplicate_of {
+        file_info_text.push_str(&format!("\nDuplicate of: {}", original.display()));
+    }
+    if !vm.suggestions.is_empty() {
+        let suggestions: Vec<String> = vm
+            .suggestions
+            .iter()
+            .map(|(label, prob)| format!("{} {:.0}%", label, prob * 100.0))
+            .collect();
+        file_info_text.push_str(&format!("\nSuggest: {}", suggestions.join(", ")));
+    }
+    let file_info_widget = Paragraph::new(file_info_text).block(vm.theme.block().title("Info"));
+    f.render_widget(file_info_widget, chunks[2]);
+
+    if thumbs_height > 0 {
+        draw_thumbs(f, vm, chunks[1]);
+    }
+}
+
+/// 枠や情報欄を出さず画像だけを描画し，直前の操作を少しの間だけ下端に重ねる
+fn draw_zen(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    vm.image_area = area;
+    if vm.is_loading() {
+        draw_loading(f, vm, area);
+    } else {
+        draw_img(f, vm, area);
+    }
+
+    let flashing = vm
+        .log_since
+        .is_some_and(|since| since.elapsed() < FLASH_DURATION);
+    if let Some(log) = vm.log.as_ref().filter(|_| flashing && area.height > 0) {
+        let line = Line::from(format!(" {} ", log_text(log)));
+        let width = u16::try_from(line.width())
+            .unwrap_or(u16::MAX)
+            .min(area.width);
+        let flash_area = Rect {
+            x: area.x + (area.width - width) / 2,
+            y: area.bottom() - 1,
+            width,
+            height: 1,
+        };
+        f.render_widget(Clear, flash_area);
+        f.render_widget(
+            Paragraph::new(line).style(Style::default().fg(vm.theme.done)),
+            flash_area,
+        );
+    }
+}
+
+/// ワーカーとデコードの列の状態を1行で描画
+fn draw_status_bar(f: &mut Frame, vm: &ViewModel, area: Rect) {
+    let status = &vm.worker_status;
+    let text = format!(
+        "Workers {}: {} reading, {} decoding, {} blocked  Queue: {} ready, {} left  {:.1} img/s",
+        status.workers,
+        status.reading,
+        status.decoding,
+        status.blocked,
+        status.ready,
+        status.left,
+        vm.decode_rate
+    );
+    let bar = Paragraph::new(text).style(Style::default().fg(vm.theme.dim));
+    f.render_widget(bar, area);
+}
+
+/// 表示中の画像を描画．デコードに失敗した画像は代わりに理由を表示する
+fn draw_img(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    if let Some(img) = vm.img() {
+        f.render_stateful_widget(StatefulImage::default(), area, img);
+        return;
+    }
+    let error = vm.error.as_deref().unwrap_or("no frames");
+    let text = Paragraph::new(format!(
+        "Cannot display this image\n\n{}\n\nIt can still be sorted with the keys.",
+        error
+    ))
+    .style(Style::default().fg(vm.theme.warning))
+    .alignment(Alignment::Center)
+    .wrap(Wrap { trim: true });
+    f.render_widget(text, centered_rect(80, 40, area));
+}
+
+/// デコード待ちのスピナーを描画
+fn draw_loading(f: &mut Frame, vm: &ViewModel, area: Rect) {
+    let Some(since) = vm.loading_since else {
+        return;
+    };
+    let frame = (since.elapsed().as_millis() / 100) as usize % SPINNER.len();
+    let status = if vm.waiting {
+        "Waiting for new images…"
+    } else {
+        "Decoding…"
+    };
+    let text = Paragraph::new(format!("{} {}", SPINNER[frame], status))
+        .style(Style::default().fg(vm.theme.warning))
+        .alignment(Alignment::Center);
+    f.render_widget(text, centered_rect(60, 20, area));
+}
+
+/// これから表示する画像のサムネイルを並べて描画
+fn draw_thumbs(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    let block = vm.theme.block().title("Next");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let upcoming = vm.upcoming.clone();
+    let cells = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            upcoming
+                .iter()
+                .map(|_| Constraint::Ratio(1, upcoming.len() as u32)),
+        )
+        .split(inner);
+    for (&idx, cell) in upcoming.iter().zip(cells.iter()) {
+        draw_thumb(f, vm, idx, *cell);
+    }
+}
+
+/// サムネイルを1枚描画．まだデコード中なら代わりの表示をする
+fn draw_thumb(f: &mut Frame, vm: &mut ViewModel, idx: usize, area: Rect) {
+    let dim = vm.theme.dim;
+    match vm.thumb(idx) {
+        Some(thumb) => f.render_stateful_widget(StatefulImage::default(), area, thumb),
+        None => f.render_widget(
+            Paragraph::new("…")
+                .style(Style::default().fg(dim))
+                .alignment(Alignment::Center),
+            area,
+        ),
+    }
+}
+
+/// 表示中の画像とこれから表示する画像を格子状に並べて描画
+fn draw_gallery(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    let page = vm.gallery_page.clone();
+    let has_current = !vm.is_loading() && !page.is_empty();
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
+        .split(area);
+    let cells: Vec<Rect> = rows
+        .iter()
+        .flat_map(|row| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Ratio(1, GALLERY_COLUMNS); GALLERY_COLUMNS as usize])
+                .split(*row)
+                .to_vec()
+        })
+        .collect();
+
+    for (i, (&idx, cell)) in page.iter().zip(cells.iter()).enumerate() {
+        let marked = vm.marked.contains(&idx);
+        let style = if i == vm.gallery_cursor {
+            Style::default().fg(vm.theme.cursor)
+        } else if marked {
+            Style::default().fg(vm.theme.marked)
+        } else {
+            Style::default().fg(vm.theme.border)
+        };
+        let title = if marked { "[x]" } else { "[ ]" };
+        let block = vm.theme.block().title(title).border_style(style);
+        let inner = block.inner(*cell);
+        f.render_widget(block, *cell);
+        if i == 0 && has_current {
+            // 先頭は表示中の画像
+            draw_img(f, vm, inner);
+        } else {
+            draw_thumb(f, vm, idx, inner);
+        }
+    }
+}
+
+/// 明るさのヒストグラムと代表色を描画
+fn draw_colors(f: &mut Frame, theme: &Theme, colors: &ColorStats, area: Rect) {
+    let block = theme.block().title("Colors");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)])
+        .split(inner);
+
+    // 表示する幅に合わせて区間をまとめる
+    let bins = colors.histogram.len();
+    let columns = usize::from(rows[0].width).clamp(1, bins);
+    let data: Vec<u64> = (0..columns)
+        .map(|i| {
+            colors.histogram[i * bins / columns..(i + 1) * bins / columns]
+                .iter()
+                .sum()
+        })
+        .collect();
+    let histogram = Sparkline::default()
+        .data(&data)
+        .style(Style::default().fg(theme.histogram));
+    f.render_widget(histogram, rows[0]);
+
+    let swatches: Vec<Span> = colors
+        .dominant
+        .iter()
+        .map(|&[r, g, b]| Span::styled("███ ", Style::default().fg(Color::Rgb(r, g, b))))
+        .collect();
+    f.render_widget(Paragraph::new(Line::from(swatches)), rows[1]);
+}
+
+/// 画像の大きさや形式を1行にまとめる
+fn format_meta(meta: &ImgMeta) -> String {
+    let format = meta
+        .format
+        .and_then(|format| format.extensions_str().first())
+        .map_or("?".to_string(), |ext| ext.to_uppercase());
+    let alpha = if meta.alpha { "  alpha" } else { "" };
+    format!(
+        "{}x{}  {}  {}-bit{}  {}",
+        meta.width,
+        meta.height,
+        format,
+        meta.bit_depth,
+        alpha,
+        format_bytes(meta.bytes)
+    )
+}
+
+/// バイト数を読みやすい単位にする
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// 2枚の画像を左右に並べて描画
+fn draw_compare(f: &mut Frame, vm: &mut ViewModel, area: Rect) {
+    let Some(compare) = &mut vm.compare else {
+        return;
+    };
+    let cells = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
+        .split(area);
+    let sides = [
+        ("[←] keep", Some(&mut compare.left)),
+        ("[→] keep", compare.right.as_mut()),
+    ];
+    for ((key, img), cell) in sides.into_iter().zip(cells.iter()) {
+        let title = match &img {
+            Some(img) => format!(
+                "{} {}",
+                key,
+                img.path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            None => "No image to compare".to_string(),
+        };
+        let block = vm.theme.block().title(title);
+        let inner = block.inner(*cell);
+        f.render_widget(block, *cell);
+        if let Some(img) = img {
+            f.render_stateful_widget(StatefulImage::default(), inner, &mut img.state);
+        }
+    }
 }
 
 /// 情報エリアを描画
 fn draw_info_panel(f: &mut Frame, vm: &ViewModel, area: Rect) {
+    // キーバインド
+    let mut key_items = keybind_items(vm);
+    key_items.push(ListItem::new("---"));
+    key_items.extend(control_items(vm));
+
+    // 分類先が増えても全て表示できるよう一覧の長さに合わせる
+    let keys_height = u16::try_from(key_items.len() + 2).unwrap_or(u16::MAX);
+    let colors = vm.meta.as_ref().map(|meta| &meta.colors);
+    let colors_height = if colors.is_some() && !vm.is_fin {
+        COLORS_HEIGHT
+    } else {
+        0
+    };
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(10), Constraint::Min(0)])
+        .constraints([
+            Constraint::Length(keys_height),
+            Constraint::Length(colors_height),
+            Constraint::Min(0),
+        ])
         .split(area);
 
-    // キーバインド
-    let mut key_items: Vec<ListItem> = vm
-        .keybind
+    let keys_widget = List::new(key_items)
+        .block(vm.theme.block().title("Keybinds"))
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD));
+    f.render_widget(keys_widget, chunks[0]);
+
+    if let Some(colors) = colors.filter(|_| colors_height > 0) {
+        draw_colors(f, &vm.theme, colors, chunks[1]);
+    }
+
+    draw_history(f, vm, chunks[2]);
+}
+
+/// 操作の履歴を新しいものが下になるよう描画
+fn draw_history(f: &mut Frame, vm: &ViewModel, area: Rect) {
+    let height = usize::from(area.height.saturating_sub(2));
+    let end = vm.history.len().saturating_sub(vm.history_scroll);
+    let items: Vec<ListItem> = vm.history[end.saturating_sub(height)..end]
         .iter()
-        .map(|(key, folder)| {
-            let text = format!("[{}] -> {}", key, folder.display());
-            let style = if folder == Path::new("skip") {
-                Style::default().fg(Color::Yellow)
-            } else {
-                Style::default().fg(Color::Cyan)
+        .rev()
+        .enumerate()
+        .map(|(i, log)| {
+            let mut style = match log {
+                AppLog::Error(_) | AppLog::Missing(_) => Style::default().fg(vm.theme.warning),
+                _ => Style::default(),
             };
-            ListItem::new(text).style(style)
+            // 遡っているときは一番下の項目をUで取り消す対象として目立たせる
+            if i == 0 && vm.history_scroll > 0 {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            ListItem::new(log_text(log)).style(style)
         })
+        .rev()
         .collect();
-    key_items.push(ListItem::new("---"));
-    key_items.push(ListItem::new("[q] -> exit").style(Style::default().fg(Color::Red)));
+    let title = if vm.history_scroll > 0 {
+        format!("History (-{}, U: undo this)", vm.history_scroll)
+    } else {
+        "History (PgUp/PgDn)".to_string()
+    };
+    let history = List::new(items).block(vm.theme.block().title(title));
+    f.render_widget(history, area);
+}
 
-    let keys_widget = List::new(key_items)
-        .block(Block::default().title("Keybinds").borders(Borders::ALL))
-        .highlight_style(Style::default().add_modifier(Modifier::BOLD));
-    f.render_widget(keys_widget, chunks[0]);
+/// 直前の操作の説明
+fn log_text(log: &AppLog) -> String {
+    match log {
+        AppLog::MoveSuccess(file, dest) => {
+            format!("{} to {}", file.display(), dest.display())
+        }
+        AppLog::Copy(file, dest) => format!("Copy {} to {}", file.display(), dest.display()),
+        AppLog::Skip(file) => format!("Skip {}", file.display()),
+        AppLog::Trash(file) => format!("Trash {}", file.display()),
+        AppLog::Defer(file) => format!("Defer {} to the end", file.display()),
+        AppLog::Undo(file) => format!("Undo {}", file.display()),
+        AppLog::Redo(file) => format!("Redo {}", file.display()),
+        AppLog::Staged(file, dest) => {
+            format!("Stage {} to {}", file.display(), dest.display())
+        }
+        AppLog::Commit(num) => format!("Applied {} staged actions", num),
+        AppLog::NewDest(key, dest) => format!("Bind [{}] to {}", key, dest.display()),
+        AppLog::Flag(file) => format!("Flag {}", file.display()),
+        AppLog::Rules(num) => format!("Sorted {} images by rules", num),
+        AppLog::Missing(file) => format!("{} no longer exists; skipped", file.display()),
+        AppLog::Rotate(file, true) => format!("Rotate {} right", file.display()),
+        AppLog::Rotate(file, false) => format!("Rotate {} left", file.display()),
+        AppLog::FilterCleared => "No more images match the filter; showing all".to_string(),
+        AppLog::Error(error) => format!("Error: {}", error),
+        AppLog::Label(file, label, true) => format!("Label {} as {}", file.display(), label),
+        AppLog::Label(file, label, false) => {
+            format!("Unlabel {} from {}", file.display(), label)
+        }
+    }
+}
 
-    // ログ
-    if let Some(log) = &vm.log {
-        let log_widget = Paragraph::new(match log {
-            AppLog::MoveSuccess(file, dest) => {
-                format!("{} to {}", file.display(), dest.display())
+/// 分類先のキーバインドの一覧
+fn keybind_items(vm: &ViewModel) -> Vec<ListItem<'static>> {
+    let mut keybinds: Vec<_> = vm
+        .keybind
+        .iter()
+        .map(|(key, dest)| (key.to_string(), dest))
+        .collect();
+    keybinds.sort_by(|a, b| a.0.cmp(&b.0));
+    keybinds
+        .into_iter()
+        .map(|(key, dest)| {
+            let name = dest.name();
+            // タグ付けモードではラベルごとに数える
+            let count = vm
+                .counts
+                .get(dest.path.as_path())
+                .or_else(|| vm.counts.get(Path::new(&name)))
+                .copied()
+                .unwrap_or(0);
+            let mut text = format!("[{}] -> {} ({})", key, name, count);
+            if let Some(&free) = vm.free_space.get(dest.path.as_path()) {
+                text.push_str(&format!(" {} free", format_bytes(free)));
             }
-            AppLog::Skip(file) => format!("Skip {}", file.display()),
+            let style = Style::default().fg(dest_color(&vm.theme, dest));
+            ListItem::new(text).style(style)
         })
-        .block(Block::default().title("Last Action").borders(Borders::ALL))
-        .wrap(Wrap { trim: true });
-        f.render_widget(log_widget, chunks[1]);
+        .collect()
+}
+
+/// 分類先のキーの色
+fn dest_color(theme: &Theme, dest: &Dest) -> Color {
+    if let Some(color) = dest.color {
+        color
+    } else if ["skip", "ignore", "defer"]
+        .iter()
+        .any(|name| dest.path == Path::new(name))
+    {
+        theme.skip
+    } else if dest.path == Path::new("trash") {
+        theme.trash
+    } else {
+        theme.dest
+    }
+}
+
+/// 分類以外の操作キーの一覧
+fn control_items(vm: &ViewModel) -> Vec<ListItem<'static>> {
+    let control_style = Style::default().fg(vm.theme.control);
+    let mut items = vec![
+        ListItem::new("[u] -> undo").style(control_style),
+        ListItem::new("[Ctrl+r] -> redo").style(control_style),
+        ListItem::new("[f] -> flag for review").style(control_style),
+        ListItem::new("[c] -> caption").style(control_style),
+        ListItem::new("[o] -> open in viewer").style(control_style),
+        ListItem::new("[n] -> new destination").style(control_style),
+        ListItem::new("[Enter] -> search destinations").style(control_style),
+        ListItem::new("[:n or /name] -> go to image").style(control_style),
+        ListItem::new("[F] -> filter by file name").style(control_style),
+        ListItem::new("[S] -> shuffle").style(control_style),
+        ListItem::new("[P+key] -> peek into destination").style(control_style),
+        ListItem::new("[PgUp/PgDn] -> scroll history").style(control_style),
+        ListItem::new("[U] -> undo selected history entry").style(control_style),
+        ListItem::new("[g] -> gallery (Space: mark)").style(control_style),
+        ListItem::new("[Tab] -> compare with next").style(control_style),
+        ListItem::new("[←/→] -> prev/next").style(control_style),
+        ListItem::new("[+/-/0] -> zoom in/out/reset").style(control_style),
+        ListItem::new("[[/]] -> rotate left/right").style(control_style),
+        ListItem::new("[{/}] -> rotate file left/right").style(control_style),
+        ListItem::new("[Z] -> image only").style(control_style),
+        ListItem::new("[B] -> low bandwidth").style(control_style),
+        ListItem::new("[R] -> sticky repeat").style(control_style),
+        ListItem::new(format!("[{}+key] -> copy and stay", vm.copy_modifier)).style(control_style),
+        ListItem::new("[number+key] -> apply to next images").style(control_style),
+    ];
+    if vm.text.is_some() {
+        items.push(ListItem::new("[T] -> toggle text").style(control_style));
+    }
+    if vm.similar_num > 0 {
+        items.push(ListItem::new("[k] -> keep this, send similar away").style(control_style));
     }
+    if vm.is_paged() {
+        items.push(ListItem::new("[,/.] -> prev/next page").style(control_style));
+    }
+    if vm.is_animated() {
+        let text = if vm.is_paused {
+            "[Space] -> play"
+        } else {
+            "[Space] -> pause"
+        };
+        items.push(ListItem::new(text).style(control_style));
+    }
+    items.push(ListItem::new("[?] -> help").style(control_style));
+    items.push(ListItem::new("[q] -> exit").style(Style::default().fg(vm.theme.quit)));
+    items
+}
+
+/// 全てのキーバインドを一覧するヘルプを描画
+fn draw_help_popup(f: &mut Frame, vm: &ViewModel, area: Rect) {
+    let area = centered_rect(80, 80, area);
+    let mut items = keybind_items(vm);
+    items.push(ListItem::new("---"));
+    items.extend(control_items(vm));
+    items.push(ListItem::new("---"));
+    items.push(ListItem::new("[?/Esc] -> close help"));
+
+    let help_widget = List::new(items).block(vm.theme.block().title("Help"));
+    f.render_widget(Clear, area);
+    f.render_widget(help_widget, area);
 }
 
 /// 指定された矩形の中央に、指定されたパーセンテージの矩形を生成する
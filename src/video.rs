@@ -0,0 +1,77 @@
+use anyhow::Result;
+use image::DynamicImage;
+
+use std::path::Path;
+
+/// 途中のフレームを表示する動画の拡張子
+pub const EXTENSIONS: [&str; 6] = ["mp4", "mov", "m4v", "mkv", "webm", "avi"];
+
+/// 拡張子が動画のファイルか
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            EXTENSIONS
+                .iter()
+                .any(|video| ext.eq_ignore_ascii_case(video))
+        })
+}
+
+/// 動画の真ん中のフレームをffmpegで取り出す．長さはffprobeで調べる
+#[cfg(feature = "video")]
+pub fn frame(path: &Path) -> Result<DynamicImage> {
+    use anyhow::{anyhow, Context};
+    use image::ImageFormat;
+    use std::process::{Command, Stdio};
+
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("cannot run ffprobe")?;
+    // 長さが分からない動画は最初のフレームにする
+    let duration: f64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap_or(0.0);
+    let output = Command::new("ffmpeg")
+        .args([
+            "-v",
+            "error",
+            "-ss",
+            &format!("{:.3}", duration / 2.0),
+            "-i",
+        ])
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("cannot run ffmpeg")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffmpeg exited with {} for {}",
+            output.status,
+            path.display()
+        ));
+    }
+    image::load_from_memory_with_format(&output.stdout, ImageFormat::Png)
+        .with_context(|| format!("cannot decode frame of {}", path.display()))
+}
+
+/// video機能なしでビルドした場合は取り出せない
+#[cfg(not(feature = "video"))]
+pub fn frame(path: &Path) -> Result<DynamicImage> {
+    anyhow::bail!(
+        "shotclassif was built without the video feature: {}",
+        path.display()
+    )
+}
+
+/// video機能を有効にしてビルドしたか
+pub fn is_available() -> bool {
+    cfg!(feature = "video")
+}
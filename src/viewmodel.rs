@@ -1,55 +1,1151 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
-use ratatui_image::protocol::StatefulProtocol;
+use image::DynamicImage;
+use ratatui::layout::Rect;
+use ratatui_image::{protocol::StatefulProtocol, FontSize};
+
+use crate::{
+    app::{App, AppLog, CollisionPolicy, CompareImg, Frame, ImgInfo, ImgMeta, Peek, WorkerStatus},
+    config::{Dest, KeyBind, Modifier},
+    suggest::Suggestion,
+    summary::Summary,
+    theme::Theme,
+    ui::PanelLayout,
+};
+// 拡大率の上限
+const MAX_ZOOM: f64 = 16.0;
+// デコードの速さを測り直す間隔
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+// 連続入力モードで同じキーを続けて押したとみなす間隔
+const STICKY_WINDOW: Duration = Duration::from_secs(1);
 
-use crate::app::{App, AppLog};
 pub struct ViewModel {
-    // 画像
-    pub img: StatefulProtocol,
+    // 画像 (アニメーションの場合は全フレーム)
+    frames: Vec<Frame>,
+    // 表示中のフレーム．複数ページの画像では表示中のページ
+    frame_idx: usize,
+    // 複数ページの画像のページ数．最初のページ以外はフレームを持たず，表示するときに読む
+    pages: usize,
+    frame_since: Instant,
+    // アニメーションを一時停止しているか
+    pub is_paused: bool,
+    // 次の画像のデコードを待ち始めた時刻 (待っていなければNone)
+    pub loading_since: Option<Instant>,
+    // 拡大率 (1.0で全体を表示)
+    pub zoom: f64,
+    // 表示範囲の中心 (画像に対する割合)
+    zoom_center: (f64, f64),
+    // 表示の回転 (時計回りに90度単位)
+    pub rotation: u8,
+    // 拡大や回転のために読み込んだ画像 (回転済み)
+    view_source: Option<DynamicImage>,
+    // 拡大や回転をした画像
+    transformed: Option<StatefulProtocol>,
+    // 先読みした画像のサムネイル
+    thumbs: BTreeMap<usize, StatefulProtocol>,
+    // これから表示する画像の番号
+    pub upcoming: Vec<usize>,
+    // ギャラリー表示中か
+    pub gallery: bool,
+    // ギャラリーに並べる画像の番号 (デコード待ちでなければ先頭は表示中の画像)
+    pub gallery_page: Vec<usize>,
+    // ギャラリーで選択している位置
+    pub gallery_cursor: usize,
+    // ギャラリーで印を付けた画像の番号
+    pub marked: BTreeSet<usize>,
+    // 2枚の画像を並べた比較表示 (閉じていればNone)
+    pub compare: Option<Compare>,
+    // 覗いている分類先の最近の画像
+    pub peek: Option<Peek>,
+    // 覗く分類先のキーを待っているか
+    pub peek_pending: bool,
+    // 前回描画したときの画像表示エリア
+    pub image_area: Rect,
     // 画像情報
     pub img_path: PathBuf,
     pub progress: usize,
+    // 分類先ごとの画像の数
+    pub counts: HashMap<PathBuf, usize>,
+    // 分類先ごとの置く先の空き容量
+    pub free_space: HashMap<PathBuf, u64>,
     pub img_num: usize,
+    // 監視モードで新しい画像を待っているか
+    pub waiting: bool,
+    // ワーカーとデコードの列の状態
+    pub worker_status: WorkerStatus,
+    // 1秒あたりにデコードした画像の数
+    pub decode_rate: f64,
+    // 前回デコードの速さを測った時刻とその時点のデコード済みの数
+    rate_since: (Instant, usize),
     // キーバインド
-    pub keybind: HashMap<char, PathBuf>,
+    pub keybind: HashMap<KeyBind, Dest>,
+    // UIの色
+    pub theme: Theme,
+    // 画像と情報欄の並べ方
+    pub layout: PanelLayout,
+    // 画面のうち画像を表示する領域の割合 (%)
+    pub image_percent: u16,
+    // 分類先のキーと一緒に押すとコピーだけする修飾キー
+    pub copy_modifier: Modifier,
+    // 端末の文字の大きさ (ピクセル)
+    pub font_size: FontSize,
+    // 解像度と色数を落として表示しているか
+    pub low_bandwidth: bool,
     // ログ
     pub log: Option<AppLog>,
+    // logが変わった時刻 (画像だけの表示で一時的に知らせる)
+    pub log_since: Option<Instant>,
+    // このセッションで行った操作の履歴
+    pub history: Vec<AppLog>,
+    // 履歴を新しいほうから何件遡って表示しているか
+    pub history_scroll: usize,
     // 終了画面か
     pub is_fin: bool,
+    // 終了時の集計
+    pub summary: Option<Summary>,
+    // 反映を保留している操作 (元の場所, 分類先)
+    pub staged: Vec<(PathBuf, PathBuf)>,
+    // 移動先で衝突している同名ファイル
+    // 衝突した移動先のファイルと，中身が同じかどうか
+    pub conflict: Option<(PathBuf, bool)>,
+    // 送る前に確認する分類先のキーと名前
+    pub confirm: Option<(KeyBind, String)>,
+    // ファイルの操作に失敗してやり直すかを尋ねている分類先のキー，まとめて分類する残りの枚数とエラー
+    pub retry: Option<(KeyBind, Option<usize>, String)>,
+    // 終了する前の確認中なら残っている画像の枚数と，反映していない保留中の操作の数
+    pub quit_confirm: Option<(usize, usize)>,
+    // 次の分類先をまとめて適用する画像の枚数
+    pub count: Option<usize>,
+    // 同じキーを続けて押すと確認なしで同じ分類先に送る連続入力モードか
+    pub sticky: bool,
+    // 最後に分類に使ったキーと押した時刻
+    pub last_key: Option<(KeyBind, Instant)>,
+    // ヘルプを表示しているか
+    pub show_help: bool,
+    // 入力中のテキスト
+    pub prompt: Option<(Prompt, String)>,
+    // 分類先の一覧で選んでいる位置
+    pub picker_cursor: usize,
+    // 表示する画像を絞り込んでいるファイル名のパターン
+    pub filter: Option<String>,
+    // シャッフルした順に表示していれば，その乱数の種
+    pub shuffled: Option<u64>,
+    // 表示中の画像の説明文
+    pub caption: Option<String>,
+    // 列の中で先にある，中身が同じ画像
+    pub duplicate_of: Option<PathBuf>,
+    // 表示中の画像と似ている，まだ決定していない画像の数
+    pub similar_num: usize,
+    // モデルが出した表示中の画像の分類の候補
+    pub suggestions: Vec<Suggestion>,
+    // OCRで表示中の画像から読み取った文字列
+    pub text: Option<String>,
+    // 表示中の画像の大きさや形式などの情報
+    pub meta: Option<ImgMeta>,
+    // 表示中の画像のデコードに失敗した理由
+    pub error: Option<String>,
+    // 読み取った文字列の欄を表示するか
+    pub show_text: bool,
+    // 枠や情報欄を出さず画像だけを画面いっぱいに表示するか
+    pub zen: bool,
+    // タグ付けモードで表示中の画像に付けたラベル
+    pub labels: Vec<String>,
+}
+
+/// 2枚の画像を並べて，どちらを残すかを選ぶ比較表示
+pub struct Compare {
+    pub left: CompareImg,
+    // 比べる画像がなければNone
+    pub right: Option<CompareImg>,
+    // 残した画像に次の画像を並べ続けるか (ギャラリーで選んだ組ではfalse)
+    follow: bool,
+}
+
+/// ファイルの操作に失敗したときの選択
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RetryChoice {
+    Retry,
+    // 分類せずに次の画像に進む
+    Skip,
+    Abort,
+}
+
+/// テキストの入力欄の種類
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    // 新しい分類先
+    NewDest,
+    // 画像の説明文
+    Caption,
+    // 分類先を名前で絞り込んで選ぶ
+    PickDest,
+    // 移る先の画像の番号 (:42) かファイル名の一部 (/name)
+    Goto,
+    // 表示する画像を絞り込むファイル名のパターン
+    Filter,
 }
 
 // modelからのfrom
 impl ViewModel {
     pub fn new_from_app(app: &mut App) -> Result<Self> {
-        let img_info = app.get_img()?;
         let app_info = app.get_app_info();
-        Ok(ViewModel {
-            img: img_info.state,
-            img_path: img_info.path,
-            progress: 0,
+        let mut vm = ViewModel {
+            frames: Vec::new(),
+            frame_idx: 0,
+            pages: 1,
+            frame_since: Instant::now(),
+            is_paused: false,
+            loading_since: Some(Instant::now()),
+            zoom: 1.0,
+            zoom_center: (0.5, 0.5),
+            rotation: 0,
+            view_source: None,
+            transformed: None,
+            thumbs: BTreeMap::new(),
+            upcoming: Vec::new(),
+            gallery: false,
+            gallery_page: Vec::new(),
+            gallery_cursor: 0,
+            marked: BTreeSet::new(),
+            compare: None,
+            peek: None,
+            peek_pending: false,
+            image_area: Rect::default(),
+            img_path: PathBuf::new(),
+            progress: app_info.done_num,
+            counts: app.counts(),
+            free_space: app.free_space(),
             img_num: app_info.img_num,
+            waiting: false,
             keybind: app_info.keybind,
+            theme: app_info.theme,
+            layout: app_info.layout,
+            image_percent: app_info.image_percent,
+            copy_modifier: app_info.copy_modifier,
+            font_size: app.font_size(),
+            low_bandwidth: app.is_low_bandwidth(),
+            worker_status: WorkerStatus::default(),
+            decode_rate: 0.0,
+            rate_since: (Instant::now(), 0),
             log: None,
+            log_since: None,
+            history: Vec::new(),
+            history_scroll: 0,
             is_fin: false,
-        })
+            summary: None,
+            staged: Vec::new(),
+            conflict: None,
+            confirm: None,
+            quit_confirm: None,
+            retry: None,
+            count: None,
+            sticky: app_info.sticky,
+            last_key: None,
+            show_help: false,
+            prompt: None,
+            picker_cursor: 0,
+            filter: None,
+            shuffled: None,
+            caption: None,
+            duplicate_of: None,
+            similar_num: 0,
+            suggestions: Vec::new(),
+            text: None,
+            meta: None,
+            error: None,
+            show_text: true,
+            zen: false,
+            labels: Vec::new(),
+        };
+        if let Some(img_info) = app.get_img()? {
+            vm.set_img(img_info);
+        }
+        vm.sync(app);
+        Ok(vm)
+    }
+
+    /// 次の画像のデコードを待っているか
+    pub fn is_loading(&self) -> bool {
+        self.loading_since.is_some()
+    }
+
+    /// 表示中のフレーム．デコードに失敗した画像ではNone
+    pub fn img(&mut self) -> Option<&mut StatefulProtocol> {
+        match &mut self.transformed {
+            Some(transformed) => Some(transformed),
+            None => self
+                .frames
+                .get_mut(self.frame_idx)
+                .map(|frame| &mut frame.state),
+        }
+    }
+
+    /// 先読みが済んでいればその画像のサムネイル
+    pub fn thumb(&mut self, idx: usize) -> Option<&mut StatefulProtocol> {
+        self.thumbs.get_mut(&idx)
+    }
+
+    /// 拡大表示中か
+    pub fn is_zoomed(&self) -> bool {
+        self.zoom > 1.0
+    }
+
+    /// 拡大率をfactor倍にする
+    pub fn on_zoom(&mut self, app: &App, factor: f64) -> Result<()> {
+        self.zoom = (self.zoom * factor).clamp(1.0, MAX_ZOOM);
+        if !self.is_zoomed() {
+            self.zoom_center = (0.5, 0.5);
+        }
+        self.update_view(app)
+    }
+
+    /// 全体表示に戻す
+    pub fn reset_zoom(&mut self, app: &App) -> Result<()> {
+        self.zoom = 1.0;
+        self.zoom_center = (0.5, 0.5);
+        self.update_view(app)
+    }
+
+    /// 表示範囲に対する割合で表示位置を動かす
+    pub fn on_pan(&mut self, app: &App, dx: f64, dy: f64) -> Result<()> {
+        if !self.is_zoomed() {
+            return Ok(());
+        }
+        self.zoom_center.0 += dx / self.zoom;
+        self.zoom_center.1 += dy / self.zoom;
+        self.update_view(app)
+    }
+
+    /// マウスでドラッグしたセル数だけ表示位置を動かす
+    pub fn on_drag(&mut self, app: &App, dx: i32, dy: i32) -> Result<()> {
+        let width = f64::from(self.image_area.width.max(1));
+        let height = f64::from(self.image_area.height.max(1));
+        // 画像をつかんで動かすので表示位置は逆向きに動く
+        self.on_pan(app, -f64::from(dx) / width, -f64::from(dy) / height)
+    }
+
+    /// 端末の大きさが変わったとき，文字の大きさも変わっていれば表示中の画像を作り直す
+    pub fn on_resize(&mut self, app: &mut App) -> Result<()> {
+        if !app.on_resize() {
+            return Ok(());
+        }
+        self.font_size = app.font_size();
+        self.reload_img(app)
+    }
+
+    /// 低帯域モードを切り替え，表示中の画像を作り直す
+    pub fn toggle_low_bandwidth(&mut self, app: &mut App) -> Result<()> {
+        app.toggle_low_bandwidth();
+        self.low_bandwidth = app.is_low_bandwidth();
+        self.reload_img(app)
+    }
+
+    /// まだ表示していない画像をシャッフルした順に表示するかを切り替える
+    pub fn toggle_shuffle(&mut self, app: &mut App) {
+        app.toggle_shuffle();
+        self.shuffled = app.is_shuffled().then(|| app.seed());
+    }
+
+    /// 失敗した操作を履歴に残す
+    pub fn on_error(&mut self, app: &mut App, error: anyhow::Error) {
+        app.push_log(AppLog::Error(format!("{:#}", error)));
+        self.sync(app);
+    }
+
+    /// 操作の履歴を遡る (upがfalseなら新しいほうに戻る)
+    pub fn scroll_history(&mut self, up: bool, lines: usize) {
+        self.history_scroll = if up {
+            (self.history_scroll + lines).min(self.history.len().saturating_sub(1))
+        } else {
+            self.history_scroll.saturating_sub(lines)
+        };
+    }
+
+    /// 連続入力モードを切り替える
+    pub fn toggle_sticky(&mut self) {
+        self.sticky = !self.sticky;
+        self.last_key = None;
+    }
+
+    /// 連続入力モードで，直前に分類に使ったキーを続けて押したか
+    pub fn is_sticky_repeat(&self, key: KeyBind) -> bool {
+        self.sticky
+            && matches!(self.last_key, Some((last, at)) if last == key && at.elapsed() < STICKY_WINDOW)
+    }
+
+    /// 表示中の画像を今の設定で作り直す
+    fn reload_img(&mut self, app: &App) -> Result<()> {
+        if self.is_loading() || self.is_fin {
+            return Ok(());
+        }
+        let (zoom, zoom_center, rotation) = (self.zoom, self.zoom_center, self.rotation);
+        let page = self.frame_idx;
+        self.set_img(app.current_img()?);
+        // 拡大や回転，ページはそのまま保つ
+        if self.is_paged() {
+            self.frame_idx = page.min(self.pages - 1);
+        }
+        (self.zoom, self.zoom_center, self.rotation) = (zoom, zoom_center, rotation);
+        self.rotate_source(app)?;
+        self.update_view(app)
+    }
+
+    /// 読み込み直した画像を今の回転に合わせる
+    fn rotate_source(&mut self, app: &App) -> Result<()> {
+        if self.rotation != 0 {
+            let source = self.take_source(app)?;
+            self.view_source = Some(match self.rotation {
+                1 => source.rotate90(),
+                2 => source.rotate180(),
+                _ => source.rotate270(),
+            });
+        }
+        Ok(())
+    }
+
+    /// 複数ページの画像のページを切り替える．拡大や回転はそのまま保つ
+    pub fn on_page(&mut self, app: &App, forward: bool) -> Result<()> {
+        if self.is_loading() || !self.is_paged() {
+            return Ok(());
+        }
+        self.frame_idx = if forward {
+            (self.frame_idx + 1).min(self.pages - 1)
+        } else {
+            self.frame_idx.saturating_sub(1)
+        };
+        self.view_source = None;
+        self.rotate_source(app)?;
+        self.update_view(app)
+    }
+
+    /// 表示を90度回転する
+    pub fn on_rotate(&mut self, app: &App, clockwise: bool) -> Result<()> {
+        if self.is_loading() {
+            return Ok(());
+        }
+        let source = self.take_source(app)?;
+        self.view_source = Some(if clockwise {
+            source.rotate90()
+        } else {
+            source.rotate270()
+        });
+        self.rotation = if clockwise {
+            (self.rotation + 1) % 4
+        } else {
+            (self.rotation + 3) % 4
+        };
+        self.update_view(app)
+    }
+
+    /// 表示中の画像のファイルを90度回転し，読み込み直す
+    pub fn on_rotate_file(&mut self, app: &mut App, clockwise: bool) -> Result<()> {
+        if self.is_loading() || self.is_fin {
+            return Ok(());
+        }
+        app.rotate_file(clockwise)?;
+        self.sync(app);
+        self.reload_img(app)
+    }
+
+    /// 拡大や回転のために読み込んだ画像を取り出す
+    fn take_source(&mut self, app: &App) -> Result<DynamicImage> {
+        match self.view_source.take() {
+            Some(source) => Ok(source),
+            None => app.load_page(&self.img_path, self.frame_idx),
+        }
+    }
+
+    /// 拡大率，表示位置，回転に合わせて表示する画像を作り直す
+    fn update_view(&mut self, app: &App) -> Result<()> {
+        let later_page = self.is_paged() && self.frame_idx > 0;
+        if self.is_loading() || (!self.is_zoomed() && self.rotation == 0 && !later_page) {
+            self.transformed = None;
+            return Ok(());
+        }
+        let source = self.take_source(app)?;
+
+        // 画像の端からはみ出さないよう中心を制限する
+        let half = 0.5 / self.zoom;
+        self.zoom_center.0 = self.zoom_center.0.clamp(half, 1.0 - half);
+        self.zoom_center.1 = self.zoom_center.1.clamp(half, 1.0 - half);
+
+        let width = f64::from(source.width());
+        let height = f64::from(source.height());
+        let crop_width = (width / self.zoom).max(1.0);
+        let crop_height = (height / self.zoom).max(1.0);
+        let x = (self.zoom_center.0 * width - crop_width / 2.0).max(0.0);
+        let y = (self.zoom_center.1 * height - crop_height / 2.0).max(0.0);
+        let cropped = source.crop_imm(x as u32, y as u32, crop_width as u32, crop_height as u32);
+        self.transformed = Some(app.new_protocol(cropped));
+        self.view_source = Some(source);
+        Ok(())
+    }
+
+    /// アニメーションしているか
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1 && !self.is_paged()
+    }
+
+    /// 複数ページの画像か．ページは自動では進めない
+    pub fn is_paged(&self) -> bool {
+        self.pages > 1
+    }
+
+    /// 表示中のページと全ページ数
+    pub fn page(&self) -> Option<(usize, usize)> {
+        self.is_paged().then(|| (self.frame_idx + 1, self.pages))
+    }
+
+    /// アニメーションを一時停止・再開する
+    pub fn toggle_pause(&mut self) {
+        self.is_paused = !self.is_paused;
+        self.frame_since = Instant::now();
+    }
+
+    /// デコードを待っている画像が届いていれば表示し，時間が経っていれば次のフレームに進める
+    pub fn tick(&mut self, app: &mut App) {
+        app.prefetch();
+        self.img_num = app.img_num();
+        self.waiting = app.is_waiting();
+        self.similar_num = app.similar_num();
+        self.worker_status = app.worker_status();
+        let (since, decoded) = self.rate_since;
+        let elapsed = since.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            let num = self.worker_status.decoded.saturating_sub(decoded);
+            self.decode_rate = num as f64 / elapsed.as_secs_f64();
+            self.rate_since = (Instant::now(), self.worker_status.decoded);
+        }
+        self.thumbs.extend(app.take_thumbs());
+        if self.is_loading() {
+            match app.poll_img() {
+                Ok(Some(img_info)) => {
+                    self.set_img(img_info);
+                    self.sync(app);
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    self.loading_since = None;
+                    self.finish(app);
+                    self.sync(app);
+                }
+            }
+        }
+        // 表示済みの画像のサムネイルは捨てる
+        self.upcoming = app.upcoming();
+        let upcoming = &self.upcoming;
+        self.thumbs.retain(|idx, _| upcoming.contains(idx));
+        let current = app.current_idx().filter(|_| !self.is_loading());
+        self.gallery_page = current.into_iter().chain(self.upcoming.clone()).collect();
+        let page = &self.gallery_page;
+        self.marked.retain(|idx| page.contains(idx));
+        self.gallery_cursor = self.gallery_cursor.min(page.len().saturating_sub(1));
+        if self.is_paused || !self.is_animated() {
+            return;
+        }
+        if self.frame_since.elapsed() >= self.frames[self.frame_idx].delay {
+            self.frame_idx = (self.frame_idx + 1) % self.frames.len();
+            self.frame_since = Instant::now();
+        }
+    }
+
+    /// 終了画面に切り替える
+    fn finish(&mut self, app: &App) {
+        let summary = app.summary();
+        // 書き出しに失敗しても画面には表示する
+        let _ = app.write_summary(&summary);
+        let _ = app.write_manifest();
+        self.summary = Some(summary);
+        self.is_fin = true;
+    }
+
+    /// 操作後のAppの状態を反映する
+    fn sync(&mut self, app: &App) {
+        self.progress = app.done_num();
+        self.counts = app.counts();
+        self.free_space = app.free_space();
+        self.staged = app.staged();
+        self.caption = app.caption();
+        self.labels = app.labels();
+        self.filter = app.filter();
+        if app.logs().len() != self.history.len() {
+            self.log = app.log.clone();
+            self.log_since = Some(Instant::now());
+            let new = &app.logs()[self.history.len()..];
+            // 遡っているときは新しい項目が増えても同じ項目を選んだままにする
+            if self.history_scroll > 0 {
+                self.history_scroll += new.len();
+            }
+            self.history.extend_from_slice(new);
+        }
+    }
+
+    fn set_img(&mut self, img_info: ImgInfo) {
+        self.zoom = 1.0;
+        self.zoom_center = (0.5, 0.5);
+        self.rotation = 0;
+        self.view_source = None;
+        self.transformed = None;
+        self.frames = img_info.frames;
+        self.frame_idx = 0;
+        self.pages = img_info.meta.as_ref().map_or(1, |meta| meta.pages);
+        self.frame_since = Instant::now();
+        self.img_path = img_info.path;
+        self.caption = img_info.caption;
+        self.duplicate_of = img_info.duplicate_of;
+        self.suggestions = img_info.suggestions;
+        self.text = img_info.text;
+        self.meta = img_info.meta;
+        self.error = img_info.error;
+        self.labels = img_info.labels;
+        self.loading_since = None;
+    }
+
+    /// 次の画像のデコードを待ち始める
+    fn start_loading(&mut self) {
+        self.loading_since.get_or_insert_with(Instant::now);
+    }
+
+    pub fn on_key(&mut self, app: &mut App, key: KeyBind) -> Result<()> {
+        let repeat = self.is_sticky_repeat(key);
+        if repeat {
+            // デコード待ちで受け付けなかった入力でも続けて押したものとして扱う
+            self.last_key = Some((key, Instant::now()));
+        }
+        // デコード待ちの間は分類済みの画像を操作してしまわないよう受け付けない
+        if self.is_fin || self.is_loading() {
+            return Ok(());
+        }
+        // コピーしても次の画像には進まず，続けて別の分類先を選べる
+        if let Some(base) = app.copy_key(key) {
+            self.count = None;
+            let result = app.copy_to(base);
+            self.sync(app);
+            return result;
+        }
+        if let Some(name) = app.dest_to_confirm(key).filter(|_| !repeat) {
+            self.confirm = Some((key, name));
+            return Ok(());
+        }
+        self.apply_key(app, key)
+    }
+
+    /// 数字のキーを次の分類先をまとめて適用する枚数として受け付ける
+    ///
+    /// 分類先に割り当てられた数字や，枚数を入力していないときの0は受け付けない
+    pub fn push_count(&mut self, app: &App, key: KeyBind, digit: u32) -> bool {
+        if app.has_dest(key) || (digit == 0 && self.count.is_none()) {
+            return false;
+        }
+        let count = self.count.unwrap_or(0);
+        self.count = Some(count.saturating_mul(10).saturating_add(digit as usize));
+        true
+    }
+
+    /// 確認した分類先に送るか，やめる
+    pub fn on_confirm(&mut self, app: &mut App, accepted: bool) -> Result<()> {
+        let Some((key, _)) = self.confirm.take() else {
+            return Ok(());
+        };
+        match (accepted, self.gallery) {
+            (false, _) => {
+                self.count = None;
+                Ok(())
+            }
+            (true, false) => self.apply_key(app, key),
+            (true, true) => self.apply_gallery_key(app, key),
+        }
+    }
+
+    /// 失敗したファイルの操作をやり直す．skipなら分類せずに次の画像に進み，abortなら何もしない
+    pub fn on_retry(&mut self, app: &mut App, choice: RetryChoice) -> Result<()> {
+        let Some((key, left, _)) = self.retry.take() else {
+            return Ok(());
+        };
+        match choice {
+            // まとめて分類していれば，失敗した画像から残りの枚数をやり直す
+            RetryChoice::Retry => {
+                self.count = left;
+                self.apply_key(app, key)
+            }
+            RetryChoice::Skip if !self.is_loading() => self.on_next(app),
+            RetryChoice::Skip | RetryChoice::Abort => Ok(()),
+        }
+    }
+
+    /// すぐに終了してよいか．未決定の画像が残っていれば確認画面を開く
+    pub fn request_quit(&mut self, app: &App) -> bool {
+        if self.quit_confirm.is_some() {
+            return true;
+        }
+        // 保留中の操作は終了すると失われるので，見直しの画面でも確認する
+        let staged = app.staged().len();
+        let remaining = if self.is_fin {
+            0
+        } else {
+            app.remaining().len()
+        };
+        if remaining > 0 || staged > 0 {
+            self.quit_confirm = Some((remaining, staged));
+        }
+        remaining == 0 && staged == 0
     }
 
-    pub fn on_key(&mut self, app: &mut App, key: char) -> Result<()> {
-        app.on_key(key)?;
+    fn apply_key(&mut self, app: &mut App, key: KeyBind) -> Result<()> {
+        let (result, left) = match self.count.take() {
+            Some(count) if count > 1 => {
+                let (done, result) = app.on_key_repeat(key, count);
+                (result, Some(count - done))
+            }
+            _ => (app.on_key(key), None),
+        };
+        // 一時的な失敗かもしれないので，押したキーと残りの枚数を覚えておいてやり直せるようにする
+        if let Err(e) = result {
+            self.retry = Some((key, left, format!("{:#}", e)));
+            if left.is_some() {
+                // まとめて分類する途中で止めた画像を表示する
+                self.set_img(app.current_img()?);
+                self.sync(app);
+            }
+            self.on_error(app, e);
+            return Ok(());
+        }
+        self.last_key = Some((key, Instant::now()));
+        if app.is_tagging() {
+            // ラベルを付けても次の画像には進まない
+            self.sync(app);
+            return Ok(());
+        }
+        self.next_img(app)
+    }
+
+    /// テキストの入力を始める
+    pub fn open_prompt(&mut self, prompt: Prompt) {
+        let text = match prompt {
+            Prompt::NewDest | Prompt::PickDest | Prompt::Goto => String::new(),
+            // 説明文は付けたものを編集できるようにする
+            Prompt::Caption => self.caption.clone().unwrap_or_default(),
+            Prompt::Filter => self.filter.clone().unwrap_or_default(),
+        };
+        self.prompt = Some((prompt, text));
+        self.picker_cursor = 0;
+    }
+
+    /// 移る先の入力を始める．押したキー (:か/) で番号とファイル名のどちらで探すかを決める
+    pub fn open_goto(&mut self, prefix: char) {
+        self.open_prompt(Prompt::Goto);
+        if let Some((_, text)) = &mut self.prompt {
+            text.push(prefix);
+        }
+    }
+
+    /// 表示する画像を絞り込み，表示中の画像が合わなければ次の画像に進む
+    fn on_filter(&mut self, app: &mut App, pattern: &str) -> Result<()> {
+        app.set_filter(pattern)?;
+        self.sync(app);
+        let matches = app.current_idx().is_none_or(|idx| app.in_filter(idx));
+        if self.is_fin || self.is_loading() || matches {
+            return Ok(());
+        }
+        self.on_next(app)
+    }
+
+    /// 番号かファイル名の一部で指定した画像に移る
+    fn on_goto(&mut self, app: &mut App, target: &str) -> Result<()> {
+        if self.is_fin {
+            return Ok(());
+        }
+        let idx = match target.strip_prefix('/') {
+            Some(name) => app.find_img(name),
+            // 番号は1から数える
+            None => target
+                .trim_start_matches(':')
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .and_then(|num| num.checked_sub(1)),
+        };
+        let Some(idx) = idx else {
+            return Ok(());
+        };
+        match app.seek(idx)? {
+            Some(img_info) => self.set_img(img_info),
+            None => self.start_loading(),
+        }
+        self.conflict = None;
+        self.sync(app);
+        Ok(())
+    }
+
+    /// 入力した文字列に曖昧一致する分類先を，よく一致する順に並べる
+    pub fn picker_matches(&self, query: &str) -> Vec<(KeyBind, &Dest)> {
+        let mut matches: Vec<_> = self
+            .keybind
+            .iter()
+            .filter_map(|(key, dest)| {
+                let name = dest.name();
+                // パスはどれも同じような親ディレクトリを含むので，最後の部分で一致するものを優先する
+                let last = Path::new(&name)
+                    .file_name()
+                    .map(|last| last.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| name.clone());
+                let score = match fuzzy_score(query, &last) {
+                    Some(score) => (0, score),
+                    None => (1, fuzzy_score(query, &name)?),
+                };
+                Some((score, key.to_string(), *key, dest))
+            })
+            .collect();
+        matches.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        matches
+            .into_iter()
+            .map(|(_, _, key, dest)| (key, dest))
+            .collect()
+    }
+
+    /// 分類先の一覧で選ぶ位置を動かす
+    pub fn move_picker(&mut self, down: bool) {
+        let Some((_, query)) = &self.prompt else {
+            return;
+        };
+        let len = self.picker_matches(query).len();
+        self.picker_cursor = if down {
+            (self.picker_cursor + 1).min(len.saturating_sub(1))
+        } else {
+            self.picker_cursor.saturating_sub(1)
+        };
+    }
+
+    /// 入力したテキストを反映する
+    pub fn on_prompt(&mut self, app: &mut App) -> Result<()> {
+        match self.prompt.take() {
+            Some((Prompt::NewDest, dest)) => self.add_dest(app, &dest),
+            Some((Prompt::Caption, caption)) => {
+                app.set_caption(caption)?;
+                self.sync(app);
+                Ok(())
+            }
+            Some((Prompt::Goto, target)) => self.on_goto(app, &target),
+            Some((Prompt::Filter, pattern)) => self.on_filter(app, &pattern),
+            Some((Prompt::PickDest, query)) => {
+                let picked = self
+                    .picker_matches(&query)
+                    .get(self.picker_cursor)
+                    .map(|(key, _)| *key);
+                match picked {
+                    Some(key) => self.on_key(app, key),
+                    None => Ok(()),
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// 入力した分類先を空いているキーに割り当てる
+    fn add_dest(&mut self, app: &mut App, dest: &str) -> Result<()> {
+        let key = app.add_dest(PathBuf::from(dest.trim()))?;
+        if let Some(dest) = app.get_app_info().keybind.get(&key) {
+            self.keybind.insert(key, dest.clone());
+        }
+        self.sync(app);
+        Ok(())
+    }
+
+    /// 表示中の画像に印を付けて次の画像に進む
+    pub fn on_flag(&mut self, app: &mut App) -> Result<()> {
+        if self.is_fin || self.is_loading() {
+            return Ok(());
+        }
+        app.flag()?;
+        self.next_img(app)
+    }
+
+    /// 表示中の画像を残し，似ている画像をまとめて分類する
+    pub fn on_similar(&mut self, app: &mut App) -> Result<()> {
+        if self.is_fin || self.is_loading() {
+            return Ok(());
+        }
+        let result = app.dispatch_similar();
+        self.sync(app);
+        result
+    }
+
+    /// 表示中の画像と次の画像を並べて比較表示を開く
+    pub fn open_compare(&mut self, app: &App) -> Result<()> {
+        if self.is_fin || self.is_loading() || app.is_tagging() {
+            return Ok(());
+        }
+        let Some(idx) = app.current_idx() else {
+            return Ok(());
+        };
+        let left = app.compare_img(idx)?;
+        let right = app
+            .compare_next(idx)
+            .map(|idx| app.compare_img(idx))
+            .transpose()?;
+        self.compare = Some(Compare {
+            left,
+            right,
+            follow: true,
+        });
+        Ok(())
+    }
+
+    /// ギャラリーで印を付けた2枚の画像を並べて比較表示を開く
+    pub fn open_compare_marked(&mut self, app: &App) -> Result<()> {
+        let &[left, right] = self.marked.iter().copied().collect::<Vec<_>>().as_slice() else {
+            return Ok(());
+        };
+        if app.is_tagging() {
+            return Ok(());
+        }
+        self.compare = Some(Compare {
+            left: app.compare_img(left)?,
+            right: Some(app.compare_img(right)?),
+            follow: false,
+        });
+        self.marked.clear();
+        Ok(())
+    }
+
+    /// キーに割り当てた分類先に最近置いた画像を並べて表示する
+    pub fn on_peek(&mut self, app: &App, key: KeyBind) -> Result<()> {
+        self.peek_pending = false;
+        self.peek = Some(app.peek(key)?);
+        Ok(())
+    }
+
+    pub fn close_compare(&mut self) {
+        self.compare = None;
+    }
+
+    /// 比較表示で片方を残し，もう片方を分類する
+    pub fn on_compare_keep(&mut self, app: &mut App, keep_left: bool) -> Result<()> {
+        let Some(Compare {
+            left,
+            right: Some(right),
+            follow,
+        }) = self.compare.take()
+        else {
+            return Ok(());
+        };
+        let (kept, dropped) = if keep_left {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        let result = app.discard(dropped.idx);
+        // 次の画像と比べ続ける場合は，残した画像を左に寄せて次の画像を右に並べる
+        if follow {
+            let right = app
+                .compare_next(kept.idx)
+                .and_then(|idx| app.compare_img(idx).ok());
+            self.compare = Some(Compare {
+                left: kept,
+                right,
+                follow,
+            });
+        }
+        // 表示中の画像を分類した場合は次の画像に進む
+        if app.current_idx() == Some(dropped.idx) {
+            self.next_img(app)?;
+        }
+        self.sync(app);
+        result
+    }
+
+    /// OCRで読み取った文字列の欄を表示するか切り替える
+    pub fn toggle_text(&mut self, app: &mut App) {
+        self.show_text = !self.show_text;
+        if let Some(text) = app.set_show_text(self.show_text) {
+            self.text = Some(text);
+        }
+    }
+
+    /// ギャラリー表示を切り替える
+    pub fn toggle_gallery(&mut self) {
+        self.gallery = !self.gallery;
+        self.gallery_cursor = 0;
+        self.marked.clear();
+    }
+
+    /// ギャラリーで選択している位置を動かす
+    pub fn on_gallery_move(&mut self, forward: bool) {
+        let len = self.gallery_page.len();
+        if len == 0 {
+            return;
+        }
+        self.gallery_cursor = if forward {
+            (self.gallery_cursor + 1) % len
+        } else {
+            (self.gallery_cursor + len - 1) % len
+        };
+    }
+
+    /// ギャラリーで選択している画像の印を切り替える
+    pub fn on_gallery_mark(&mut self) {
+        if let Some(&idx) = self.gallery_page.get(self.gallery_cursor) {
+            if !self.marked.remove(&idx) {
+                self.marked.insert(idx);
+            }
+        }
+    }
+
+    /// 印を付けた画像 (なければ選択している画像) をまとめて分類する
+    pub fn on_gallery_key(&mut self, app: &mut App, key: KeyBind) -> Result<()> {
+        if app.conflict().is_some() {
+            return Ok(());
+        }
+        if let Some(name) = app.dest_to_confirm(key) {
+            self.confirm = Some((key, name));
+            return Ok(());
+        }
+        self.apply_gallery_key(app, key)
+    }
+
+    fn apply_gallery_key(&mut self, app: &mut App, key: KeyBind) -> Result<()> {
+        let targets: Vec<usize> = if self.marked.is_empty() {
+            let selected = self.gallery_page.get(self.gallery_cursor);
+            selected.copied().into_iter().collect()
+        } else {
+            self.marked.iter().copied().collect()
+        };
+        let result = app.on_key_many(&targets, key);
+        self.marked.clear();
+        self.gallery_cursor = 0;
+        self.next_img(app)?;
+        self.sync(app);
+        result
+    }
+
+    pub fn on_conflict(&mut self, app: &mut App, policy: CollisionPolicy) -> Result<()> {
+        app.resolve_conflict(policy)?;
+        self.next_img(app)
+    }
+
+    /// 移動先に中身が同じファイルがある画像をゴミ箱に送る
+    pub fn on_conflict_delete(&mut self, app: &mut App) -> Result<()> {
+        app.resolve_duplicate()?;
+        self.next_img(app)
+    }
+
+    pub fn on_conflict_cancel(&mut self, app: &mut App) {
+        app.cancel_conflict();
+        self.conflict = None;
+    }
+
+    /// 表示中の画像の分類が済んでいれば次の画像に進む
+    fn next_img(&mut self, app: &mut App) -> Result<()> {
+        self.conflict = app.conflict();
+        if app.is_pending() {
+            return Ok(());
+        }
         let img_info = app.get_img();
         match img_info {
-            Ok(img_info) => {
-                self.img = img_info.state;
-                self.img_path = img_info.path;
-                self.progress += 1;
+            Ok(Some(img_info)) => {
+                self.set_img(img_info);
             }
+            Ok(None) => self.start_loading(),
             Err(_) => {
-                self.is_fin = true;
+                self.finish(app);
             }
         };
-        self.log = app.log.clone();
+        self.sync(app);
+        Ok(())
+    }
+
+    /// 保留中の操作をまとめて反映する
+    pub fn on_commit(&mut self, app: &mut App) -> Result<()> {
+        let result = app.commit_staged();
+        self.sync(app);
+        self.finish(app);
+        result
+    }
+
+    /// 1つ前に表示した画像に戻る
+    pub fn on_prev(&mut self, app: &mut App) -> Result<()> {
+        let img_info = app.go_prev()?;
+        self.set_img(img_info);
+        self.is_fin = false;
+        self.conflict = None;
         Ok(())
     }
+
+    /// 分類せずに次の画像に進む
+    pub fn on_next(&mut self, app: &mut App) -> Result<()> {
+        self.conflict = None;
+        match app.go_next() {
+            Ok(Some(img_info)) => {
+                self.set_img(img_info);
+            }
+            Ok(None) => self.start_loading(),
+            // タグ付けモードでは最後の画像から進むと終了する
+            Err(_) if !app.is_pending() || app.is_tagging() => {
+                self.finish(app);
+            }
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    pub fn on_undo(&mut self, app: &mut App) -> Result<()> {
+        app.undo()?;
+        self.conflict = None;
+        let img_info = app.current_img()?;
+        self.set_img(img_info);
+        self.sync(app);
+        self.is_fin = false;
+        Ok(())
+    }
+
+    /// 取り消した操作をやり直す
+    pub fn on_redo(&mut self, app: &mut App) -> Result<()> {
+        app.redo()?;
+        self.conflict = None;
+        let img_info = app.current_img()?;
+        self.set_img(img_info);
+        self.sync(app);
+        Ok(())
+    }
+
+    /// 履歴で選んでいる項目 (履歴の欄の一番下) の操作だけを取り消す
+    pub fn on_undo_selected(&mut self, app: &mut App) -> Result<()> {
+        let pos = self
+            .history
+            .len()
+            .checked_sub(self.history_scroll + 1)
+            .context("nothing to undo")?;
+        app.undo_log(pos)?;
+        self.conflict = None;
+        let img_info = app.current_img()?;
+        self.set_img(img_info);
+        self.sync(app);
+        self.is_fin = false;
+        Ok(())
+    }
+}
+
+/// queryの文字がtextに順に含まれていれば，間に挟まる文字の数と一致し始めた位置 (小さいほどよく一致する)
+fn fuzzy_score(query: &str, text: &str) -> Option<(usize, usize)> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let query: Vec<char> = query
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let mut start = None;
+    let mut pos = 0;
+    for q in &query {
+        let found = pos + text[pos..].iter().position(|c| c == q)?;
+        start.get_or_insert(found);
+        pos = found + 1;
+    }
+    let start = start.unwrap_or(0);
+    Some((pos - start - query.len(), start))
 }
@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+    time::{Duration, Instant},
+};
+
+// 書き込み中のファイルを読まないよう，最後の変更からこれだけ待ってから追加する
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// 分類元のディレクトリに新しく作られたファイルを見つける
+pub struct DirWatcher {
+    // 破棄すると通知が止まるので持っておく
+    _watcher: RecommendedWatcher,
+    rx: Receiver<notify::Result<Event>>,
+    // 分類先のディレクトリ (ここに移動したファイルは対象にしない)
+    excludes: Vec<PathBuf>,
+    // 変更が落ち着くのを待っているファイルと最後に変更された時刻
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl DirWatcher {
+    pub fn new(dirs: &[PathBuf], recursive: bool, excludes: Vec<PathBuf>) -> Result<Self> {
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(tx).context("cannot start watching dir")?;
+        let mode = if recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        for dir in dirs {
+            watcher
+                .watch(dir, mode)
+                .with_context(|| format!("cannot watch dir: {}", dir.display()))?;
+        }
+        Ok(DirWatcher {
+            _watcher: watcher,
+            rx,
+            excludes,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// 作られてから変更が落ち着いたファイル
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        while let Ok(event) = self.rx.try_recv() {
+            let Ok(event) = event else {
+                continue;
+            };
+            // 作成と，書き込みや別の場所からの移動による変更だけを見る
+            let relevant = matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(ModifyKind::Data(_))
+                    | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Any))
+            );
+            if !relevant {
+                continue;
+            }
+            for path in event.paths {
+                if !self.is_excluded(&path) {
+                    self.pending.insert(path, Instant::now());
+                }
+            }
+        }
+
+        let mut settled = Vec::new();
+        self.pending.retain(|path, changed| {
+            if changed.elapsed() < SETTLE_TIME {
+                return true;
+            }
+            if path.is_file() {
+                settled.push(path.clone());
+            }
+            false
+        });
+        settled.sort();
+        settled
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        let Ok(path) = path.canonicalize() else {
+            return false;
+        };
+        self.excludes.iter().any(|dir| path.starts_with(dir))
+    }
+}
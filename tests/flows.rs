@@ -0,0 +1,1058 @@
+use crossterm::event::{KeyCode, KeyModifiers};
+use image::{
+    codecs::png::PngEncoder, ExtendedColorType, ImageDecoder, ImageEncoder, ImageFormat,
+    ImageReader, Rgb, RgbImage,
+};
+use shotclassif::{
+    app::{App, DestMode},
+    config::{Config, Dest},
+    harness::Harness,
+    input::Next,
+    lock::SessionLock,
+};
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+// デコードを待つ時間の上限
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// テストの一時ディレクトリ．テストが失敗しても消えるよう，落とすときに消す
+struct TempDir(PathBuf);
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// テストごとの一時ディレクトリに画像を作る
+fn setup(name: &str, imgs: &[&str]) -> TempDir {
+    let dir = std::env::temp_dir().join(format!("shotclassif-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (i, img) in imgs.iter().enumerate() {
+        RgbImage::from_pixel(16, 16, Rgb([i as u8 * 40, 0, 0]))
+            .save(dir.join(img))
+            .unwrap();
+    }
+    TempDir(dir)
+}
+
+/// "a"で移動，"s"でスキップ，"p"でコピー，"x"で確認してから移動する設定
+///
+/// OSのゴミ箱を汚さないよう，ゴミ箱は一時ディレクトリの中に作る
+fn config(dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.dir = vec![dir.to_path_buf()];
+    config.trash_dir = Some(dir.join(".trash"));
+    config
+        .dests
+        .insert("a".parse().unwrap(), dir.join("sorted").into());
+    config
+        .dests
+        .insert("s".parse().unwrap(), PathBuf::from("skip").into());
+    let copy = Dest {
+        mode: DestMode::Copy,
+        ..Dest::from(dir.join("copied"))
+    };
+    config.dests.insert("p".parse().unwrap(), copy);
+    let confirm = Dest {
+        confirm: true,
+        ..Dest::from(dir.join("archive"))
+    };
+    config.dests.insert("x".parse().unwrap(), confirm);
+    config
+}
+
+fn start(dir: &Path) -> Harness {
+    let mut harness = Harness::new(config(dir), 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness
+}
+
+#[test]
+fn move_to_dest() {
+    let dir = setup("move", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+    assert!(harness.screen_contains("1.png"));
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("sorted/1.png").exists());
+    assert!(!dir.join("1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+}
+
+#[test]
+fn skip_keeps_file() {
+    let dir = setup("skip", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(!dir.join("sorted/1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+}
+
+#[test]
+fn undo_restores_file() {
+    let dir = setup("undo", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("sorted/1.png").exists());
+
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(!dir.join("sorted/1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+}
+
+#[test]
+fn undo_overwrite_restores_replaced_file() {
+    let dir = setup("overwrite", &["1.png", "2.png"]);
+    fs::create_dir_all(dir.join("sorted")).unwrap();
+    fs::write(dir.join("sorted/1.png"), b"already there").unwrap();
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(harness.screen_contains("sorted"));
+    harness.press(KeyCode::Char('o')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(!dir.join("1.png").exists());
+    assert_ne!(
+        fs::read(dir.join("sorted/1.png")).unwrap(),
+        b"already there"
+    );
+    assert!(dir.join(".trash").read_dir().unwrap().next().is_some());
+
+    // 上書きされたファイルはゴミ箱から戻る
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert_eq!(
+        fs::read(dir.join("sorted/1.png")).unwrap(),
+        b"already there"
+    );
+}
+
+#[test]
+fn caption_is_written_next_to_image() {
+    let dir = setup("caption", &["1.png", "2.png"]);
+    fs::write(dir.join("1.txt"), b"notes").unwrap();
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('c')).unwrap();
+    for c in "cat".chars() {
+        harness.press(KeyCode::Char(c)).unwrap();
+    }
+    harness.press(KeyCode::Enter).unwrap();
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert_eq!(fs::read(dir.join("1.png.txt")).unwrap(), b"cat");
+    // 元からある同じ名前の.txtはそのまま
+    assert_eq!(fs::read(dir.join("1.txt")).unwrap(), b"notes");
+
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(!dir.join("1.png.txt").exists());
+    assert!(dir.join("1.txt").exists());
+}
+
+#[test]
+fn watch_ignores_files_placed_in_new_dest() {
+    let dir = setup("watch", &["1.png", "2.png"]);
+    let mut config = config(&dir);
+    config.watch = true;
+    config.recursive = true;
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert_eq!(harness.app.rest_num(), 2);
+
+    // 分類先は始めた時点ではまだない
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("sorted/1.png").exists());
+    // 変更が落ち着くまで待ってから監視の結果を取り込む
+    let started = std::time::Instant::now();
+    while started.elapsed() < Duration::from_secs(2) {
+        harness.draw().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    // 分類先に置いた画像が新しい画像として加わらない
+    assert_eq!(harness.app.rest_num(), 2);
+}
+
+#[test]
+fn copy_keeps_source() {
+    let dir = setup("copy", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('p')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(dir.join("copied/1.png").exists());
+
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(!dir.join("copied/1.png").exists());
+}
+
+#[test]
+fn copy_with_modifier_then_move() {
+    let dir = setup("modifier", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness
+        .press_with(KeyCode::Char('x'), KeyModifiers::ALT)
+        .unwrap();
+    assert!(dir.join("archive/1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("archive/1.png").exists());
+    assert!(dir.join("sorted/1.png").exists());
+
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(!dir.join("archive/1.png").exists());
+}
+
+#[test]
+fn copy_with_modifier_is_recorded() {
+    let dir = setup("modifier-record", &["1.png", "2.png"]);
+    let mut with_manifest = config(&dir);
+    with_manifest.manifest = Some(dir.join("manifest.csv"));
+    let mut harness = Harness::new(with_manifest, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness
+        .press_with(KeyCode::Char('x'), KeyModifiers::ALT)
+        .unwrap();
+    harness.app.write_manifest().unwrap();
+    let manifest = fs::read_to_string(dir.join("manifest.csv")).unwrap();
+    assert!(manifest.contains("archive"));
+    drop(harness);
+
+    // コピーしただけの画像は未決定のまま残り，取り消すとコピーが消える
+    let mut app = App::headless(config(&dir), true).unwrap();
+    assert_eq!(app.rest_num(), 2);
+    assert_eq!(app.undo_session(1).unwrap(), 1);
+    assert!(!dir.join("archive/1.png").exists());
+    assert!(dir.join("1.png").exists());
+}
+
+#[test]
+fn confirm_before_move() {
+    let dir = setup("confirm", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('x')).unwrap();
+    assert!(harness.screen_contains("Send to"));
+    harness.press(KeyCode::Char('n')).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+
+    harness.press(KeyCode::Char('x')).unwrap();
+    harness.press(KeyCode::Char('y')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("archive/1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+}
+
+#[test]
+fn finish_and_quit() {
+    let dir = setup("finish", &["1.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.is_fin);
+    assert!(matches!(
+        harness.press(KeyCode::Char('q')).unwrap(),
+        Next::Quit
+    ));
+}
+
+#[test]
+fn staged_commit_renames_on_collision() {
+    let dir = setup("staged", &["1.png"]);
+    fs::create_dir_all(dir.join("sorted")).unwrap();
+    fs::write(dir.join("sorted/1.png"), b"already there").unwrap();
+    let mut config = config(&dir);
+    config.staged = true;
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    // 保留中の操作も進み具合に含める
+    assert_eq!(harness.viewmodel.progress, 1);
+    assert!(harness.viewmodel.is_fin);
+    assert!(dir.join("1.png").exists());
+
+    harness.press(KeyCode::Enter).unwrap();
+    assert!(!dir.join("1.png").exists());
+    assert!(dir.join("sorted/1_1.png").exists());
+    assert_eq!(
+        fs::read(dir.join("sorted/1.png")).unwrap(),
+        b"already there"
+    );
+}
+
+#[test]
+fn quit_asks_when_staged_moves_remain() {
+    let dir = setup("staged-quit", &["1.png"]);
+    let mut config = config(&dir);
+    config.staged = true;
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(harness.viewmodel.is_fin);
+    assert!(matches!(
+        harness.press(KeyCode::Char('q')).unwrap(),
+        Next::Continue
+    ));
+    assert_eq!(harness.viewmodel.quit_confirm, Some((0, 1)));
+    assert!(harness.screen_contains("1 staged moves are not committed"));
+}
+
+#[test]
+fn count_prefix_moves_several() {
+    let dir = setup("count", &["1.png", "2.png", "3.png", "4.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('3')).unwrap();
+    assert!(harness.screen_contains("next 3 images"));
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    for img in ["1.png", "2.png", "3.png"] {
+        assert!(dir.join("sorted").join(img).exists());
+    }
+    assert!(dir.join("4.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("4.png"));
+    assert!(harness.viewmodel.count.is_none());
+}
+
+#[test]
+fn count_prefix_keeps_earlier_decisions() {
+    let dir = setup("count-back", &["1.png", "2.png", "3.png", "4.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('p')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Left).unwrap();
+    harness.press(KeyCode::Left).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+
+    // 1枚目をやり直し，コピーした2枚目は飛ばして3枚目まで送る
+    harness.press(KeyCode::Char('2')).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(dir.join("sorted/1.png").exists());
+    assert!(dir.join("2.png").exists());
+    assert!(dir.join("copied/2.png").exists());
+    assert!(dir.join("sorted/3.png").exists());
+    assert!(dir.join("4.png").exists());
+}
+
+#[test]
+fn sticky_skips_repeated_confirm() {
+    let dir = setup("sticky", &["1.png", "2.png", "3.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('R')).unwrap();
+    assert!(harness.screen_contains("sticky"));
+    harness.press(KeyCode::Char('x')).unwrap();
+    harness.press(KeyCode::Char('y')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("archive/1.png").exists());
+
+    harness.press(KeyCode::Char('x')).unwrap();
+    assert!(harness.viewmodel.confirm.is_none());
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("archive/2.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("3.png"));
+}
+
+#[test]
+fn pick_dest_by_name() {
+    let dir = setup("picker", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Enter).unwrap();
+    for c in "srt".chars() {
+        harness.press(KeyCode::Char(c)).unwrap();
+    }
+    assert!(harness.screen_contains("[↑/↓] select"));
+    let matches = harness.viewmodel.picker_matches("srt");
+    assert_eq!(matches[0].0, "a".parse().unwrap());
+    let matches = harness.viewmodel.picker_matches("skp");
+    assert_eq!(matches[0].0, "s".parse().unwrap());
+    harness.press(KeyCode::Enter).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(dir.join("sorted/1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+}
+
+#[test]
+fn goto_skips_ahead_and_comes_back() {
+    let dir = setup("goto", &["1.png", "2.png", "3.png", "4.png"]);
+    let mut harness = start(&dir);
+
+    for c in ":3".chars() {
+        harness.press(KeyCode::Char(c)).unwrap();
+    }
+    harness.press(KeyCode::Enter).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("3.png"));
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("4.png"));
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    // 飛ばした画像は最後に表示する
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+
+    for c in "/1.p".chars() {
+        harness.press(KeyCode::Char(c)).unwrap();
+    }
+    harness.press(KeyCode::Enter).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+}
+
+#[test]
+fn filter_shows_matching_first() {
+    let dir = setup("filter", &["a1.png", "b1.png", "a2.png", "b2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('F')).unwrap();
+    for c in "b*".chars() {
+        harness.press(KeyCode::Char(c)).unwrap();
+    }
+    harness.press(KeyCode::Enter).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("b1.png"));
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("b2.png"));
+
+    // 合う画像がなくなると絞り込みをやめて残りを表示する
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("a2.png"));
+    assert!(harness.viewmodel.filter.is_none());
+}
+
+/// シャッフルしてから全て分類し，表示した順のファイル名を返す
+fn shuffled_order(name: &str) -> Vec<String> {
+    let imgs = ["1.png", "2.png", "3.png", "4.png", "5.png", "6.png"];
+    let dir = setup(name, &imgs);
+    let mut config = config(&dir);
+    config.seed = Some(42);
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('S')).unwrap();
+    assert!(harness.screen_contains("seed 42"));
+    let mut order = Vec::new();
+    while !harness.viewmodel.is_fin {
+        let path = &harness.viewmodel.img_path;
+        order.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        harness.press(KeyCode::Char('a')).unwrap();
+        harness.wait_for_image(TIMEOUT).unwrap();
+    }
+    order
+}
+
+#[test]
+fn shuffle_is_reproducible_with_seed() {
+    let first = shuffled_order("shuffle1");
+    let second = shuffled_order("shuffle2");
+    assert_eq!(first, second);
+    let mut sorted = first.clone();
+    sorted.sort();
+    assert_ne!(first, sorted);
+    assert_eq!(
+        sorted,
+        ["1.png", "2.png", "3.png", "4.png", "5.png", "6.png"]
+    );
+}
+
+#[test]
+fn history_keeps_every_action() {
+    let dir = setup("history", &["1.png", "2.png", "3.png"]);
+    // 履歴の欄が見えるよう画面を大きくする
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert_eq!(harness.viewmodel.history.len(), 3);
+    assert!(harness.screen_contains("Skip 2.png"));
+    assert!(harness.screen_contains("Undo 2.png"));
+
+    harness.press(KeyCode::PageUp).unwrap();
+    assert_eq!(harness.viewmodel.history_scroll, 2);
+    assert!(!harness.screen_contains("Undo 2.png"));
+    harness.press(KeyCode::PageDown).unwrap();
+    assert_eq!(harness.viewmodel.history_scroll, 0);
+}
+
+#[test]
+fn undo_older_history_entry() {
+    let dir = setup("undo-entry", &["1.png", "2.png", "3.png"]);
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness
+        .press_with(KeyCode::PageUp, KeyModifiers::SHIFT)
+        .unwrap();
+    assert_eq!(harness.viewmodel.history_scroll, 1);
+
+    harness.press(KeyCode::Char('U')).unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(dir.join("sorted/2.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+
+    // 取り消した項目はもう一度取り消せない
+    harness.press(KeyCode::Char('U')).unwrap();
+    harness.press(KeyCode::PageDown).unwrap();
+    assert!(harness.screen_contains("Error: this entry"));
+    assert!(dir.join("sorted/2.png").exists());
+}
+
+#[test]
+fn redo_after_undo() {
+    let dir = setup("redo", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(dir.join("1.png").exists());
+
+    harness
+        .press_with(KeyCode::Char('r'), KeyModifiers::CONTROL)
+        .unwrap();
+    assert!(!dir.join("1.png").exists());
+    assert!(dir.join("sorted/1.png").exists());
+
+    // やり直した操作も取り消せるが，新しく決定した後はやり直せない
+    harness.press(KeyCode::Char('u')).unwrap();
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness
+        .press_with(KeyCode::Char('r'), KeyModifiers::CONTROL)
+        .unwrap();
+    assert!(dir.join("1.png").exists());
+    assert!(!dir.join("sorted/1.png").exists());
+}
+
+#[test]
+fn quit_asks_when_images_remain() {
+    let dir = setup("quit", &["1.png", "2.png"]);
+    let mut harness = start(&dir);
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(matches!(
+        harness.press(KeyCode::Char('q')).unwrap(),
+        Next::Continue
+    ));
+    assert!(harness.screen_contains("1 images are not sorted yet"));
+    harness.press(KeyCode::Esc).unwrap();
+    assert!(harness.viewmodel.quit_confirm.is_none());
+
+    harness.press(KeyCode::Char('q')).unwrap();
+    assert!(matches!(
+        harness.press(KeyCode::Char('w')).unwrap(),
+        Next::Quit
+    ));
+    let remaining = fs::read_to_string(dir.join("remaining.txt")).unwrap();
+    assert_eq!(remaining.lines().count(), 1);
+    assert!(remaining.contains("2.png"));
+}
+
+#[test]
+fn remaining_list_includes_skipped() {
+    let dir = setup("remaining", &["1.png", "2.png", "3.png"]);
+    let mut config = config(&dir);
+    config.write_remaining = true;
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.app.write_remaining_on_quit().unwrap();
+    let remaining = fs::read_to_string(dir.join("remaining.txt")).unwrap();
+    let names: Vec<&str> = remaining
+        .lines()
+        .filter_map(|line| Path::new(line).file_name()?.to_str())
+        .collect();
+    assert_eq!(names, ["2.png", "3.png"]);
+}
+
+#[test]
+fn defer_shows_image_again_at_end() {
+    let dir = setup("defer", &["1.png", "2.png", "3.png"]);
+    let mut config = config(&dir);
+    config
+        .dests
+        .insert("l".parse().unwrap(), PathBuf::from("defer").into());
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('l')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("1.png"));
+    assert!(!harness.viewmodel.is_fin);
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.is_fin);
+    assert!(dir.join("sorted/1.png").exists());
+}
+
+#[test]
+fn peek_shows_recent_files_in_dest() {
+    let dir = setup("peek", &["1.png", "2.png"]);
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('P')).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    // 覗いている間は分類先のキーを押しても送らない
+    assert!(dir.join("2.png").exists());
+    assert!(harness.screen_contains("Recent in"));
+    assert_eq!(harness.viewmodel.peek.as_ref().unwrap().imgs.len(), 1);
+
+    harness.press(KeyCode::Esc).unwrap();
+    assert!(harness.viewmodel.peek.is_none());
+    assert!(dir.join("2.png").exists());
+}
+
+#[test]
+fn free_space_is_shown_per_dest() {
+    let dir = setup("free", &["1.png"]);
+    let harness = start(&dir);
+
+    let free_space = &harness.viewmodel.free_space;
+    assert!(free_space.contains_key(&dir.join("sorted")));
+    assert!(!free_space.contains_key(Path::new("skip")));
+}
+
+#[test]
+fn retry_after_failed_move() {
+    let dir = setup("retry", &["1.png", "2.png"]);
+    // 分類先と同じ名前のファイルがあるとディレクトリを作れずに失敗する
+    fs::write(dir.join("sorted"), "").unwrap();
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(harness.viewmodel.retry.is_some());
+    assert!(harness.screen_contains("[r] retry"));
+    assert!(dir.join("1.png").exists());
+
+    fs::remove_file(dir.join("sorted")).unwrap();
+    harness.press(KeyCode::Char('r')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.retry.is_none());
+    assert!(dir.join("sorted/1.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+}
+
+#[test]
+fn retry_repeat_from_failed_image() {
+    let dir = setup("retry-repeat", &["1.png", "2.png", "3.png", "4.png"]);
+    // 同じ名前のファイルがある画像はまとめて分類するときに失敗する
+    fs::create_dir_all(dir.join("sorted")).unwrap();
+    fs::write(dir.join("sorted/2.png"), "").unwrap();
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    harness.press(KeyCode::Char('3')).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    let (_, left, error) = harness.viewmodel.retry.clone().unwrap();
+    assert_eq!(left, Some(2));
+    assert!(error.contains("sorted 1 of 3 images"));
+    assert!(dir.join("sorted/1.png").exists());
+    assert!(dir.join("3.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("2.png"));
+
+    fs::remove_file(dir.join("sorted/2.png")).unwrap();
+    harness.press(KeyCode::Char('r')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.retry.is_none());
+    assert!(dir.join("sorted/2.png").exists());
+    assert!(dir.join("sorted/3.png").exists());
+    assert!(dir.join("4.png").exists());
+    assert!(harness.viewmodel.img_path.ends_with("4.png"));
+}
+
+#[test]
+fn lock_blocks_second_session() {
+    let dir = setup("lock", &[]);
+    let lock = SessionLock::acquire(&dir, false).unwrap();
+    assert!(SessionLock::acquire(&dir, false).is_err());
+
+    let forced = SessionLock::acquire(&dir, true).unwrap();
+    drop(lock);
+    drop(forced);
+    // ロックはファイルを開いている間だけなので，ファイルが残っていても取れる
+    assert!(dir.join(".shotclassif.lock").exists());
+    assert!(SessionLock::acquire(&dir, false).is_ok());
+}
+
+#[test]
+fn deleted_images_are_skipped_with_warning() {
+    let dir = setup("deleted", &["1.png", "2.png", "3.png"]);
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    // デコードした後に消えた画像は表示しない
+    fs::remove_file(dir.join("2.png")).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.img_path.ends_with("3.png"));
+    assert!(harness.screen_contains("2.png no longer exists"));
+
+    // 表示中に消えた画像は分類せずに次に進む
+    fs::remove_file(dir.join("3.png")).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.retry.is_none());
+    assert!(harness.viewmodel.is_fin);
+    assert!(harness.screen_contains("3.png no longer exists"));
+}
+
+#[test]
+fn strip_exif_removes_metadata() {
+    let dir = setup("strip", &[]);
+    // EXIFのAPP1セグメントをSOIの直後に差し込んだJPEG
+    let mut jpeg = Vec::new();
+    RgbImage::from_pixel(16, 16, Rgb([200, 0, 0]))
+        .write_to(&mut std::io::Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+        .unwrap();
+    let tiff = b"II*\0\x08\0\0\0\0\0\0\0\0\0";
+    let mut app1 = vec![0xff, 0xe1, 0, (2 + 6 + tiff.len()) as u8];
+    app1.extend_from_slice(b"Exif\0\0");
+    app1.extend_from_slice(tiff);
+    jpeg.splice(2..2, app1);
+    fs::write(dir.join("1.jpg"), &jpeg).unwrap();
+    assert!(exif(&dir.join("1.jpg")).is_some());
+
+    let mut config = config(&dir);
+    let public = Dest {
+        strip_exif: true,
+        ..Dest::from(dir.join("public"))
+    };
+    config.dests.insert("b".parse().unwrap(), public);
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('b')).unwrap();
+    assert!(!dir.join("1.jpg").exists());
+    assert!(exif(&dir.join("public/1.jpg")).is_none());
+
+    // 取り消すとゴミ箱から元の画像が戻る
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(!dir.join("public/1.jpg").exists());
+    assert_eq!(fs::read(dir.join("1.jpg")).unwrap(), jpeg);
+}
+
+#[test]
+fn icc_profile_is_converted_to_srgb() {
+    let dir = setup("icc", &[]);
+    // Display P3のプロファイルを埋め込んだPNG
+    let file = fs::File::create(dir.join("1.png")).unwrap();
+    let mut encoder = PngEncoder::new(file);
+    let p3 = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+    encoder.set_icc_profile(p3).unwrap();
+    let img = RgbImage::from_pixel(16, 16, Rgb([200, 100, 50]));
+    encoder
+        .write_image(&img, 16, 16, ExtendedColorType::Rgb8)
+        .unwrap();
+
+    let mut config = config(&dir);
+    let public = Dest {
+        strip_exif: true,
+        ..Dest::from(dir.join("public"))
+    };
+    config.dests.insert("b".parse().unwrap(), public);
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('b')).unwrap();
+    // 書き直した画像はsRGBの画素になる．P3の赤はsRGBではより鮮やかな値になる
+    let img = image::open(dir.join("public/1.png")).unwrap().to_rgb8();
+    assert!(img.get_pixel(0, 0)[0] > 200);
+}
+
+/// 画像のEXIF
+fn exif(path: &Path) -> Option<Vec<u8>> {
+    let mut decoder = ImageReader::open(path)
+        .unwrap()
+        .with_guessed_format()
+        .unwrap()
+        .into_decoder()
+        .unwrap();
+    decoder.exif_metadata().unwrap()
+}
+
+#[test]
+fn max_dimension_downscales_on_move() {
+    let dir = setup("resize", &["1.png"]);
+    let mut config = config(&dir);
+    let share = Dest {
+        max_dimension: Some(8),
+        ..Dest::from(dir.join("share"))
+    };
+    config.dests.insert("b".parse().unwrap(), share);
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('b')).unwrap();
+    assert!(!dir.join("1.png").exists());
+    let img = image::open(dir.join("share/1.png")).unwrap();
+    assert_eq!((img.width(), img.height()), (8, 8));
+}
+
+#[test]
+fn undo_downscaled_move_restores_original() {
+    let dir = setup("resize-undo", &["1.png", "2.png"]);
+    let mut config = config(&dir);
+    let share = Dest {
+        max_dimension: Some(8),
+        ..Dest::from(dir.join("share"))
+    };
+    config.dests.insert("b".parse().unwrap(), share);
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('b')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(!dir.join("1.png").exists());
+
+    harness.press(KeyCode::Char('u')).unwrap();
+    assert!(!dir.join("share/1.png").exists());
+    let img = image::open(dir.join("1.png")).unwrap();
+    assert_eq!((img.width(), img.height()), (16, 16));
+}
+
+#[test]
+fn rotate_file_on_disk() {
+    let dir = setup("rotate", &[]);
+    RgbImage::from_pixel(16, 8, Rgb([0, 200, 0]))
+        .save(dir.join("1.png"))
+        .unwrap();
+    RgbImage::from_pixel(16, 8, Rgb([0, 0, 200]))
+        .save(dir.join("2.jpg"))
+        .unwrap();
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    // JPEG以外は画素を回して書き直す
+    harness.press(KeyCode::Char('}')).unwrap();
+    let img = image::open(dir.join("1.png")).unwrap();
+    assert_eq!((img.width(), img.height()), (8, 16));
+    assert!(harness.screen_contains("Rotate 1.png right"));
+    // 取り消すとゴミ箱に送った元の画像に戻る
+    harness.press(KeyCode::Char('u')).unwrap();
+    let img = image::open(dir.join("1.png")).unwrap();
+    assert_eq!((img.width(), img.height()), (16, 8));
+    harness.press(KeyCode::Char('}')).unwrap();
+
+    // JPEGはEXIFの向きだけを書き換える
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('{')).unwrap();
+    let mut decoder = ImageReader::open(dir.join("2.jpg"))
+        .unwrap()
+        .with_guessed_format()
+        .unwrap()
+        .into_decoder()
+        .unwrap();
+    assert_eq!(decoder.dimensions(), (16, 8));
+    assert_eq!(decoder.orientation().unwrap().to_exif(), 8);
+    harness.press(KeyCode::Char('{')).unwrap();
+    assert!(exif(&dir.join("2.jpg")).is_some());
+    let mut decoder = ImageReader::open(dir.join("2.jpg"))
+        .unwrap()
+        .with_guessed_format()
+        .unwrap()
+        .into_decoder()
+        .unwrap();
+    assert_eq!(decoder.orientation().unwrap().to_exif(), 3);
+    // JPEGは逆向きに回して取り消す
+    harness.press(KeyCode::Char('u')).unwrap();
+    let mut decoder = ImageReader::open(dir.join("2.jpg"))
+        .unwrap()
+        .with_guessed_format()
+        .unwrap()
+        .into_decoder()
+        .unwrap();
+    assert_eq!(decoder.orientation().unwrap().to_exif(), 8);
+}
+
+#[test]
+fn move_asks_when_destination_has_same_content() {
+    let dir = setup("duplicate", &["1.png", "2.png", "3.png"]);
+    fs::create_dir(dir.join("sorted")).unwrap();
+    fs::copy(dir.join("1.png"), dir.join("sorted/old.png")).unwrap();
+    fs::copy(dir.join("2.png"), dir.join("3.png")).unwrap();
+    let mut harness = start(&dir);
+
+    // 始める前から分類先にあったファイル
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(harness.screen_contains("Duplicate"));
+    assert!(dir.join("1.png").exists());
+    harness.press(KeyCode::Char('s')).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+
+    // セッションの途中で分類先に置いたファイル
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(dir.join("sorted/2.png").exists());
+    harness.wait_for_image(TIMEOUT).unwrap();
+    harness.press(KeyCode::Char('a')).unwrap();
+    assert!(harness.screen_contains("Duplicate"));
+    assert!(dir.join("3.png").exists());
+    assert!(!dir.join("sorted/3.png").exists());
+}
+
+#[test]
+fn raw_shows_embedded_preview() {
+    let dir = setup("raw", &[]);
+    let mut jpeg = Vec::new();
+    RgbImage::from_pixel(32, 16, Rgb([200, 200, 0]))
+        .write_to(&mut std::io::Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+        .unwrap();
+    // IFD0にJPEGの位置と長さだけを書いたTIFF
+    let mut raw = b"II*\0\x08\0\0\0\x02\0".to_vec();
+    for (tag, value) in [(0x0201u16, 38u32), (0x0202, jpeg.len() as u32)] {
+        raw.extend_from_slice(&tag.to_le_bytes());
+        raw.extend_from_slice(&4u16.to_le_bytes());
+        raw.extend_from_slice(&1u32.to_le_bytes());
+        raw.extend_from_slice(&value.to_le_bytes());
+    }
+    raw.extend_from_slice(&0u32.to_le_bytes());
+    raw.extend_from_slice(&jpeg);
+    fs::write(dir.join("1.dng"), raw).unwrap();
+
+    let harness = start(&dir);
+    assert!(harness.viewmodel.img_path.ends_with("1.dng"));
+    assert!(harness.viewmodel.error.is_none());
+    assert_eq!(harness.viewmodel.meta.as_ref().unwrap().width, 32);
+}
+
+#[test]
+fn page_through_multipage_tiff() {
+    let dir = setup("tiff", &[]);
+    let file = fs::File::create(dir.join("1.tif")).unwrap();
+    let mut encoder = tiff::encoder::TiffEncoder::new(file).unwrap();
+    for value in [50u8, 150] {
+        encoder
+            .write_image::<tiff::encoder::colortype::RGB8>(16, 8, &[value; 16 * 8 * 3])
+            .unwrap();
+    }
+    drop(encoder);
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.screen_contains("page 1/2"));
+
+    // 2ページ目は切り替えたときに読む
+    harness.press(KeyCode::Char('.')).unwrap();
+    assert!(harness.screen_contains("page 2/2"));
+    assert!(!harness.screen_contains("Error"));
+    // 拡大するとそのページを読み直す
+    harness.press(KeyCode::Char('+')).unwrap();
+    assert!(harness.screen_contains("page 2/2"));
+    assert!(!harness.screen_contains("Error"));
+    harness.press(KeyCode::Char(',')).unwrap();
+    assert!(harness.screen_contains("page 1/2"));
+}
+
+#[test]
+fn apng_is_animated() {
+    let dir = setup("apng", &[]);
+    let file = fs::File::create(dir.join("1.png")).unwrap();
+    let mut encoder = png::Encoder::new(file, 16, 8);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_animated(2, 0).unwrap();
+    encoder.set_frame_delay(1, 10).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+    for value in [50u8, 150] {
+        writer.write_image_data(&[value; 16 * 8 * 3]).unwrap();
+    }
+    writer.finish().unwrap();
+    let mut harness = Harness::new(config(&dir), 120, 50).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert!(harness.viewmodel.is_animated());
+    assert!(harness.screen_contains("[Space] -> pause"));
+}
+
+#[test]
+fn svg_is_rasterized() {
+    let dir = setup("svg", &[]);
+    fs::write(
+        dir.join("icon.svg"),
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="24" height="12"><rect width="24" height="12" fill="red"/></svg>"#,
+    )
+    .unwrap();
+    let harness = start(&dir);
+    assert!(harness.viewmodel.img_path.ends_with("icon.svg"));
+    assert!(harness.viewmodel.error.is_none());
+    // 小さなSVGは拡大して描く
+    assert_eq!(harness.viewmodel.meta.as_ref().unwrap().width, 1024);
+}
+
+#[test]
+fn ocr_text_from_rules_is_reused() {
+    let dir = setup("ocr", &["1.png"]);
+    let mut config = config(&dir);
+    let log = dir.join("ocr.log");
+    config.ocr = Some(format!(
+        "sh -c \"echo run >> '{}'; echo hello\"",
+        log.display()
+    ));
+    config.rules = vec![toml::from_str("text = \"nomatch\"\ndest = \"skip\"").unwrap()];
+    let mut harness = Harness::new(config, 80, 24).unwrap();
+    harness.wait_for_image(TIMEOUT).unwrap();
+    assert_eq!(harness.viewmodel.text.as_deref(), Some("hello"));
+    // 欄を隠して表示し直しても読み取り直さない
+    harness.press(KeyCode::Char('T')).unwrap();
+    harness.press(KeyCode::Char('T')).unwrap();
+    assert_eq!(harness.viewmodel.text.as_deref(), Some("hello"));
+    assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 1);
+}